@@ -0,0 +1,395 @@
+#!/usr/bin/env python3
+"""Builds the rv32um and rv32ua conformance elfs in e2e-tests.
+
+The test bodies follow the riscv-tests macros (TEST_RR_OP, TEST_RR_*_BYPASS, TEST_CASE) and
+run in the same "p" environment as the checked in rv32ui-p-* binaries: a trap vector that
+writes gp to tohost, a reset vector that clears the registers, opens pmp and mrets into user
+mode, and the RVTEST_PASS / RVTEST_FAIL exit sequences. Expected values are computed here from
+the instruction semantics in the spec.
+
+There is no riscv linker in the toolchain this was built with, so the code is assembled with
+llvm-mc into a single position dependent .text section (no relocations) and wrapped in an elf
+with the upstream layout: .text.init at 0x80000000, .tohost at 0x80001000 and .data at
+0x80002000.
+
+Usage: python3 e2e-tests/src/build.py [llvm-mc]
+"""
+
+import os
+import struct
+import subprocess
+import sys
+import tempfile
+
+BASE = 0x80000000
+TOHOST = 0x80001000
+DATA = 0x80002000
+MASK = 0xFFFFFFFF
+
+OUT_DIR = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..")
+
+# RVTEST_CODE_BEGIN for the "p" environment
+PROLOGUE = f"""
+    .text
+_start:
+    j reset_vector
+trap_vector:
+    csrr t5, mcause
+    li t6, 8
+    beq t5, t6, write_tohost
+    li t6, 9
+    beq t5, t6, write_tohost
+    li t6, 11
+    beq t5, t6, write_tohost
+other_exception:
+    ori gp, gp, 1337
+write_tohost:
+    li t5, {TOHOST}
+    sw gp, 0(t5)
+    sw zero, 4(t5)
+    j write_tohost
+reset_vector:
+{chr(10).join(f"    li x{i}, 0" for i in range(1, 32))}
+    csrr a0, mhartid
+1:  bnez a0, 1b
+    li t0, {BASE + 4}
+    csrw mtvec, t0
+    csrwi satp, 0
+    li t0, 0x7fffffff
+    csrw pmpaddr0, t0
+    li t0, 31
+    csrw pmpcfg0, t0
+    csrwi mie, 0
+    csrwi medeleg, 0
+    csrwi mideleg, 0
+    li gp, 0
+    csrwi mstatus, 0
+    auipc t0, 0
+    addi t0, t0, 20
+    csrw mepc, t0
+    csrr a0, mhartid
+    mret
+"""
+
+# RVTEST_FAIL and RVTEST_PASS
+EPILOGUE = """
+    bne x0, gp, pass
+fail:
+    fence
+1:  beqz gp, 1b
+    slli gp, gp, 1
+    ori gp, gp, 1
+    li a7, 93
+    addi a0, gp, 0
+    ecall
+pass:
+    fence
+    li gp, 1
+    li a7, 93
+    li a0, 0
+    ecall
+    unimp
+"""
+
+
+def signed(value):
+    value &= MASK
+    return value - (1 << 32) if value & 0x80000000 else value
+
+
+def div(a, b):
+    a, b = signed(a), signed(b)
+    if b == 0:
+        return MASK
+    if a == -(1 << 31) and b == -1:
+        return a & MASK
+    quotient = abs(a) // abs(b)
+    return (-quotient if (a < 0) != (b < 0) else quotient) & MASK
+
+
+def rem(a, b):
+    a, b = signed(a), signed(b)
+    if b == 0:
+        return a & MASK
+    if a == -(1 << 31) and b == -1:
+        return 0
+    remainder = abs(a) % abs(b)
+    return (-remainder if a < 0 else remainder) & MASK
+
+
+M_OPS = {
+    "mul": lambda a, b: (a * b) & MASK,
+    "mulh": lambda a, b: ((signed(a) * signed(b)) >> 32) & MASK,
+    "mulhsu": lambda a, b: ((signed(a) * b) >> 32) & MASK,
+    "mulhu": lambda a, b: ((a * b) >> 32) & MASK,
+    "div": div,
+    "divu": lambda a, b: MASK if b == 0 else a // b,
+    "rem": rem,
+    "remu": lambda a, b: a if b == 0 else a % b,
+}
+
+MUL_OPERANDS = [
+    (0x00000000, 0x00000000),
+    (0x00000001, 0x00000001),
+    (0x00000003, 0x00000007),
+    (0x00000000, 0xFFFF8000),
+    (0x80000000, 0x00000000),
+    (0x80000000, 0xFFFF8000),
+    (0xAAAAAAAB, 0x0002FE7D),
+    (0x0002FE7D, 0xAAAAAAAB),
+    (0xFF000000, 0xFF000000),
+    (0xFFFFFFFF, 0xFFFFFFFF),
+    (0xFFFFFFFF, 0x00000001),
+    (0x00000001, 0xFFFFFFFF),
+    (0x00007E00, 0xB6DB6DB7),
+    (0x00007FC0, 0xB6DB6DB7),
+    (0x80000000, 0x80000000),
+    (0x7FFFFFFF, 0x7FFFFFFF),
+    (0x7FFFFFFF, 0x80000000),
+]
+
+DIV_OPERANDS = [
+    (20, 6),
+    (-20 & MASK, 6),
+    (20, -6 & MASK),
+    (-20 & MASK, -6 & MASK),
+    (0x80000000, 1),
+    (0x80000000, MASK),
+    (0x80000000, 0),
+    (1, 0),
+    (0, 0),
+    (0x7FFFFFFF, MASK),
+    (MASK, 0x7FFFFFFF),
+    (MASK, 2),
+    (0x80000001, 0x7FFFFFFF),
+]
+
+
+class Test:
+    def __init__(self):
+        self.lines = []
+        self.number = 1
+
+    def case(self, body, register, expected):
+        """TEST_CASE: runs the body and compares the register against the expected value"""
+        self.number += 1
+        self.lines += [f"test_{self.number}:", f"    li gp, {self.number}"]
+        self.lines += [f"    {line}" for line in body]
+        self.lines += [f"    li x7, {expected}", f"    bne {register}, x7, fail"]
+
+    def rr_op(self, inst, a, b, result):
+        self.case([f"li x11, {a}", f"li x12, {b}", f"{inst} x14, x11, x12"], "x14", result)
+
+    def rr_bypass(self, inst, a, b, result, src1_nops, src2_nops, swap, dest_nops=None):
+        """TEST_RR_DEST_BYPASS, TEST_RR_SRC12_BYPASS and TEST_RR_SRC21_BYPASS"""
+        if dest_nops is not None:
+            body = [f"li x1, {a}", f"li x2, {b}", f"{inst} x14, x1, x2"]
+            body += ["nop"] * dest_nops + ["addi x6, x14, 0"]
+            register = "x6"
+        else:
+            loads = [[f"li x1, {a}"], [f"li x2, {b}"]]
+            if swap:
+                loads.reverse()
+            body = loads[0] + ["nop"] * src1_nops + loads[1] + ["nop"] * src2_nops
+            body += [f"{inst} x14, x1, x2"]
+            register = "x14"
+        self.case(
+            ["li x4, 0", "1:"]
+            + body
+            + ["addi x4, x4, 1", "li x5, 2", "bne x4, x5, 1b"],
+            register,
+            result,
+        )
+
+    def source(self):
+        return PROLOGUE + "\n".join(self.lines) + EPILOGUE
+
+
+def m_test(inst):
+    op = M_OPS[inst]
+    operands = DIV_OPERANDS if inst in ("div", "divu", "rem", "remu") else MUL_OPERANDS
+    test = Test()
+    for a, b in operands:
+        test.rr_op(inst, a, b, op(a, b))
+
+    # TEST_RR_SRC1_EQ_DEST, TEST_RR_SRC2_EQ_DEST, TEST_RR_SRC12_EQ_DEST
+    a, b = 143, 11
+    test.case([f"li x11, {a}", f"li x12, {b}", f"{inst} x11, x11, x12"], "x11", op(a, b))
+    test.case([f"li x11, {a}", f"li x12, {b}", f"{inst} x12, x11, x12"], "x12", op(a, b))
+    test.case([f"li x11, {a}", f"{inst} x11, x11, x11"], "x11", op(a, a))
+
+    for nops in range(3):
+        test.rr_bypass(inst, a, b, op(a, b), 0, 0, False, dest_nops=nops)
+    for swap in (False, True):
+        for src1_nops, src2_nops in [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (2, 0)]:
+            test.rr_bypass(inst, a, b, op(a, b), src1_nops, src2_nops, swap)
+
+    # TEST_RR_ZEROSRC1, TEST_RR_ZEROSRC2, TEST_RR_ZEROSRC12, TEST_RR_ZERODEST
+    test.case([f"li x1, {b}", f"{inst} x2, x0, x1"], "x2", op(0, b))
+    test.case([f"li x1, {a}", f"{inst} x2, x1, x0"], "x2", op(a, 0))
+    test.case([f"{inst} x1, x0, x0"], "x1", op(0, 0))
+    test.case([f"li x1, {a}", f"li x2, {b}", f"{inst} x0, x1, x2"], "x0", 0)
+    return test
+
+
+A_OPS = {
+    "amoadd_w": lambda mem, src: (mem + src) & MASK,
+    "amoand_w": lambda mem, src: mem & src,
+    "amomax_w": lambda mem, src: max(signed(mem), signed(src)) & MASK,
+    "amomaxu_w": max,
+    "amomin_w": lambda mem, src: min(signed(mem), signed(src)) & MASK,
+    "amominu_w": min,
+    "amoor_w": lambda mem, src: mem | src,
+    "amoswap_w": lambda mem, src: src,
+    "amoxor_w": lambda mem, src: mem ^ src,
+}
+
+
+def a_test(name):
+    op = A_OPS[name]
+    inst = name.replace("_", ".")
+    test = Test()
+    test.lines.append(f"    li a3, {DATA}")
+    memory = None
+    # (initial memory or None to keep the previous result, source operand)
+    for initial, src in [
+        (0x80000000, 0xFFFFF800),
+        (None, 0x80000000),
+        (0x00000000, 0x00000001),
+        (None, 0xFFFFFFFF),
+        (0x7FFFFFFF, 0x80000000),
+    ]:
+        body = []
+        if initial is not None:
+            memory = initial
+            body += [f"li a0, {initial}", "sw a0, 0(a3)"]
+        body += [f"li a1, {src}", f"{inst} a4, a1, 0(a3)"]
+        test.case(body, "a4", memory)
+        memory = op(memory, src)
+        test.case(["lw a5, 0(a3)"], "a5", memory)
+
+    # rd = x0 still updates memory
+    test.case(["li a1, 5", "sw zero, 0(a3)", f"{inst} x0, a1, 0(a3)", "lw a5, 0(a3)"], "a5", op(0, 5))
+    return test
+
+
+def lrsc_test():
+    test = Test()
+    # sc without a reservation fails and doesn't store
+    test.case([f"li a0, {DATA}", "li a5, 0xdeadbeef", "sc.w a4, a5, (a0)"], "a4", 1)
+    test.case(["lw a4, 0(a0)"], "a4", 0)
+    # increment through an lr/sc loop 1024 times
+    test.case(
+        [
+            "li a1, 1024",
+            "1:",
+            "lr.w a4, (a0)",
+            "addi a4, a4, 1",
+            "sc.w a5, a4, (a0)",
+            "bnez a5, 1b",
+            "addi a1, a1, -1",
+            "bnez a1, 1b",
+            "lw a4, 0(a0)",
+        ],
+        "a4",
+        1024,
+    )
+    # the successful sc consumed the reservation
+    test.case(["sc.w a4, a5, (a0)"], "a4", 1)
+    test.case(["lw a4, 0(a0)"], "a4", 1024)
+    # lr returns the loaded value and sc writes the new one
+    test.case(["lr.w a4, (a0)", "li a5, 7", "sc.w a6, a5, (a0)"], "a4", 1024)
+    test.case(["lw a4, 0(a0)"], "a4", 7)
+    return test
+
+
+def assemble(llvm_mc, source):
+    with tempfile.TemporaryDirectory() as tmp:
+        asm = os.path.join(tmp, "test.S")
+        obj = os.path.join(tmp, "test.o")
+        with open(asm, "w") as f:
+            f.write(source)
+        subprocess.run(
+            [llvm_mc, "-triple=riscv32", "-mattr=+m,+a,-c,-relax", "-filetype=obj", asm, "-o", obj],
+            check=True,
+        )
+        with open(obj, "rb") as f:
+            return text_section(f.read())
+
+
+def text_section(obj):
+    """Returns the .text bytes of a relocatable object, which must not need relocating"""
+    shoff, = struct.unpack_from("<I", obj, 0x20)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", obj, 0x2E)
+    sections = [struct.unpack_from("<IIIIIIIIII", obj, shoff + i * shentsize) for i in range(shnum)]
+    names = sections[shstrndx][4]
+
+    def name(section):
+        start = names + section[0]
+        return obj[start:obj.index(b"\0", start)].decode()
+
+    for section in sections:
+        if name(section).startswith(".rela"):
+            raise RuntimeError(f"unexpected relocations in {name(section)}")
+    text = next(section for section in sections if name(section) == ".text")
+    return obj[text[4]:text[4] + text[5]]
+
+
+def elf(text, data_size):
+    """ELF32 executable with the riscv-tests layout"""
+    sections = [(".text.init", BASE, 0x1000, text, 6)]
+    rw = bytearray(0x48)
+    sections.append((".tohost", TOHOST, 0x2000, bytes(rw), 3))
+    if data_size:
+        sections.append((".data", DATA, 0x3000, bytes(data_size), 3))
+
+    shstrtab = b"\0" + b"".join(name.encode() + b"\0" for name, *_ in sections) + b".shstrtab\0"
+    body = bytearray(0x1000)
+    for _, _, offset, contents, _ in sections:
+        body += bytes(offset - len(body)) + contents
+    shstrtab_offset = len(body)
+    body += shstrtab
+    body += bytes(-len(body) % 4)
+    shoff = len(body)
+
+    rw_end = sections[-1][1] + len(sections[-1][3])
+    program_headers = [
+        (1, 0x1000, BASE, BASE, len(text), len(text), 5, 0x1000),
+        (1, 0x2000, TOHOST, TOHOST, rw_end - TOHOST, rw_end - TOHOST, 6, 0x1000),
+    ]
+    header = struct.pack(
+        "<4sBBBB8xHHIIIIIHHHHHH",
+        b"\x7fELF", 1, 1, 1, 0,
+        2, 0xF3, 1, BASE, 52, shoff, 0, 52, 32, len(program_headers), 40,
+        len(sections) + 2, len(sections) + 1,
+    )
+    body[:len(header)] = header
+    for i, program_header in enumerate(program_headers):
+        struct.pack_into("<IIIIIIII", body, 52 + i * 32, *program_header)
+
+    name_offset = 1
+    section_headers = [bytes(40)]
+    for name, addr, offset, contents, flags in sections:
+        section_headers.append(
+            struct.pack("<IIIIIIIIII", name_offset, 1, flags, addr, offset, len(contents), 0, 0, 64, 0)
+        )
+        name_offset += len(name) + 1
+    section_headers.append(
+        struct.pack("<IIIIIIIIII", name_offset, 3, 0, 0, shstrtab_offset, len(shstrtab), 0, 0, 1, 0)
+    )
+    return bytes(body) + b"".join(section_headers)
+
+
+def main():
+    llvm_mc = sys.argv[1] if len(sys.argv) > 1 else "llvm-mc"
+    tests = {f"rv32um-p-{inst}": (m_test(inst), 0) for inst in M_OPS}
+    tests.update({f"rv32ua-p-{name}": (a_test(name), 16) for name in A_OPS})
+    tests["rv32ua-p-lrsc"] = (lrsc_test(), 16)
+    for name, (test, data_size) in tests.items():
+        with open(os.path.join(OUT_DIR, name), "wb") as f:
+            f.write(elf(assemble(llvm_mc, test.source()), data_size))
+        print(name)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,291 @@
+// Loader for raw linux kernel images, the riscv boot protocol
+// Specification: https://docs.kernel.org/arch/riscv/boot-image-header.html
+
+use crate::fdt::RAM_BASE;
+use crate::memory::PAGE_SIZE;
+use crate::vm::{Environment, VM};
+
+const HEADER_SIZE: usize = 64;
+const TEXT_OFFSET: usize = 8;
+const IMAGE_SIZE: usize = 16;
+const MAGIC2: usize = 56;
+const MAGIC2_VALUE: &[u8; 4] = b"RSC\x05";
+
+// rv32 kernels must be loaded on a 4MiB megapage boundary
+const KERNEL_ALIGNMENT: u32 = 4 << 20;
+// the device tree is read with 8 byte accesses
+const DEVICE_TREE_ALIGNMENT: u32 = 8;
+// qemu's limit for how far the initrd is placed from the kernel
+const MAX_INITRD_OFFSET: u32 = 128 << 20;
+
+/// Where the kernel, initrd and device tree are placed, None selects the default address
+#[derive(Debug, Clone, Default)]
+pub struct BootConfig {
+    /// Defaults to the text offset from the image header, after the start of ram
+    pub kernel_addr: Option<u32>,
+    pub initrd: Option<Vec<u8>>,
+    /// Defaults to halfway into ram (at most 128MiB) past the kernel
+    pub initrd_addr: Option<u32>,
+    /// Defaults to the end of ram
+    pub device_tree_addr: Option<u32>,
+    /// Kernel command line, passed as /chosen/bootargs
+    pub bootargs: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootError {
+    /// The kernel address is not aligned to 4MiB
+    MisalignedKernel(u32),
+    /// The device tree address is not aligned to 8 bytes
+    MisalignedDeviceTree(u32),
+    /// A part of the boot payload doesn't fit in ram
+    OutsideRam(u32),
+    /// The kernel, initrd and device tree overlap
+    Overlap,
+}
+
+impl VM {
+    /// Loads a raw kernel Image, an optional initrd and a device tree describing the vm,
+    /// then hands over to the kernel in supervisor mode with the built in sbi firmware
+    /// devices must be added beforehand so they end up in the device tree
+    pub fn boot_linux(&mut self, image: &[u8], config: BootConfig) -> Result<(), BootError> {
+        let (text_offset, image_size) = image_header(image);
+        let kernel_addr = config
+            .kernel_addr
+            .unwrap_or(RAM_BASE.wrapping_add(text_offset));
+        if !kernel_addr.is_multiple_of(KERNEL_ALIGNMENT) {
+            return Err(BootError::MisalignedKernel(kernel_addr));
+        }
+        // the image size includes the bss, which must stay clear of the other parts
+        let kernel = (kernel_addr, image_size.max(image.len() as u32));
+        self.check_in_ram(kernel)?;
+
+        let initrd = match &config.initrd {
+            Some(initrd) => {
+                let addr = config.initrd_addr.unwrap_or_else(|| {
+                    let offset = (self.ram_size() / 2).min(MAX_INITRD_OFFSET);
+                    let default = kernel_addr.wrapping_add(offset);
+                    default.max(align_up(kernel_addr.wrapping_add(kernel.1)))
+                });
+                let range = (addr, initrd.len() as u32);
+                self.check_in_ram(range)?;
+                Some(range)
+            }
+            None => None,
+        };
+
+        self.bootargs = config.bootargs.clone();
+        self.initrd = initrd.map(|(addr, size)| (addr, addr.wrapping_add(size)));
+
+        let device_tree_addr = config
+            .device_tree_addr
+            .unwrap_or_else(|| self.default_device_tree_address());
+        if !device_tree_addr.is_multiple_of(DEVICE_TREE_ALIGNMENT) {
+            return Err(BootError::MisalignedDeviceTree(device_tree_addr));
+        }
+        let device_tree = (device_tree_addr, self.device_tree().len() as u32);
+        self.check_in_ram(device_tree)?;
+
+        let mut ranges = vec![kernel, device_tree];
+        ranges.extend(initrd);
+        for (i, a) in ranges.iter().enumerate() {
+            if ranges[i + 1..].iter().any(|b| ranges_intersect(*a, *b)) {
+                return Err(BootError::Overlap);
+            }
+        }
+
+        self.write_memory(kernel_addr, image);
+        if let (Some(data), Some((addr, _))) = (&config.initrd, initrd) {
+            self.write_memory(addr, data);
+        }
+        self.load_device_tree_at(device_tree_addr);
+
+        self.set_environment(Environment::Sbi);
+        self.csr.satp = 0;
+        self.pc = kernel_addr;
+        Ok(())
+    }
+
+    /// Checks that [addr, addr + size) is part of ram
+    fn check_in_ram(&self, (addr, size): (u32, u32)) -> Result<(), BootError> {
+        let end = addr as u64 + size as u64;
+        let ram_end = RAM_BASE as u64 + self.ram_size() as u64;
+        if addr < RAM_BASE || end > ram_end {
+            return Err(BootError::OutsideRam(addr));
+        }
+        Ok(())
+    }
+}
+
+/// The text offset and image size from the header, images without a header are loaded as is
+fn image_header(image: &[u8]) -> (u32, u32) {
+    if image.len() < HEADER_SIZE || &image[MAGIC2..MAGIC2 + 4] != MAGIC2_VALUE {
+        return (0, image.len() as u32);
+    }
+    let field = |offset: usize| {
+        let value = u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap());
+        value.min(u32::MAX as u64) as u32
+    };
+    (field(TEXT_OFFSET), field(IMAGE_SIZE))
+}
+
+fn align_up(addr: u32) -> u32 {
+    addr.wrapping_add(PAGE_SIZE as u32 - 1) & !(PAGE_SIZE as u32 - 1)
+}
+
+fn ranges_intersect((a, a_size): (u32, u32), (b, b_size): (u32, u32)) -> bool {
+    (a as u64) < b as u64 + b_size as u64 && (b as u64) < a as u64 + a_size as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::boot::{BootConfig, BootError, MAGIC2, MAGIC2_VALUE};
+    use crate::capture::CaptureBuffer;
+    use crate::csr::Privilege;
+    use crate::decode_instruction::Register;
+    use crate::fdt::RAM_BASE;
+    use crate::uart::{Uart16550, UART_BASE};
+    use crate::vm::{HaltReason, VM};
+
+    /// An image header with the given image size, followed by code
+    fn image_with_code(image_size: u64, code: &[u32]) -> Vec<u8> {
+        let mut image = vec![0; 64];
+        // j 0x40
+        image[0..4].copy_from_slice(&0x0400006f_u32.to_le_bytes());
+        // image size, larger than the file to cover the bss
+        image[16..24].copy_from_slice(&image_size.to_le_bytes());
+        image[MAGIC2..MAGIC2 + 4].copy_from_slice(MAGIC2_VALUE);
+        image.extend(code.iter().flat_map(|v| v.to_le_bytes()));
+        image
+    }
+
+    /// Code that loads the device tree magic into t0 and shuts down
+    fn test_image() -> Vec<u8> {
+        // lw t0, 0(a1)
+        // li a7, 0x53525354 (system reset)
+        // li a6, 0
+        // li a0, 0
+        // li a1, 0
+        // ecall
+        let code = [
+            0x0005a283_u32,
+            0x535258b7,
+            0x35488893,
+            0x00000813,
+            0x00000513,
+            0x00000593,
+            0x00000073,
+        ];
+        image_with_code(0x10000, &code)
+    }
+
+    #[test]
+    fn test_boot_image() {
+        let mut vm = VM::init();
+        let config = BootConfig {
+            initrd: Some(b"initrd".to_vec()),
+            bootargs: Some("console=ttyS0".to_string()),
+            ..BootConfig::default()
+        };
+        vm.boot_linux(&test_image(), config).unwrap();
+        assert_eq!(vm.pc(), RAM_BASE);
+        assert_eq!(vm.privilege(), Privilege::Supervisor);
+
+        // halfway into the default 128MiB of ram
+        let (start, end) = vm.initrd.unwrap();
+        assert_eq!(
+            (start, end),
+            (RAM_BASE + (64 << 20), RAM_BASE + (64 << 20) + 6)
+        );
+        assert_eq!(vm.read_memory(start, 6), b"initrd");
+
+        vm.run_with_limit(100);
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::Exit));
+        // the device tree magic, big endian
+        assert_eq!(vm.reg(Register::T0.into()), 0xedfe0dd0);
+    }
+
+    #[test]
+    fn test_boot_config_errors() {
+        let image = test_image();
+        let boot = |config: BootConfig| VM::init().boot_linux(&image, config);
+        assert_eq!(
+            boot(BootConfig {
+                kernel_addr: Some(RAM_BASE + 0x1000),
+                ..BootConfig::default()
+            }),
+            Err(BootError::MisalignedKernel(RAM_BASE + 0x1000))
+        );
+        assert_eq!(
+            boot(BootConfig {
+                initrd: Some(vec![0; 16]),
+                initrd_addr: Some(RAM_BASE + 0x8000),
+                ..BootConfig::default()
+            }),
+            Err(BootError::Overlap)
+        );
+        assert_eq!(
+            boot(BootConfig {
+                device_tree_addr: Some(0x1000),
+                ..BootConfig::default()
+            }),
+            Err(BootError::OutsideRam(0x1000))
+        );
+    }
+
+    /// Boots a kernel that turns on sv32 paging and prints through the uart
+    #[test]
+    fn test_boot_paging_and_uart() {
+        // li t0, 0x80010000 (root page table, in the bss)
+        // li t1, 0x200000cf (4MiB rwx megapage at 0x80000000)
+        // addi t2, t0, 0x7fc
+        // sw t1, 4(t2) (identity map the kernel)
+        // sw t1, 0x404(t2) (and alias it at 0xc0000000)
+        // li t1, 0x040000c7 (rw megapage at the uart)
+        // sw t1, 0x100(t0)
+        // srli t1, a1, 22 (identity map the device tree's megapage)
+        // slli t2, t1, 2
+        // add t2, t2, t0
+        // slli t1, t1, 20
+        // ori t1, t1, 0xcf
+        // sw t1, 0(t2)
+        // li t1, 0x80080010 (sv32, root at 0x80010000)
+        // csrw satp, t1
+        // sfence.vma
+        // li t2, 0x10000000
+        // li t1, 'o'
+        // sb t1, 0(t2)
+        // li t1, 'k'
+        // sb t1, 0(t2)
+        // lw t3, 0(a1)
+        // li t1, 0xc0000000
+        // lw t4, 0(t1)
+        // li a7, 0x53525354 (system reset)
+        // li a6, 0
+        // li a0, 0
+        // li a1, 0
+        // ecall
+        let code = [
+            0x800102b7, 0x20000337, 0x0cf30313, 0x7fc28393, 0x0063a223, 0x4063a223, 0x04000337,
+            0x0c730313, 0x1062a023, 0x0165d313, 0x00231393, 0x005383b3, 0x01431313, 0x0cf36313,
+            0x0063a023, 0x80080337, 0x01030313, 0x18031073, 0x12000073, 0x100003b7, 0x06f00313,
+            0x00638023, 0x06b00313, 0x00638023, 0x0005ae03, 0xc0000337, 0x00032e83, 0x535258b7,
+            0x35488893, 0x00000813, 0x00000513, 0x00000593, 0x00000073,
+        ];
+
+        let mut vm = VM::init();
+        let output = CaptureBuffer::new();
+        vm.add_uart(UART_BASE, Uart16550::new(Box::new(output.clone())))
+            .unwrap();
+        vm.boot_linux(&image_with_code(0x11000, &code), BootConfig::default())
+            .unwrap();
+        vm.run_with_limit(1000);
+
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::Exit));
+        assert_eq!(output.contents(), b"ok");
+        assert_ne!(vm.read_csr(0x180).unwrap(), 0);
+        // the device tree magic and the kernel's first instruction through the alias
+        assert_eq!(vm.reg(Register::T3.into()), 0xedfe0dd0);
+        assert_eq!(vm.reg(Register::T4.into()), 0x0400006f);
+    }
+}
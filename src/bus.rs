@@ -0,0 +1,152 @@
+// Memory mapped devices
+// physical accesses that don't hit a device fall through to ram
+
+use crate::memory::Memory;
+use crate::plic::Plic;
+
+/// A memory mapped peripheral
+pub trait Device {
+    /// Reads size (1, 2 or 4) bytes at offset from the start of the device's range
+    fn read(&mut self, offset: u32, size: u32) -> u32;
+
+    /// Writes the lower size (1, 2 or 4) bytes of value at offset from the start of the device's range
+    fn write(&mut self, offset: u32, size: u32, value: u32);
+
+    /// Called after every instruction, memory gives the device dma access to ram
+    fn tick(&mut self, _memory: &mut dyn Memory) {}
+
+    /// Level of the device's interrupt line, forwarded to the plic source it was registered with
+    fn interrupt(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusError {
+    /// The range is empty or wraps around the address space
+    InvalidRange,
+    /// The range overlaps a device that is already mapped
+    Overlap,
+    /// The interrupt is not a valid plic source
+    InvalidInterrupt(u32),
+}
+
+struct Mapping {
+    base: u32,
+    size: u32,
+    interrupt: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+#[derive(Default)]
+pub(crate) struct Bus {
+    mappings: Vec<Mapping>,
+}
+
+impl Bus {
+    pub(crate) fn add(
+        &mut self,
+        base: u32,
+        size: u32,
+        interrupt: Option<u32>,
+        device: Box<dyn Device>,
+    ) {
+        self.mappings.push(Mapping {
+            base,
+            size,
+            interrupt,
+            device,
+        });
+    }
+
+    /// Returns true if [base, base + size) overlaps a mapped device
+    pub(crate) fn overlaps(&self, base: u32, size: u32) -> bool {
+        self.mappings
+            .iter()
+            .any(|mapping| ranges_overlap(base, size, mapping.base, mapping.size))
+    }
+
+    /// The device mapped at addr and the offset of addr into it
+    pub(crate) fn device_at(&mut self, addr: u32) -> Option<(&mut dyn Device, u32)> {
+        self.mappings.iter_mut().find_map(|mapping| {
+            let offset = device_offset(addr, mapping.base, mapping.size)?;
+            Some((mapping.device.as_mut() as &mut dyn Device, offset))
+        })
+    }
+
+    /// Ticks every device and forwards their interrupt lines to the plic
+    pub(crate) fn tick(&mut self, memory: &mut dyn Memory, plic: &mut Plic) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.tick(memory);
+            if let Some(source) = mapping.interrupt {
+                plic.set_level(source, mapping.device.interrupt());
+            }
+        }
+    }
+}
+
+/// Offset of addr into the range starting at base, None if addr is outside of it
+pub(crate) fn device_offset(addr: u32, base: u32, size: u32) -> Option<u32> {
+    let offset = addr.wrapping_sub(base);
+    (offset < size).then_some(offset)
+}
+
+pub(crate) fn ranges_overlap(base: u32, size: u32, other_base: u32, other_size: u32) -> bool {
+    let end = base as u64 + size as u64;
+    let other_end = other_base as u64 + other_size as u64;
+    (base as u64) < other_end && (other_base as u64) < end
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::{ranges_overlap, Bus, Device};
+    use crate::memory::{Memory, PagedMemory};
+    use crate::plic::Plic;
+
+    /// Counts ticks, raises its interrupt on every other tick
+    #[derive(Default)]
+    struct Counter {
+        ticks: u32,
+    }
+
+    impl Device for Counter {
+        fn read(&mut self, _offset: u32, _size: u32) -> u32 {
+            self.ticks
+        }
+
+        fn write(&mut self, _offset: u32, _size: u32, value: u32) {
+            self.ticks = value;
+        }
+
+        fn tick(&mut self, _memory: &mut dyn Memory) {
+            self.ticks += 1;
+        }
+
+        fn interrupt(&self) -> bool {
+            self.ticks % 2 == 1
+        }
+    }
+
+    #[test]
+    fn test_device_dispatch() {
+        let mut bus = Bus::default();
+        bus.add(0x1000, 0x100, Some(3), Box::new(Counter::default()));
+        assert!(bus.overlaps(0x10ff, 1));
+        assert!(!bus.overlaps(0x1100, 0x100));
+        assert!(!ranges_overlap(0, 0x1000, 0x1000, 1));
+
+        let mut memory = PagedMemory::new();
+        let mut plic = Plic::default();
+        // priority 1, enabled for context 0
+        plic.write(4 * 3, 4, 1);
+        plic.write(0x2000, 4, 1 << 3);
+        bus.tick(&mut memory, &mut plic);
+        assert!(plic.interrupt(0));
+        bus.tick(&mut memory, &mut plic);
+
+        let (device, offset) = bus.device_at(0x1010).unwrap();
+        assert_eq!(offset, 0x10);
+        assert_eq!(device.read(offset, 4), 2);
+        assert!(bus.device_at(0x1100).is_none());
+    }
+}
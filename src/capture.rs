@@ -0,0 +1,33 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+/// An in memory `Write` sink, clones share the same buffer so output
+/// handed to the vm can be inspected afterwards
+#[derive(Debug, Clone, Default)]
+pub struct CaptureBuffer {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl CaptureBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of everything written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.borrow().clone()
+    }
+}
+
+impl Write for CaptureBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+// Core local interruptor, the sifive compatible layout used by qemu and spike
+// msip (software interrupt) at 0x0, mtimecmp at 0x4000, mtime at 0xbff8
+
+use crate::bus::Device;
+use std::time::Instant;
+
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub(crate) const CLINT_SIZE: u32 = 0x10000;
+
+const MSIP: u32 = 0x0;
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xbff8;
+
+// mtime frequency when it follows the host clock
+const HOST_CLOCK_FREQUENCY: u64 = 10_000_000;
+
+/// What mtime counts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimerSource {
+    /// mtime advances by one for every instruction, runs are fully reproducible
+    #[default]
+    Instructions,
+    /// mtime follows the host's monotonic clock at 10MHz
+    HostClock,
+}
+
+pub(crate) struct Clint {
+    source: TimerSource,
+    msip: bool,
+    mtimecmp: u64,
+    // instruction mode: the current time
+    // host clock mode: the time at `epoch`
+    mtime: u64,
+    epoch: Instant,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self {
+            source: TimerSource::default(),
+            msip: false,
+            // the timer interrupt stays clear until mtimecmp is programmed
+            mtimecmp: u64::MAX,
+            mtime: 0,
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Clint {
+    pub(crate) fn set_source(&mut self, source: TimerSource) {
+        let now = self.mtime();
+        self.source = source;
+        self.set_mtime(now);
+    }
+
+    pub(crate) fn mtime(&self) -> u64 {
+        match self.source {
+            TimerSource::Instructions => self.mtime,
+            TimerSource::HostClock => {
+                let elapsed = self.epoch.elapsed().as_nanos();
+                let ticks = elapsed * HOST_CLOCK_FREQUENCY as u128 / 1_000_000_000;
+                self.mtime.wrapping_add(ticks as u64)
+            }
+        }
+    }
+
+    fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+        self.epoch = Instant::now();
+    }
+
+    /// Advances mtime after an instruction
+    pub(crate) fn tick(&mut self) {
+        if self.source == TimerSource::Instructions {
+            self.mtime = self.mtime.wrapping_add(1);
+        }
+    }
+
+    pub(crate) fn set_mtimecmp(&mut self, mtimecmp: u64) {
+        self.mtimecmp = mtimecmp;
+    }
+
+    pub(crate) fn software_interrupt(&self) -> bool {
+        self.msip
+    }
+
+    pub(crate) fn timer_interrupt(&self) -> bool {
+        self.mtime() >= self.mtimecmp
+    }
+
+    fn read_word(&self, offset: u32) -> u32 {
+        match offset {
+            MSIP => self.msip as u32,
+            MTIMECMP => self.mtimecmp as u32,
+            o if o == MTIMECMP + 4 => (self.mtimecmp >> 32) as u32,
+            MTIME => self.mtime() as u32,
+            o if o == MTIME + 4 => (self.mtime() >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_word(&mut self, offset: u32, value: u32) {
+        match offset {
+            // only bit 0 of msip is writable
+            MSIP => self.msip = value & 1 == 1,
+            MTIMECMP => self.mtimecmp = (self.mtimecmp & !(u32::MAX as u64)) | value as u64,
+            o if o == MTIMECMP + 4 => {
+                self.mtimecmp = (self.mtimecmp & u32::MAX as u64) | ((value as u64) << 32)
+            }
+            MTIME => self.set_mtime((self.mtime() & !(u32::MAX as u64)) | value as u64),
+            o if o == MTIME + 4 => {
+                self.set_mtime((self.mtime() & u32::MAX as u64) | ((value as u64) << 32))
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u32, size: u32) -> u32 {
+        let shift = (offset & 0b11) * 8;
+        let word = self.read_word(offset & !0b11) >> shift;
+        if size == 4 {
+            word
+        } else {
+            word & ((1 << (size * 8)) - 1)
+        }
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: u32) {
+        let shift = (offset & 0b11) * 8;
+        let mask = if size == 4 {
+            u32::MAX
+        } else {
+            ((1 << (size * 8)) - 1) << shift
+        };
+        let word = self.read_word(offset & !0b11);
+        self.write_word(offset & !0b11, (word & !mask) | ((value << shift) & mask));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Device;
+    use crate::clint::{Clint, MSIP, MTIME, MTIMECMP};
+
+    #[test]
+    fn test_registers() {
+        let mut clint = Clint::default();
+        assert!(!clint.timer_interrupt());
+
+        clint.write(MTIMECMP, 4, 3);
+        clint.write(MTIMECMP + 4, 4, 0);
+        assert_eq!(clint.read(MTIMECMP, 4), 3);
+        for _ in 0..3 {
+            assert!(!clint.timer_interrupt());
+            clint.tick();
+        }
+        assert!(clint.timer_interrupt());
+        assert_eq!(clint.read(MTIME, 4), 3);
+
+        // byte writes only touch their own byte
+        clint.write(MTIME + 4, 1, 0xaa);
+        clint.write(MTIME + 5, 1, 0xbb);
+        assert_eq!(clint.read(MTIME + 4, 4), 0xbbaa);
+        assert_eq!(clint.read(MTIME + 5, 1), 0xbb);
+
+        clint.write(MSIP, 4, 0xff);
+        assert!(clint.software_interrupt());
+        assert_eq!(clint.read(MSIP, 4), 1);
+    }
+}
@@ -0,0 +1,462 @@
+// Control and status registers
+// Specification: The RISC-V Instruction Set Manual Volume II: Privileged Architecture
+
+use crate::pmp::Pmp;
+
+// user counters (read only shadows of the machine counters)
+pub(crate) const CYCLE: u32 = 0xc00;
+pub(crate) const TIME: u32 = 0xc01;
+pub(crate) const INSTRET: u32 = 0xc02;
+pub(crate) const CYCLEH: u32 = 0xc80;
+pub(crate) const TIMEH: u32 = 0xc81;
+pub(crate) const INSTRETH: u32 = 0xc82;
+
+// supervisor trap setup
+pub(crate) const SSTATUS: u32 = 0x100;
+pub(crate) const SIE: u32 = 0x104;
+pub(crate) const STVEC: u32 = 0x105;
+pub(crate) const SCOUNTEREN: u32 = 0x106;
+
+// supervisor trap handling
+pub(crate) const SSCRATCH: u32 = 0x140;
+pub(crate) const SEPC: u32 = 0x141;
+pub(crate) const SCAUSE: u32 = 0x142;
+pub(crate) const STVAL: u32 = 0x143;
+pub(crate) const SIP: u32 = 0x144;
+
+// supervisor protection and translation
+pub(crate) const SATP: u32 = 0x180;
+
+// machine information registers
+pub(crate) const MVENDORID: u32 = 0xf11;
+pub(crate) const MARCHID: u32 = 0xf12;
+pub(crate) const MIMPID: u32 = 0xf13;
+pub(crate) const MHARTID: u32 = 0xf14;
+pub(crate) const MCONFIGPTR: u32 = 0xf15;
+
+// machine trap setup
+pub(crate) const MSTATUS: u32 = 0x300;
+pub(crate) const MISA: u32 = 0x301;
+pub(crate) const MEDELEG: u32 = 0x302;
+pub(crate) const MIDELEG: u32 = 0x303;
+pub(crate) const MIE: u32 = 0x304;
+pub(crate) const MTVEC: u32 = 0x305;
+pub(crate) const MCOUNTEREN: u32 = 0x306;
+pub(crate) const MSTATUSH: u32 = 0x310;
+
+// machine trap handling
+pub(crate) const MSCRATCH: u32 = 0x340;
+pub(crate) const MEPC: u32 = 0x341;
+pub(crate) const MCAUSE: u32 = 0x342;
+pub(crate) const MTVAL: u32 = 0x343;
+pub(crate) const MIP: u32 = 0x344;
+
+// machine counters
+pub(crate) const MCYCLE: u32 = 0xb00;
+pub(crate) const MINSTRET: u32 = 0xb02;
+pub(crate) const MCYCLEH: u32 = 0xb80;
+pub(crate) const MINSTRETH: u32 = 0xb82;
+
+// mstatus fields
+pub(crate) const MSTATUS_SIE: u32 = 1 << 1;
+pub(crate) const MSTATUS_MIE: u32 = 1 << 3;
+pub(crate) const MSTATUS_SPIE: u32 = 1 << 5;
+pub(crate) const MSTATUS_MPIE: u32 = 1 << 7;
+pub(crate) const MSTATUS_SPP: u32 = 1 << 8;
+pub(crate) const MSTATUS_MPP: u32 = 0b11 << 11;
+pub(crate) const MSTATUS_MPRV: u32 = 1 << 17;
+pub(crate) const MSTATUS_SUM: u32 = 1 << 18;
+pub(crate) const MSTATUS_MXR: u32 = 1 << 19;
+pub(crate) const MSTATUS_TVM: u32 = 1 << 20;
+pub(crate) const MSTATUS_TW: u32 = 1 << 21;
+pub(crate) const MSTATUS_TSR: u32 = 1 << 22;
+
+// the subset of mstatus visible through sstatus
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+// interrupt bits shared by mie and mip
+pub(crate) const SSI: u32 = 1 << 1;
+pub(crate) const MSI: u32 = 1 << 3;
+pub(crate) const STI: u32 = 1 << 5;
+pub(crate) const MTI: u32 = 1 << 7;
+pub(crate) const SEI: u32 = 1 << 9;
+pub(crate) const MEI: u32 = 1 << 11;
+
+// ecalls from machine mode cannot be delegated
+pub(crate) const DELEGABLE_EXCEPTIONS: u32 = 0xffff & !(1 << 11);
+pub(crate) const DELEGABLE_INTERRUPTS: u32 = SSI | STI | SEI;
+
+// misa = MXL (32 bit) | extensions
+const MISA_VALUE: u32 = (1 << 30)
+    | extension(b'A')
+    | extension(b'C')
+    | extension(b'I')
+    | extension(b'M')
+    | extension(b'S')
+    | extension(b'U');
+
+/// Privilege level the hart is executing in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decodes a privilege field (mpp, spp), the reserved value 2 is treated as machine mode
+    pub(crate) fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CsrError {
+    // the csr address is not implemented
+    Unknown,
+    // attempted to write a read only csr
+    ReadOnly,
+    // the csr requires a higher privilege level
+    Privileged,
+}
+
+#[derive(Default)]
+pub(crate) struct CsrFile {
+    pub(crate) mstatus: u32,
+    pub(crate) medeleg: u32,
+    pub(crate) mideleg: u32,
+    pub(crate) mie: u32,
+    // pending bits written by software
+    pub(crate) mip: u32,
+    // pending bits driven by the interrupt controllers
+    pub(crate) mip_external: u32,
+    pub(crate) mtvec: u32,
+    pub(crate) mcounteren: u32,
+    pub(crate) mscratch: u32,
+    pub(crate) mepc: u32,
+    pub(crate) mcause: u32,
+    pub(crate) mtval: u32,
+    pub(crate) stvec: u32,
+    pub(crate) scounteren: u32,
+    pub(crate) sscratch: u32,
+    pub(crate) sepc: u32,
+    pub(crate) scause: u32,
+    pub(crate) stval: u32,
+    pub(crate) satp: u32,
+    pub(crate) pmp: Pmp,
+    pub(crate) mcycle: u64,
+    pub(crate) minstret: u64,
+    // mirror of the clint's mtime
+    pub(crate) time: u64,
+}
+
+impl CsrFile {
+    pub(crate) fn new() -> Self {
+        Self {
+            mstatus: MSTATUS_MPP,
+            ..Self::default()
+        }
+    }
+
+    /// Checks that the csr can be accessed from the given privilege level
+    pub(crate) fn check_access(&self, addr: u32, privilege: Privilege) -> Result<(), CsrError> {
+        // csr[9:8] is the lowest privilege level that can access the csr
+        if (privilege as u32) < (addr >> 8) & 0b11 {
+            return Err(CsrError::Privileged);
+        }
+
+        // tvm traps satp accesses so machine mode can emulate them
+        if addr == SATP && privilege == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0 {
+            return Err(CsrError::Privileged);
+        }
+
+        // user counters are gated by mcounteren, and scounteren for user mode
+        if let CYCLE..=0xc1f | CYCLEH..=0xc9f = addr {
+            let bit = 1 << (addr & 0x1f);
+            let enabled = match privilege {
+                Privilege::Machine => true,
+                Privilege::Supervisor => self.mcounteren & bit != 0,
+                Privilege::User => self.mcounteren & self.scounteren & bit != 0,
+            };
+            if !enabled {
+                return Err(CsrError::Privileged);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn read(&self, addr: u32) -> Result<u32, CsrError> {
+        if let Some(value) = self.pmp.read(addr) {
+            return Ok(value);
+        }
+
+        Ok(match addr {
+            CYCLE | MCYCLE => self.mcycle as u32,
+            CYCLEH | MCYCLEH => (self.mcycle >> 32) as u32,
+            TIME => self.time as u32,
+            TIMEH => (self.time >> 32) as u32,
+            INSTRET | MINSTRET => self.minstret as u32,
+            INSTRETH | MINSTRETH => (self.minstret >> 32) as u32,
+
+            MVENDORID | MARCHID | MIMPID | MHARTID | MCONFIGPTR => 0,
+
+            SSTATUS => self.mstatus & SSTATUS_MASK,
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.pending() & self.mideleg,
+
+            SATP => self.satp,
+
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MSTATUSH => 0,
+
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.pending(),
+
+            _ if is_hpm_counter(addr) => 0,
+            _ => return Err(CsrError::Unknown),
+        })
+    }
+
+    /// Effective value of mip, seip reads as the software bit or'ed with the plic's line
+    pub(crate) fn pending(&self) -> u32 {
+        self.mip | self.mip_external
+    }
+
+    pub(crate) fn write(&mut self, addr: u32, value: u32) -> Result<(), CsrError> {
+        // csr[11:10] = 0b11 marks a read only csr
+        if (addr >> 10) & 0b11 == 0b11 {
+            // still report unknown csrs as such
+            self.read(addr)?;
+            return Err(CsrError::ReadOnly);
+        }
+
+        if self.pmp.write(addr, value) {
+            return Ok(());
+        }
+
+        match addr {
+            MCYCLE => self.mcycle = (self.mcycle & !(u32::MAX as u64)) | value as u64,
+            MCYCLEH => self.mcycle = (self.mcycle & u32::MAX as u64) | ((value as u64) << 32),
+            MINSTRET => self.minstret = (self.minstret & !(u32::MAX as u64)) | value as u64,
+            MINSTRETH => self.minstret = (self.minstret & u32::MAX as u64) | ((value as u64) << 32),
+
+            SSTATUS => self.mstatus = (self.mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK),
+            SIE => self.mie = (self.mie & !self.mideleg) | (value & self.mideleg),
+            STVEC => self.stvec = trap_vector(value),
+            SCOUNTEREN => self.scounteren = value,
+
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !1,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            // supervisor software interrupts can be raised by software once delegated
+            SIP => {
+                let writable = SSI & self.mideleg;
+                self.mip = (self.mip & !writable) | (value & writable);
+            }
+
+            // only bare and sv32 exist, so every value is legal
+            SATP => self.satp = value,
+
+            MSTATUS => {
+                let writable = SSTATUS_MASK
+                    | MSTATUS_MIE
+                    | MSTATUS_MPIE
+                    | MSTATUS_MPRV
+                    | MSTATUS_TVM
+                    | MSTATUS_TW
+                    | MSTATUS_TSR;
+                let mut mstatus = (self.mstatus & !writable) | (value & writable);
+                // WARL, mpp only holds supported privilege levels
+                if value & MSTATUS_MPP != 0b10 << 11 {
+                    mstatus = (mstatus & !MSTATUS_MPP) | (value & MSTATUS_MPP);
+                }
+                self.mstatus = mstatus;
+            }
+            // WARL, the supported extensions cannot be changed
+            MISA | MSTATUSH => {}
+            MEDELEG => self.medeleg = value & DELEGABLE_EXCEPTIONS,
+            MIDELEG => self.mideleg = value & DELEGABLE_INTERRUPTS,
+            MIE => self.mie = value & (SSI | MSI | STI | MTI | SEI | MEI),
+            MTVEC => self.mtvec = trap_vector(value),
+            MCOUNTEREN => self.mcounteren = value,
+
+            MSCRATCH => self.mscratch = value,
+            // ialign is 16 bits (compressed instructions are supported)
+            MEPC => self.mepc = value & !1,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            // machine pending bits are set by the interrupt sources,
+            // the supervisor bits can be raised by machine mode software
+            MIP => {
+                let writable = SSI | STI | SEI;
+                self.mip = (self.mip & !writable) | (value & writable);
+            }
+
+            _ if is_hpm_counter(addr) => {}
+            _ => return Err(CsrError::Unknown),
+        }
+
+        Ok(())
+    }
+}
+
+/// WARL, only direct (0) and vectored (1) modes are legal
+/// the base is always 4 byte aligned
+fn trap_vector(value: u32) -> u32 {
+    let mode = value & 0b11;
+    let mode = if mode > 1 { 0 } else { mode };
+    (value & !0b11) | mode
+}
+
+/// misa bit for the extension with the given letter
+const fn extension(letter: u8) -> u32 {
+    1 << (letter - b'A')
+}
+
+/// Hardware performance monitoring counters and events, hardwired to zero
+fn is_hpm_counter(addr: u32) -> bool {
+    matches!(addr,
+        // hpmcounter3 - hpmcounter31 (and high halves)
+        0xc03..=0xc1f | 0xc83..=0xc9f
+        // mhpmcounter3 - mhpmcounter31 (and high halves)
+        | 0xb03..=0xb1f | 0xb83..=0xb9f
+        // mhpmevent3 - mhpmevent31
+        | 0x323..=0x33f)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr::{
+        CsrError, CsrFile, Privilege, CYCLE, CYCLEH, MCOUNTEREN, MCYCLE, MEDELEG, MEPC, MHARTID,
+        MIDELEG, MIE, MISA, MSTATUS, MSTATUS_MIE, MSTATUS_MPP, MTI, MTVEC, SCOUNTEREN, SIE,
+        SSTATUS, STI,
+    };
+
+    #[test]
+    fn test_read_only_and_unknown_csrs() {
+        let mut csr = CsrFile::new();
+        assert_eq!(csr.read(MHARTID), Ok(0));
+        assert_eq!(csr.write(MHARTID, 1), Err(CsrError::ReadOnly));
+        assert_eq!(csr.write(CYCLE, 1), Err(CsrError::ReadOnly));
+
+        assert_eq!(csr.read(0x744), Err(CsrError::Unknown));
+        assert_eq!(csr.write(0x744, 0), Err(CsrError::Unknown));
+        assert_eq!(csr.write(0xfff, 0), Err(CsrError::Unknown));
+    }
+
+    #[test]
+    fn test_warl_fields() {
+        let mut csr = CsrFile::new();
+
+        // misa reports rv32imacsu and ignores writes
+        let misa = csr.read(MISA).unwrap();
+        assert_eq!(misa, 0x40141105);
+        csr.write(MISA, 0).unwrap();
+        assert_eq!(csr.read(MISA), Ok(misa));
+
+        // mpp only holds supported privilege levels
+        csr.write(MSTATUS, u32::MAX).unwrap();
+        assert_eq!(csr.read(MSTATUS), Ok(0x007e19aa));
+        csr.write(MSTATUS, 0).unwrap();
+        assert_eq!(csr.read(MSTATUS), Ok(0));
+        csr.write(MSTATUS, MSTATUS_MIE | (0b10 << 11)).unwrap();
+        assert_eq!(csr.read(MSTATUS), Ok(MSTATUS_MIE));
+        csr.write(MSTATUS, MSTATUS_MPP).unwrap();
+        assert_eq!(csr.read(MSTATUS), Ok(MSTATUS_MPP));
+
+        // reserved mtvec modes fall back to direct mode
+        csr.write(MTVEC, 0x8000_0003).unwrap();
+        assert_eq!(csr.read(MTVEC), Ok(0x8000_0000));
+        csr.write(MTVEC, 0x8000_0001).unwrap();
+        assert_eq!(csr.read(MTVEC), Ok(0x8000_0001));
+
+        csr.write(MEPC, 0x8000_0003).unwrap();
+        assert_eq!(csr.read(MEPC), Ok(0x8000_0002));
+
+        csr.write(MIE, u32::MAX).unwrap();
+        assert_eq!(csr.read(MIE), Ok(0xaaa));
+    }
+
+    #[test]
+    fn test_supervisor_view() {
+        let mut csr = CsrFile::new();
+
+        // sstatus only exposes the supervisor fields of mstatus
+        csr.write(SSTATUS, u32::MAX).unwrap();
+        assert_eq!(csr.read(SSTATUS), Ok(0x000c0122));
+        assert_eq!(csr.read(MSTATUS), Ok(MSTATUS_MPP | 0x000c0122));
+
+        // sie and sip only see delegated interrupts
+        csr.write(MIE, MTI | STI).unwrap();
+        assert_eq!(csr.read(SIE), Ok(0));
+        csr.write(MIDELEG, u32::MAX).unwrap();
+        assert_eq!(csr.read(MIDELEG), Ok(0x222));
+        assert_eq!(csr.read(SIE), Ok(STI));
+
+        // machine ecalls cannot be delegated
+        csr.write(MEDELEG, u32::MAX).unwrap();
+        assert_eq!(csr.read(MEDELEG), Ok(0xf7ff));
+    }
+
+    #[test]
+    fn test_privilege_checks() {
+        let mut csr = CsrFile::new();
+        assert_eq!(csr.check_access(MSTATUS, Privilege::Machine), Ok(()));
+        assert_eq!(
+            csr.check_access(MSTATUS, Privilege::Supervisor),
+            Err(CsrError::Privileged)
+        );
+        assert_eq!(csr.check_access(SSTATUS, Privilege::Supervisor), Ok(()));
+        assert_eq!(
+            csr.check_access(SSTATUS, Privilege::User),
+            Err(CsrError::Privileged)
+        );
+
+        // counters need to be enabled for each lower privilege level
+        assert_eq!(
+            csr.check_access(CYCLE, Privilege::Supervisor),
+            Err(CsrError::Privileged)
+        );
+        csr.write(MCOUNTEREN, 1).unwrap();
+        assert_eq!(csr.check_access(CYCLE, Privilege::Supervisor), Ok(()));
+        assert_eq!(
+            csr.check_access(CYCLE, Privilege::User),
+            Err(CsrError::Privileged)
+        );
+        csr.write(SCOUNTEREN, 1).unwrap();
+        assert_eq!(csr.check_access(CYCLE, Privilege::User), Ok(()));
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut csr = CsrFile::new();
+        csr.mcycle = 0x1_0000_0005;
+        assert_eq!(csr.read(CYCLE), Ok(5));
+        assert_eq!(csr.read(CYCLEH), Ok(1));
+
+        csr.write(MCYCLE, 7).unwrap();
+        assert_eq!(csr.mcycle, 0x1_0000_0007);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::decode_instruction::DecodeError::UnknownOpcode;
+use crate::decode_instruction::{
+    decode_instruction, map_range, mask, sext, DecodeError, DecodedInstruction,
+};
+
+// Compressed (C extension) instructions are 16 bits wide and each one is an alias
+// for a 32 bit base instruction. Decoding is done by expanding the compressed
+// instruction into its 32 bit equivalent and decoding that instead.
+
+const OP: u32 = 0b0110011;
+const OP_IMM: u32 = 0b0010011;
+const LOAD: u32 = 0b0000011;
+const STORE: u32 = 0b0100011;
+const BRANCH: u32 = 0b1100011;
+const JAL: u32 = 0b1101111;
+const JALR: u32 = 0b1100111;
+const LUI: u32 = 0b0110111;
+const EBREAK: u32 = 0x00100073;
+
+const ZERO: u32 = 0;
+const RA: u32 = 1;
+const SP: u32 = 2;
+
+/// Returns true if the lowest 16 bits of an instruction encode a compressed instruction
+pub fn is_compressed(instruction: u32) -> bool {
+    instruction & mask(2) != 0b11
+}
+
+pub fn decode_compressed_instruction(instruction: u16) -> Result<DecodedInstruction, DecodeError> {
+    let mut decoded = decode_instruction(expand(instruction as u32)?)?;
+    decoded.compressed = true;
+    Ok(decoded)
+}
+
+/// Expands a compressed instruction into the equivalent 32 bit instruction
+fn expand(inst: u32) -> Result<u32, DecodeError> {
+    let quadrant = inst & mask(2);
+    let funct3 = (inst >> 13) & mask(3);
+
+    // full 5 bit register fields
+    let rd = (inst >> 7) & mask(5);
+    let rs2 = (inst >> 2) & mask(5);
+    // 3 bit register fields, these map to x8 - x15
+    let rd_prime = ((inst >> 2) & mask(3)) + 8;
+    let rs1_prime = ((inst >> 7) & mask(3)) + 8;
+
+    Ok(match (quadrant, funct3) {
+        // c.addi4spn -> addi rd', x2, nzuimm
+        (0b00, 0b000) => {
+            let imm = addi4spn_imm(inst);
+            if imm == 0 {
+                // this also covers the all zero instruction, which is defined to be illegal
+                return Err(UnknownOpcode);
+            }
+            encode_i(OP_IMM, rd_prime, 0x0, SP, imm)
+        }
+        // c.lw -> lw rd', offset(rs1')
+        (0b00, 0b010) => encode_i(LOAD, rd_prime, 0x2, rs1_prime, word_offset(inst)),
+        // c.sw -> sw rs2', offset(rs1')
+        (0b00, 0b110) => encode_s(0x2, rs1_prime, rd_prime, word_offset(inst)),
+
+        // c.addi -> addi rd, rd, imm (c.nop when rd = 0)
+        (0b01, 0b000) => encode_i(OP_IMM, rd, 0x0, rd, ci_imm(inst)),
+        // c.jal -> jal x1, offset
+        (0b01, 0b001) => encode_j(RA, jump_offset(inst)),
+        // c.li -> addi rd, x0, imm
+        (0b01, 0b010) => encode_i(OP_IMM, rd, 0x0, ZERO, ci_imm(inst)),
+        // c.addi16sp -> addi x2, x2, nzimm
+        (0b01, 0b011) if rd == SP => {
+            let imm = addi16sp_imm(inst);
+            if imm == 0 {
+                return Err(UnknownOpcode);
+            }
+            encode_i(OP_IMM, SP, 0x0, SP, imm)
+        }
+        // c.lui -> lui rd, nzimm
+        (0b01, 0b011) => {
+            let imm = sext(ci_imm(inst) << 12, 18);
+            if imm == 0 {
+                return Err(UnknownOpcode);
+            }
+            encode_u(LUI, rd, imm)
+        }
+        (0b01, 0b100) => expand_misc_alu(inst, rs1_prime, rd_prime)?,
+        // c.j -> jal x0, offset
+        (0b01, 0b101) => encode_j(ZERO, jump_offset(inst)),
+        // c.beqz -> beq rs1', x0, offset
+        (0b01, 0b110) => encode_b(0x0, rs1_prime, ZERO, branch_offset(inst)),
+        // c.bnez -> bne rs1', x0, offset
+        (0b01, 0b111) => encode_b(0x1, rs1_prime, ZERO, branch_offset(inst)),
+
+        // c.slli -> slli rd, rd, shamt
+        (0b10, 0b000) => encode_i(OP_IMM, rd, 0x1, rd, shamt(inst)?),
+        // c.lwsp -> lw rd, offset(x2)
+        (0b10, 0b010) if rd != ZERO => encode_i(LOAD, rd, 0x2, SP, lwsp_offset(inst)),
+        (0b10, 0b100) => {
+            let rs1 = rd;
+            match ((inst >> 12) & 1, rs1, rs2) {
+                // c.jr -> jalr x0, 0(rs1)
+                (0, rs1, 0) if rs1 != ZERO => encode_i(JALR, ZERO, 0x0, rs1, 0),
+                // c.mv -> add rd, x0, rs2
+                (0, rd, rs2) if rs2 != ZERO => encode_r(rd, 0x0, ZERO, rs2, 0x00),
+                // c.ebreak
+                (1, 0, 0) => EBREAK,
+                // c.jalr -> jalr x1, 0(rs1)
+                (1, rs1, 0) => encode_i(JALR, RA, 0x0, rs1, 0),
+                // c.add -> add rd, rd, rs2
+                (1, rd, rs2) => encode_r(rd, 0x0, rd, rs2, 0x00),
+                _ => return Err(UnknownOpcode),
+            }
+        }
+        // c.swsp -> sw rs2, offset(x2)
+        (0b10, 0b110) => encode_s(0x2, SP, rs2, swsp_offset(inst)),
+
+        // floating point loads and stores, and reserved encodings
+        _ => return Err(UnknownOpcode),
+    })
+}
+
+/// Expands c.srli, c.srai, c.andi, c.sub, c.xor, c.or and c.and
+fn expand_misc_alu(inst: u32, rd: u32, rs2: u32) -> Result<u32, DecodeError> {
+    Ok(match (inst >> 10) & mask(2) {
+        // c.srli -> srli rd', rd', shamt
+        0b00 => encode_i(OP_IMM, rd, 0x5, rd, shamt(inst)?),
+        // c.srai -> srai rd', rd', shamt
+        0b01 => encode_i(OP_IMM, rd, 0x5, rd, shamt(inst)? | (0x20 << 5)),
+        // c.andi -> andi rd', rd', imm
+        0b10 => encode_i(OP_IMM, rd, 0x7, rd, ci_imm(inst)),
+        _ => {
+            // inst[12] = 1 are rv64 only instructions (c.subw, c.addw)
+            if (inst >> 12) & 1 == 1 {
+                return Err(UnknownOpcode);
+            }
+            match (inst >> 5) & mask(2) {
+                // c.sub -> sub rd', rd', rs2'
+                0b00 => encode_r(rd, 0x0, rd, rs2, 0x20),
+                // c.xor -> xor rd', rd', rs2'
+                0b01 => encode_r(rd, 0x4, rd, rs2, 0x00),
+                // c.or -> or rd', rd', rs2'
+                0b10 => encode_r(rd, 0x6, rd, rs2, 0x00),
+                // c.and -> and rd', rd', rs2'
+                _ => encode_r(rd, 0x7, rd, rs2, 0x00),
+            }
+        }
+    })
+}
+
+/// Immediate used by c.addi, c.li, c.andi and c.lui (before shifting)
+fn ci_imm(inst: u32) -> u32 {
+    let mut imm = 0;
+    // inst[12] -> imm[5]
+    imm = map_range(inst, imm, 12, 5, 1);
+    // inst[6:2] -> imm[4:0]
+    imm = map_range(inst, imm, 6, 4, 5);
+    sext(imm, 6)
+}
+
+/// Shift amount used by c.slli, c.srli and c.srai
+fn shamt(inst: u32) -> Result<u32, DecodeError> {
+    // inst[12] is shamt[5], which must be zero for rv32
+    if (inst >> 12) & 1 == 1 {
+        return Err(UnknownOpcode);
+    }
+    // inst[6:2] -> shamt[4:0]
+    Ok((inst >> 2) & mask(5))
+}
+
+fn addi4spn_imm(inst: u32) -> u32 {
+    let mut imm = 0;
+    // inst[12:11] -> imm[5:4]
+    imm = map_range(inst, imm, 12, 5, 2);
+    // inst[10:7] -> imm[9:6]
+    imm = map_range(inst, imm, 10, 9, 4);
+    // inst[6] -> imm[2]
+    imm = map_range(inst, imm, 6, 2, 1);
+    // inst[5] -> imm[3]
+    imm = map_range(inst, imm, 5, 3, 1);
+    imm
+}
+
+fn addi16sp_imm(inst: u32) -> u32 {
+    let mut imm = 0;
+    // inst[12] -> imm[9]
+    imm = map_range(inst, imm, 12, 9, 1);
+    // inst[6] -> imm[4]
+    imm = map_range(inst, imm, 6, 4, 1);
+    // inst[5] -> imm[6]
+    imm = map_range(inst, imm, 5, 6, 1);
+    // inst[4:3] -> imm[8:7]
+    imm = map_range(inst, imm, 4, 8, 2);
+    // inst[2] -> imm[5]
+    imm = map_range(inst, imm, 2, 5, 1);
+    sext(imm, 10)
+}
+
+/// Offset used by c.lw and c.sw
+fn word_offset(inst: u32) -> u32 {
+    let mut imm = 0;
+    // inst[12:10] -> imm[5:3]
+    imm = map_range(inst, imm, 12, 5, 3);
+    // inst[6] -> imm[2]
+    imm = map_range(inst, imm, 6, 2, 1);
+    // inst[5] -> imm[6]
+    imm = map_range(inst, imm, 5, 6, 1);
+    imm
+}
+
+fn lwsp_offset(inst: u32) -> u32 {
+    let mut imm = 0;
+    // inst[12] -> imm[5]
+    imm = map_range(inst, imm, 12, 5, 1);
+    // inst[6:4] -> imm[4:2]
+    imm = map_range(inst, imm, 6, 4, 3);
+    // inst[3:2] -> imm[7:6]
+    imm = map_range(inst, imm, 3, 7, 2);
+    imm
+}
+
+fn swsp_offset(inst: u32) -> u32 {
+    let mut imm = 0;
+    // inst[12:9] -> imm[5:2]
+    imm = map_range(inst, imm, 12, 5, 4);
+    // inst[8:7] -> imm[7:6]
+    imm = map_range(inst, imm, 8, 7, 2);
+    imm
+}
+
+/// Offset used by c.j and c.jal
+fn jump_offset(inst: u32) -> u32 {
+    let mut imm = 0;
+    // inst[12] -> imm[11]
+    imm = map_range(inst, imm, 12, 11, 1);
+    // inst[11] -> imm[4]
+    imm = map_range(inst, imm, 11, 4, 1);
+    // inst[10:9] -> imm[9:8]
+    imm = map_range(inst, imm, 10, 9, 2);
+    // inst[8] -> imm[10]
+    imm = map_range(inst, imm, 8, 10, 1);
+    // inst[7] -> imm[6]
+    imm = map_range(inst, imm, 7, 6, 1);
+    // inst[6] -> imm[7]
+    imm = map_range(inst, imm, 6, 7, 1);
+    // inst[5:3] -> imm[3:1]
+    imm = map_range(inst, imm, 5, 3, 3);
+    // inst[2] -> imm[5]
+    imm = map_range(inst, imm, 2, 5, 1);
+    sext(imm, 12)
+}
+
+/// Offset used by c.beqz and c.bnez
+fn branch_offset(inst: u32) -> u32 {
+    let mut imm = 0;
+    // inst[12] -> imm[8]
+    imm = map_range(inst, imm, 12, 8, 1);
+    // inst[11:10] -> imm[4:3]
+    imm = map_range(inst, imm, 11, 4, 2);
+    // inst[6:5] -> imm[7:6]
+    imm = map_range(inst, imm, 6, 7, 2);
+    // inst[4:3] -> imm[2:1]
+    imm = map_range(inst, imm, 4, 2, 2);
+    // inst[2] -> imm[5]
+    imm = map_range(inst, imm, 2, 5, 1);
+    sext(imm, 9)
+}
+
+fn encode_r(rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | OP
+}
+
+fn encode_i(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: u32) -> u32 {
+    ((imm & mask(12)) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn encode_s(funct3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+    let mut inst = (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | STORE;
+    // imm[11:5] -> inst[31:25]
+    inst = map_range(imm, inst, 11, 31, 7);
+    // imm[4:0] -> inst[11:7]
+    map_range(imm, inst, 4, 11, 5)
+}
+
+fn encode_b(funct3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+    let mut inst = (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | BRANCH;
+    // imm[12] -> inst[31]
+    inst = map_range(imm, inst, 12, 31, 1);
+    // imm[10:5] -> inst[30:25]
+    inst = map_range(imm, inst, 10, 30, 6);
+    // imm[4:1] -> inst[11:8]
+    inst = map_range(imm, inst, 4, 11, 4);
+    // imm[11] -> inst[7]
+    map_range(imm, inst, 11, 7, 1)
+}
+
+fn encode_u(opcode: u32, rd: u32, imm: u32) -> u32 {
+    (imm & !mask(12)) | (rd << 7) | opcode
+}
+
+fn encode_j(rd: u32, imm: u32) -> u32 {
+    let mut inst = (rd << 7) | JAL;
+    // imm[20] -> inst[31]
+    inst = map_range(imm, inst, 20, 31, 1);
+    // imm[10:1] -> inst[30:21]
+    inst = map_range(imm, inst, 10, 30, 10);
+    // imm[11] -> inst[20]
+    inst = map_range(imm, inst, 11, 20, 1);
+    // imm[19:12] -> inst[19:12]
+    map_range(imm, inst, 19, 19, 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decode_compressed_instruction::{decode_compressed_instruction, expand};
+    use crate::decode_instruction::Opcode;
+
+    #[test]
+    fn test_expansion() {
+        // (compressed, expanded) pairs, assembled with llvm-mc
+        let expected = [
+            // c.addi4spn s0, sp, 1020
+            (0x1fe0, 0x3fc10413),
+            // c.lw a0, 124(a5)
+            (0x5fe8, 0x07c7a503),
+            // c.sw a0, 64(a5)
+            (0xc3a8, 0x04a7a023),
+            // c.nop
+            (0x0001, 0x00000013),
+            // c.addi a0, -32
+            (0x1501, 0xfe050513),
+            // c.jal -2048
+            (0x3001, 0x801ff0ef),
+            // c.li a0, 31
+            (0x457d, 0x01f00513),
+            // c.addi16sp sp, -512
+            (0x7101, 0xe0010113),
+            // c.lui a0, 0xfffe0
+            (0x7501, 0xfffe0537),
+            // c.srli s1, 31
+            (0x80fd, 0x01f4d493),
+            // c.srai s1, 1
+            (0x8485, 0x4014d493),
+            // c.andi s1, -1
+            (0x98fd, 0xfff4f493),
+            // c.sub s1, a0
+            (0x8c89, 0x40a484b3),
+            // c.xor s1, a0
+            (0x8ca9, 0x00a4c4b3),
+            // c.or s1, a0
+            (0x8cc9, 0x00a4e4b3),
+            // c.and s1, a0
+            (0x8ce9, 0x00a4f4b3),
+            // c.j 2046
+            (0xaffd, 0x7fe0006f),
+            // c.beqz s1, -256
+            (0xd081, 0xf00480e3),
+            // c.bnez s1, 254
+            (0xecfd, 0x0e049f63),
+            // c.slli a0, 31
+            (0x057e, 0x01f51513),
+            // c.lwsp a0, 252(sp)
+            (0x557e, 0x0fc12503),
+            // c.jr ra
+            (0x8082, 0x00008067),
+            // c.mv a0, a1
+            (0x852e, 0x00b00533),
+            // c.ebreak
+            (0x9002, 0x00100073),
+            // c.jalr a0
+            (0x9502, 0x000500e7),
+            // c.add a0, a1
+            (0x952e, 0x00b50533),
+            // c.swsp a0, 252(sp)
+            (0xdfaa, 0x0ea12e23),
+        ];
+
+        for (compressed, expanded) in expected {
+            assert_eq!(
+                expand(compressed).unwrap(),
+                expanded,
+                "compressed: {:04x}",
+                compressed
+            );
+        }
+    }
+
+    #[test]
+    fn test_illegal_compressed_instructions() {
+        // all zero instruction
+        assert!(decode_compressed_instruction(0x0000).is_err());
+        // c.addi16sp with zero immediate
+        assert!(decode_compressed_instruction(0x6101).is_err());
+        // c.lui with zero immediate
+        assert!(decode_compressed_instruction(0x6501).is_err());
+        // c.slli with shamt[5] set (rv64 only)
+        assert!(decode_compressed_instruction(0x1502).is_err());
+        // c.subw (rv64 only)
+        assert!(decode_compressed_instruction(0x9c89).is_err());
+        // c.lwsp with rd = 0
+        assert!(decode_compressed_instruction(0x4002).is_err());
+        // c.jr with rs1 = 0
+        assert!(decode_compressed_instruction(0x8002).is_err());
+        // c.flw (no floating point support)
+        assert!(decode_compressed_instruction(0x6000).is_err());
+    }
+
+    #[test]
+    fn test_decoded_instruction_is_compressed() {
+        // c.addi a0, -32
+        let decoded = decode_compressed_instruction(0x1501).unwrap();
+        assert!(decoded.compressed);
+        assert_eq!(decoded.opcode, Opcode::Addi);
+        assert_eq!(decoded.imm, -32_i32 as u32);
+        assert_eq!(decoded.size(), 2);
+    }
+}
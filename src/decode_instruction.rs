@@ -1,7 +1,7 @@
 use crate::decode_instruction::DecodeError::UnknownOpcode;
 
 #[derive(Debug, Clone)]
-pub(crate) enum InstructionType {
+pub enum InstructionType {
     R,
     I,
     S,
@@ -11,8 +11,9 @@ pub(crate) enum InstructionType {
     Fence,
 }
 
+#[derive(Debug, Clone, Copy)]
 #[repr(u32)]
-pub(crate) enum Register {
+pub enum Register {
     // Zero constant
     Zero,
     // Return address
@@ -68,8 +69,8 @@ impl From<Register> for u32 {
     }
 }
 
-#[derive(Debug, Clone)]
-pub(crate) enum Opcode {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Opcode {
     Add,
     Sub,
     Xor,
@@ -81,6 +82,27 @@ pub(crate) enum Opcode {
     Slt,
     Sltu,
 
+    Mul,
+    Mulh,
+    Mulhsu,
+    Mulhu,
+    Div,
+    Divu,
+    Rem,
+    Remu,
+
+    LrW,
+    ScW,
+    AmoswapW,
+    AmoaddW,
+    AmoxorW,
+    AmoandW,
+    AmoorW,
+    AmominW,
+    AmomaxW,
+    AmominuW,
+    AmomaxuW,
+
     Addi,
     Xori,
     Ori,
@@ -116,34 +138,57 @@ pub(crate) enum Opcode {
 
     Ecall,
     Ebreak,
-    Eother,
+    Sret,
+    Mret,
+    Wfi,
+    SfenceVma,
+
+    Csrrw,
+    Csrrs,
+    Csrrc,
+    Csrrwi,
+    Csrrsi,
+    Csrrci,
 
     Fence,
 }
 
 #[derive(Debug, Clone)]
-pub(crate) struct DecodedInstruction {
-    pub(crate) inst_type: InstructionType,
-    pub(crate) opcode: Opcode,
-    pub(crate) rd: u32,
-    pub(crate) rs1: u32,
-    pub(crate) rs2: u32,
-    pub(crate) funct3: u32,
-    pub(crate) funct7: u32,
-    pub(crate) imm: u32,
+pub struct DecodedInstruction {
+    pub inst_type: InstructionType,
+    pub opcode: Opcode,
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub funct3: u32,
+    pub funct7: u32,
+    pub imm: u32,
+    // true if this was expanded from a 16 bit compressed instruction
+    pub compressed: bool,
+}
+
+impl DecodedInstruction {
+    /// Size of the encoded instruction in bytes
+    pub fn size(&self) -> u32 {
+        if self.compressed {
+            2
+        } else {
+            4
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-pub(crate) enum DecodeError {
+pub enum DecodeError {
     UnsupportedInstructionType,
     UnknownOpcode,
 }
 
-pub(crate) fn decode_instruction(instruction: u32) -> Result<DecodedInstruction, DecodeError> {
+pub fn decode_instruction(instruction: u32) -> Result<DecodedInstruction, DecodeError> {
     let opcode_value = instruction & mask(7);
 
     let inst_type = match opcode_value {
-        0b0110011 => InstructionType::R,
+        0b0110011 | 0b0101111 => InstructionType::R,
         0b0010011 | 0b0000011 | 0b1110011 | 0b1100111 => InstructionType::I,
         0b0100011 => InstructionType::S,
         0b1100011 => InstructionType::B,
@@ -170,6 +215,7 @@ pub(crate) fn decode_instruction(instruction: u32) -> Result<DecodedInstruction,
         funct3,
         funct7,
         imm,
+        compressed: false,
     })
 }
 
@@ -181,6 +227,34 @@ fn decode_opcode(
     imm: u32,
 ) -> Result<Opcode, DecodeError> {
     Ok(match inst_type {
+        // atomic extension
+        // funct7 = funct5 | aq | rl
+        InstructionType::R if opcode_value == 0b0101111 => match (funct3, funct7 >> 2) {
+            (0x2, 0b00010) => Opcode::LrW,
+            (0x2, 0b00011) => Opcode::ScW,
+            (0x2, 0b00001) => Opcode::AmoswapW,
+            (0x2, 0b00000) => Opcode::AmoaddW,
+            (0x2, 0b00100) => Opcode::AmoxorW,
+            (0x2, 0b01100) => Opcode::AmoandW,
+            (0x2, 0b01000) => Opcode::AmoorW,
+            (0x2, 0b10000) => Opcode::AmominW,
+            (0x2, 0b10100) => Opcode::AmomaxW,
+            (0x2, 0b11000) => Opcode::AmominuW,
+            (0x2, 0b11100) => Opcode::AmomaxuW,
+            _ => return Err(UnknownOpcode),
+        },
+        // multiply extension
+        InstructionType::R if funct7 == 0x01 => match funct3 {
+            0x0 => Opcode::Mul,
+            0x1 => Opcode::Mulh,
+            0x2 => Opcode::Mulhsu,
+            0x3 => Opcode::Mulhu,
+            0x4 => Opcode::Div,
+            0x5 => Opcode::Divu,
+            0x6 => Opcode::Rem,
+            0x7 => Opcode::Remu,
+            _ => return Err(UnknownOpcode),
+        },
         InstructionType::R => match funct3 {
             0x0 => match funct7 {
                 0x00 => Opcode::Add,
@@ -228,10 +302,25 @@ fn decode_opcode(
                     _ => return Err(UnknownOpcode),
                 },
                 0b1100111 => Opcode::Jalr,
-                0b1110011 => match imm {
-                    0x0 => Opcode::Ecall,
-                    0x1 => Opcode::Ebreak,
-                    _ => Opcode::Eother,
+                // system
+                0b1110011 => match funct3 {
+                    0x0 => match imm {
+                        0x0 => Opcode::Ecall,
+                        0x1 => Opcode::Ebreak,
+                        0x102 => Opcode::Sret,
+                        0x302 => Opcode::Mret,
+                        0x105 => Opcode::Wfi,
+                        // funct7 = 0b0001001, rs2 holds the asid
+                        _ if imm >> 5 == 0b0001001 => Opcode::SfenceVma,
+                        _ => return Err(UnknownOpcode),
+                    },
+                    0x1 => Opcode::Csrrw,
+                    0x2 => Opcode::Csrrs,
+                    0x3 => Opcode::Csrrc,
+                    0x5 => Opcode::Csrrwi,
+                    0x6 => Opcode::Csrrsi,
+                    0x7 => Opcode::Csrrci,
+                    _ => return Err(UnknownOpcode),
                 },
                 _ => return Err(UnknownOpcode),
             }
@@ -320,7 +409,7 @@ fn decode_immediate(instruction_type: &InstructionType, instruction: u32) -> u32
 
 /// Copies bit set in val_1 into some range in val_2
 /// [31, ..., 4, 3, 2,  1, 0]
-fn map_range(src: u32, dest: u32, src_start: u8, dest_start: u8, count: u8) -> u32 {
+pub(crate) fn map_range(src: u32, dest: u32, src_start: u8, dest_start: u8, count: u8) -> u32 {
     let right_shift_value = src_start + 1 - count; // +1 because of 0 index
     let val_1_range = (src >> right_shift_value) & mask(count);
 
@@ -364,7 +453,7 @@ pub(crate) const fn mask(n: u8) -> u32 {
 
 #[cfg(test)]
 mod tests {
-    use crate::decode_instruction::{decode_instruction, map_range};
+    use crate::decode_instruction::{decode_instruction, map_range, Opcode};
 
     #[test]
     fn test_map_range() {
@@ -399,4 +488,77 @@ mod tests {
         // jal x5, 44 (J Type)
         assert_eq!(decode_instruction(0x02c002ef).unwrap().imm, 44);
     }
+
+    #[test]
+    fn test_atomic_extension_decoding() {
+        let expected = [
+            (0x1005262f, Opcode::LrW),
+            (0x1605262f, Opcode::LrW),
+            (0x18b5262f, Opcode::ScW),
+            (0x08b5262f, Opcode::AmoswapW),
+            (0x00b5262f, Opcode::AmoaddW),
+            (0x20b5262f, Opcode::AmoxorW),
+            (0x60b5262f, Opcode::AmoandW),
+            (0x40b5262f, Opcode::AmoorW),
+            (0x80b5262f, Opcode::AmominW),
+            (0xa0b5262f, Opcode::AmomaxW),
+            (0xc0b5262f, Opcode::AmominuW),
+            (0xe0b5262f, Opcode::AmomaxuW),
+        ];
+        for (instruction, opcode) in expected {
+            assert_eq!(decode_instruction(instruction).unwrap().opcode, opcode);
+        }
+
+        // only word sized atomics are supported
+        assert!(decode_instruction(0x00b5362f).is_err());
+    }
+
+    #[test]
+    fn test_csr_decoding() {
+        let expected = [
+            // csrrw a0, mscratch, a1
+            (0x34059573, Opcode::Csrrw),
+            // csrrs a0, mscratch, a1
+            (0x3405a573, Opcode::Csrrs),
+            // csrrc a0, mscratch, a1
+            (0x3405b573, Opcode::Csrrc),
+            // csrrwi a0, mscratch, 11
+            (0x3405d573, Opcode::Csrrwi),
+            // csrrsi a0, mscratch, 11
+            (0x3405e573, Opcode::Csrrsi),
+            // csrrci a0, mscratch, 11
+            (0x3405f573, Opcode::Csrrci),
+        ];
+        for (instruction, opcode) in expected {
+            let decoded = decode_instruction(instruction).unwrap();
+            assert_eq!(decoded.opcode, opcode);
+            assert_eq!(decoded.imm & 0xfff, 0x340);
+            assert_eq!((decoded.rd, decoded.rs1), (10, 11));
+        }
+
+        // funct3 = 4 is not a valid system instruction
+        assert!(decode_instruction(0x3405c573).is_err());
+        // neither is uret, which was removed from the spec
+        assert!(decode_instruction(0x00200073).is_err());
+    }
+
+    #[test]
+    fn test_multiply_extension_decoding() {
+        let expected = [
+            (0x02b50633, Opcode::Mul),
+            (0x02b51633, Opcode::Mulh),
+            (0x02b52633, Opcode::Mulhsu),
+            (0x02b53633, Opcode::Mulhu),
+            (0x02b54633, Opcode::Div),
+            (0x02b55633, Opcode::Divu),
+            (0x02b56633, Opcode::Rem),
+            (0x02b57633, Opcode::Remu),
+        ];
+        for (instruction, opcode) in expected {
+            let decoded = decode_instruction(instruction).unwrap();
+            assert_eq!(decoded.opcode, opcode);
+            // a2, a0, a1
+            assert_eq!((decoded.rd, decoded.rs1, decoded.rs2), (12, 10, 11));
+        }
+    }
 }
@@ -1,21 +1,59 @@
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
 
-/// Parses a very specific type of elf, that meets the following constraints
-/// 32 bit, little endian, executable, riscv
+// Parses a very specific type of elf, that meets the following constraints
+// 32 bit, little endian, executable, riscv
 
-/// Specification: https://en.wikipedia.org/wiki/Executable_and_Linkable_Format
+// Specification: https://en.wikipedia.org/wiki/Executable_and_Linkable_Format
 
 const MAGIC_NUMBER: [u8; 4] = [0x7f, 0x45, 0x4c, 0x46];
 
-// MemorySegment = (Address, Data)
-type MemorySegment = (u32, Vec<u8>);
+// segment flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// A loadable segment, memory past the file data up to memory_size is zero filled
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+    pub memory_size: u32,
+    // combination of PF_R, PF_W and PF_X
+    pub flags: u32,
+}
+
+pub struct ProgramInfo {
+    pub entry_point: u32,
+    // every PT_LOAD segment in program header order
+    pub segments: Vec<Segment>,
+    // address of the .tohost section used by the riscv-tests to report results
+    pub tohost: Option<u32>,
+    // end of the highest segment in memory including its bss, where the heap starts
+    pub end: u32,
+    // address of the program header table if a segment loads it into memory
+    pub program_headers: Option<u32>,
+    pub program_header_count: u32,
+}
 
-pub(crate) struct ProgramInfo {
-    pub(crate) entry_point: u32,
-    pub(crate) code: MemorySegment,
-    pub(crate) data: MemorySegment,
+#[derive(Debug)]
+pub enum ElfError {
+    Io(io::Error),
+    InvalidMagicNumber,
+    UnsupportedClass,
+    UnsupportedEndianness,
+    UnsupportedAbi,
+    NotExecutable,
+    UnsupportedMachine,
+    // an offset or size in a header points outside the file
+    OutOfBounds,
+}
+
+impl From<io::Error> for ElfError {
+    fn from(value: io::Error) -> Self {
+        ElfError::Io(value)
+    }
 }
 
 struct ElfHeaderInfo {
@@ -23,157 +61,282 @@ struct ElfHeaderInfo {
     program_header_table_offset: u32,
     program_header_entry_size: u32,
     program_entry_count: u32,
+    section_header_table_offset: u32,
+    section_header_entry_size: u32,
+    section_entry_count: u32,
+    section_names_index: u32,
 }
 
 struct ProgramHeaderInfo {
     data: Vec<u8>,
+    file_offset: u32,
     virtual_address: u32,
-    // true = code_segment, false = data_segment
-    code: bool,
+    memory_size: u32,
+    flags: u32,
+}
+
+/// Parses the elf file at file_path
+pub fn parse_elf(file_path: String) -> Result<ProgramInfo, ElfError> {
+    parse_elf_from(&mut BufReader::new(File::open(file_path)?))
 }
 
-pub(crate) fn parse_elf(file_path: String) -> ProgramInfo {
-    let mut f = BufReader::new(File::open(file_path).unwrap());
+/// Parses an elf file that has already been loaded into memory
+pub fn parse_elf_bytes(bytes: &[u8]) -> Result<ProgramInfo, ElfError> {
+    parse_elf_from(&mut Cursor::new(bytes))
+}
 
-    let header_info = parse_elf_header(&mut f);
+fn parse_elf_from<R: Read + Seek>(f: &mut R) -> Result<ProgramInfo, ElfError> {
+    let header_info = parse_elf_header(f)?;
 
-    let mut code = None;
-    let mut data = None;
+    let mut segments = Vec::new();
+    let mut end = 0;
+    let mut program_headers = None;
 
     for i in 0..header_info.program_entry_count {
-        let offset =
-            (i * header_info.program_header_entry_size) + header_info.program_header_table_offset;
-
-        if let Some(program_header) = parse_program_header(&mut f, offset) {
-            if program_header.code {
-                if code != None {
-                    panic!("multiple code segments");
-                }
-                code = Some((program_header.virtual_address, program_header.data));
-            } else {
-                if data != None {
-                    panic!("multiple data segments");
+        let offset = table_entry(
+            header_info.program_header_table_offset,
+            header_info.program_header_entry_size,
+            i,
+        )?;
+
+        if let Some(program_header) = parse_program_header(f, offset)? {
+            let size = program_header
+                .memory_size
+                .max(program_header.data.len() as u32);
+            end = end.max(program_header.virtual_address.saturating_add(size));
+            // the whole table has to be loaded for the auxiliary vector to point at it
+            let table_offset = header_info
+                .program_header_table_offset
+                .checked_sub(program_header.file_offset);
+            let table_size = header_info.program_entry_count as u64
+                * header_info.program_header_entry_size as u64;
+            if let Some(table_offset) = table_offset {
+                if table_offset as u64 + table_size <= program_header.data.len() as u64 {
+                    program_headers =
+                        Some(program_header.virtual_address.wrapping_add(table_offset));
                 }
-                data = Some((program_header.virtual_address, program_header.data));
             }
+            segments.push(Segment {
+                address: program_header.virtual_address,
+                data: program_header.data,
+                memory_size: program_header.memory_size,
+                flags: program_header.flags,
+            });
         }
     }
 
-    ProgramInfo {
+    let tohost = find_section_address(f, &header_info, b".tohost")?;
+
+    Ok(ProgramInfo {
         entry_point: header_info.entry_point,
-        code: code.unwrap(),
-        data: data.unwrap(),
+        segments,
+        tohost,
+        end,
+        program_headers,
+        program_header_count: header_info.program_entry_count,
+    })
+}
+
+/// Returns the address of the section with the given name, None if there is no such section
+fn find_section_address<R: Read + Seek>(
+    f: &mut R,
+    header_info: &ElfHeaderInfo,
+    name: &[u8],
+) -> Result<Option<u32>, ElfError> {
+    if header_info.section_header_table_offset == 0
+        || header_info.section_names_index >= header_info.section_entry_count
+    {
+        return Ok(None);
+    }
+
+    let section_header_offset = |index: u32| {
+        table_entry(
+            header_info.section_header_table_offset,
+            header_info.section_header_entry_size,
+            index,
+        )
+    };
+
+    // the section name string table, names are offsets into it
+    seek(
+        f,
+        field(
+            section_header_offset(header_info.section_names_index)?,
+            0x10,
+        )?,
+    )?;
+    let names_offset = u32_le(&read_bytes::<R, 4>(f)?);
+
+    for i in 0..header_info.section_entry_count {
+        let offset = section_header_offset(i)?;
+
+        // sh_name
+        seek(f, offset)?;
+        let sh_name = u32_le(&read_bytes::<R, 4>(f)?);
+
+        // sh_addr
+        seek(f, field(offset, 0x0C)?)?;
+        let sh_addr = u32_le(&read_bytes::<R, 4>(f)?);
+
+        // compare the null terminated name
+        seek(f, field(names_offset, sh_name)?)?;
+        let mut section_name = vec![0_u8; name.len() + 1];
+        if f.read_exact(&mut section_name).is_err() {
+            continue;
+        }
+        if &section_name[..name.len()] == name && section_name[name.len()] == 0 {
+            return Ok(Some(sh_addr));
+        }
     }
+
+    Ok(None)
+}
+
+/// Reads N bytes and returns the given error if they don't match the expected value
+fn expect_bytes<R: Read, const N: usize>(
+    f: &mut R,
+    expected: [u8; N],
+    error: ElfError,
+) -> Result<(), ElfError> {
+    if read_bytes::<R, N>(f)? != expected {
+        return Err(error);
+    }
+    Ok(())
 }
 
-fn parse_elf_header(f: &mut BufReader<File>) -> ElfHeaderInfo {
+fn parse_elf_header<R: Read + Seek>(f: &mut R) -> Result<ElfHeaderInfo, ElfError> {
     // verify_magic_number
-    let file_magic_number: [u8; 4] = read_bytes(f).unwrap();
-    assert_eq!(file_magic_number, MAGIC_NUMBER);
+    expect_bytes(f, MAGIC_NUMBER, ElfError::InvalidMagicNumber)?;
 
     // the class must be 32 bits
-    assert_eq!(read_bytes(f).unwrap(), [0x01]);
+    expect_bytes(f, [0x01], ElfError::UnsupportedClass)?;
 
     // ensure little-endian
-    assert_eq!(read_bytes(f).unwrap(), [0x01]);
+    expect_bytes(f, [0x01], ElfError::UnsupportedEndianness)?;
 
     // ensure system-v abi
-    seek(f, 0x07).unwrap();
-    assert_eq!(read_bytes(f).unwrap(), [0x00]);
+    seek(f, 0x07)?;
+    expect_bytes(f, [0x00], ElfError::UnsupportedAbi)?;
 
     // skip to offset 0x10 -> e_type
-    seek(f, 0x10).unwrap();
+    seek(f, 0x10)?;
 
     // ensure file type is executable
-    assert_eq!(read_bytes(f).unwrap(), [0x02]);
+    expect_bytes(f, [0x02], ElfError::NotExecutable)?;
 
     // seek to machine type
-    seek(f, 0x12).unwrap();
+    seek(f, 0x12)?;
 
     // ensure machine type is riscv (0xF3)
-    assert_eq!(read_bytes(f).unwrap(), [0xF3]);
+    expect_bytes(f, [0xF3], ElfError::UnsupportedMachine)?;
 
     // seek to entry point
-    seek(f, 0x18).unwrap();
+    seek(f, 0x18)?;
 
     // extract entry point
-    let entry_point = u32_le(&read_bytes::<4>(f).unwrap());
+    let entry_point = u32_le(&read_bytes::<R, 4>(f)?);
 
     // extract program header table offset
-    let program_header_table_offset = u32_le(&read_bytes::<4>(f).unwrap());
+    let program_header_table_offset = u32_le(&read_bytes::<R, 4>(f)?);
+
+    // extract section header table offset
+    let section_header_table_offset = u32_le(&read_bytes::<R, 4>(f)?);
 
     // seek to program header size
-    seek(f, 0x2A).unwrap();
+    seek(f, 0x2A)?;
 
     // extract program header size
-    let program_header_entry_size = u32_le(&read_bytes::<2>(f).unwrap());
+    let program_header_entry_size = u32_le(&read_bytes::<R, 2>(f)?);
 
     // extract program header count
-    let program_entry_count = u32_le(&read_bytes::<2>(f).unwrap());
+    let program_entry_count = u32_le(&read_bytes::<R, 2>(f)?);
+
+    // extract section header size, count and the index of the section names
+    let section_header_entry_size = u32_le(&read_bytes::<R, 2>(f)?);
+    let section_entry_count = u32_le(&read_bytes::<R, 2>(f)?);
+    let section_names_index = u32_le(&read_bytes::<R, 2>(f)?);
 
-    ElfHeaderInfo {
+    Ok(ElfHeaderInfo {
         entry_point,
         program_header_table_offset,
         program_header_entry_size,
         program_entry_count,
-    }
+        section_header_table_offset,
+        section_header_entry_size,
+        section_entry_count,
+        section_names_index,
+    })
 }
 
-fn parse_program_header(f: &mut BufReader<File>, offset: u32) -> Option<ProgramHeaderInfo> {
+fn parse_program_header<R: Read + Seek>(
+    f: &mut R,
+    offset: u32,
+) -> Result<Option<ProgramHeaderInfo>, ElfError> {
     // seek to offset
-    seek(f, offset).unwrap();
+    seek(f, offset)?;
 
     // read type
-    let p_type = u32_le(&read_bytes::<4>(f).unwrap());
+    let p_type = u32_le(&read_bytes::<R, 4>(f)?);
 
     // ensure program header is of type LOAD
     if p_type != 1 {
-        return None;
+        return Ok(None);
     }
 
-    let p_offset = u32_le(&read_bytes::<4>(f).unwrap());
-    let virtual_address = u32_le(&read_bytes::<4>(f).unwrap());
+    let p_offset = u32_le(&read_bytes::<R, 4>(f)?);
+    let virtual_address = u32_le(&read_bytes::<R, 4>(f)?);
 
     // seek to p_filesz
-    seek(f, offset + 0x10).unwrap();
+    seek(f, field(offset, 0x10)?)?;
 
-    let p_filesz = u32_le(&read_bytes::<4>(f).unwrap());
-    let p_memsz = u32_le(&read_bytes::<4>(f).unwrap());
-    let p_flags = u32_le(&read_bytes::<4>(f).unwrap());
+    let p_filesz = u32_le(&read_bytes::<R, 4>(f)?);
+    let p_memsz = u32_le(&read_bytes::<R, 4>(f)?);
+    let p_flags = u32_le(&read_bytes::<R, 4>(f)?);
+
+    // the segment body must lie within the file before allocating a buffer for it
+    let body_end = field(p_offset, p_filesz)?;
+    if body_end as u64 > f.seek(SeekFrom::End(0))? {
+        return Err(ElfError::OutOfBounds);
+    }
 
     // seek to p_offset
-    seek(f, p_offset).unwrap();
+    seek(f, p_offset)?;
 
     // read header body
     let mut header_body = vec![0_u8; p_filesz as usize];
-    f.read_exact(&mut header_body).unwrap();
-
-    // decode flag
-    // EXECUTABLE (E) = 1, WRITEABLE (W) = 2, READABLE (R) = 4
-    // code = R + E = 4 + 1 = 5
-    let is_code = if p_flags == 5 {
-        true
-    } else if p_flags == 6 {
-        false
-    } else {
-        // neither code nor data panic
-        panic!("expected code or data program header body");
-    };
+    f.read_exact(&mut header_body)?;
 
-    Some(ProgramHeaderInfo {
+    Ok(Some(ProgramHeaderInfo {
         data: header_body,
+        file_offset: p_offset,
         virtual_address,
-        code: is_code,
-    })
+        memory_size: p_memsz,
+        flags: p_flags,
+    }))
+}
+
+/// Offset of entry `index` in a table of `entry_size` byte entries starting at `table_offset`
+fn table_entry(table_offset: u32, entry_size: u32, index: u32) -> Result<u32, ElfError> {
+    index
+        .checked_mul(entry_size)
+        .and_then(|offset| offset.checked_add(table_offset))
+        .ok_or(ElfError::OutOfBounds)
+}
+
+/// Offset of a field relative to the start of its header
+fn field(offset: u32, field_offset: u32) -> Result<u32, ElfError> {
+    offset
+        .checked_add(field_offset)
+        .ok_or(ElfError::OutOfBounds)
 }
 
-fn read_bytes<const N: usize>(f: &mut BufReader<File>) -> io::Result<[u8; N]> {
+fn read_bytes<R: Read, const N: usize>(f: &mut R) -> io::Result<[u8; N]> {
     let mut buffer = [0_u8; N];
     f.read_exact(&mut buffer)?;
     Ok(buffer)
 }
 
-fn seek(f: &mut BufReader<File>, offset_from_start: u32) -> io::Result<u64> {
+fn seek<R: Seek>(f: &mut R, offset_from_start: u32) -> io::Result<u64> {
     f.seek(SeekFrom::Start(offset_from_start as u64))
 }
 
@@ -183,16 +346,90 @@ pub(crate) fn u32_le(data: &[u8]) -> u32 {
     buffer[..len].copy_from_slice(&data[..len]);
     u32::from_le_bytes(buffer)
 }
+
 #[cfg(test)]
 mod test {
-    use crate::elf::{parse_elf_header, parse_program_header};
+    use crate::elf::{
+        parse_elf, parse_elf_bytes, parse_elf_header, parse_program_header, ElfError, PF_R, PF_W,
+        PF_X,
+    };
+    use std::fs;
     use std::fs::File;
     use std::io::BufReader;
 
+    #[test]
+    fn test_parse_elf_bytes() {
+        let bytes = fs::read("e2e-tests/rv32ui-p-add").unwrap();
+        let from_bytes = parse_elf_bytes(&bytes).unwrap();
+        let from_file = parse_elf("e2e-tests/rv32ui-p-add".to_string()).unwrap();
+        assert_eq!(from_bytes.entry_point, from_file.entry_point);
+        assert_eq!(from_bytes.segments, from_file.segments);
+        assert_eq!(from_bytes.segments.len(), 2);
+        assert_eq!(from_bytes.tohost, Some(0x80001000));
+        assert_eq!(from_bytes.end, 0x80001048);
+        // the program headers are not part of a loaded segment
+        assert_eq!(from_bytes.program_headers, None);
+        assert_eq!(from_bytes.program_header_count, 3);
+    }
+
+    #[test]
+    fn test_invalid_elf() {
+        assert!(matches!(
+            parse_elf_bytes(&[0x7f, 0x45, 0x4c, 0x00]),
+            Err(ElfError::InvalidMagicNumber)
+        ));
+        assert!(matches!(parse_elf_bytes(&[0x7f]), Err(ElfError::Io(_))));
+    }
+
+    #[test]
+    fn test_read_only_segment() {
+        let mut bytes = fs::read("e2e-tests/rv32ui-p-add").unwrap();
+        // p_flags of the data segment, its header is at 116
+        bytes[116 + 0x18..116 + 0x1c].copy_from_slice(&PF_R.to_le_bytes());
+        let program = parse_elf_bytes(&bytes).unwrap();
+        let flags: Vec<u32> = program.segments.iter().map(|s| s.flags).collect();
+        assert_eq!(flags, [PF_R | PF_X, PF_R]);
+        assert_eq!(program.segments[1].address, 0x80001000);
+    }
+
+    #[test]
+    fn test_segment_out_of_bounds() {
+        let mut bytes = fs::read("e2e-tests/rv32ui-p-add").unwrap();
+        // p_filesz of the code segment, its header is at 84
+        bytes[84 + 0x10..84 + 0x14].copy_from_slice(&0xffff_f000_u32.to_le_bytes());
+        assert!(matches!(
+            parse_elf_bytes(&bytes),
+            Err(ElfError::OutOfBounds)
+        ));
+
+        // section header table that wraps around the address space
+        let mut bytes = fs::read("e2e-tests/rv32ui-p-add").unwrap();
+        bytes[0x20..0x24].copy_from_slice(&0xffff_fff0_u32.to_le_bytes());
+        assert!(matches!(
+            parse_elf_bytes(&bytes),
+            Err(ElfError::OutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn test_program_headers_in_segment() {
+        let mut bytes = fs::read("e2e-tests/rv32ui-p-add").unwrap();
+        // load the code segment from the start of the file, the table is at 0x34..0x94
+        bytes[84 + 0x04..84 + 0x08].copy_from_slice(&0_u32.to_le_bytes());
+        bytes[84 + 0x10..84 + 0x14].copy_from_slice(&0x94_u32.to_le_bytes());
+        let program = parse_elf_bytes(&bytes).unwrap();
+        assert_eq!(program.program_headers, Some(0x8000_0034));
+
+        // only the start of the table is loaded
+        bytes[84 + 0x10..84 + 0x14].copy_from_slice(&0x93_u32.to_le_bytes());
+        let program = parse_elf_bytes(&bytes).unwrap();
+        assert_eq!(program.program_headers, None);
+    }
+
     #[test]
     fn test_elf_header_parsing() {
         let mut f = BufReader::new(File::open("e2e-tests/rv32ui-p-add").unwrap());
-        let header_info = parse_elf_header(&mut f);
+        let header_info = parse_elf_header(&mut f).unwrap();
         assert_eq!(header_info.entry_point, 0x80000000);
         assert_eq!(header_info.program_header_table_offset, 0x34);
         assert_eq!(header_info.program_header_entry_size, 32);
@@ -206,12 +443,12 @@ mod test {
         // first header is at offset 52, each header file is 32 bytes
         // hence offset values = 52, 84, 116
 
-        let header_one = parse_program_header(&mut f, 52);
+        let header_one = parse_program_header(&mut f, 52).unwrap();
         // should be none because it is not of type load
         assert!(header_one.is_none());
 
-        let header_two = parse_program_header(&mut f, 84).unwrap();
-        assert_eq!(header_two.code, true);
+        let header_two = parse_program_header(&mut f, 84).unwrap().unwrap();
+        assert_eq!(header_two.flags, PF_R | PF_X);
         assert_eq!(header_two.virtual_address, 0x80000000);
         assert_eq!(
             header_two.data,
@@ -343,8 +580,8 @@ mod test {
             ]
         );
 
-        let header_three = parse_program_header(&mut f, 116).unwrap();
-        assert_eq!(header_three.code, false);
+        let header_three = parse_program_header(&mut f, 116).unwrap().unwrap();
+        assert_eq!(header_three.flags, PF_R | PF_W);
         assert_eq!(header_three.virtual_address, 0x80001000);
         assert_eq!(
             header_three.data,
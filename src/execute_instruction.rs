@@ -1,8 +1,12 @@
-use crate::decode_instruction::{mask, sext, DecodedInstruction, Opcode, Register};
-use crate::elf::u32_le;
-use crate::vm::VM;
+use crate::csr::{Privilege, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW};
+use crate::decode_instruction::{mask, sext, DecodedInstruction, Opcode};
+use crate::mmu::AccessType;
+use crate::trap::Exception;
+use crate::vm::{Environment, VM};
 
-pub(crate) fn execute_instruction(vm: &mut VM, instruction: DecodedInstruction) {
+/// Executes a single decoded instruction, an exception is returned if the instruction traps.
+/// Illegal instruction exceptions are reported with a zero tval, the caller knows the encoding.
+pub fn execute_instruction(vm: &mut VM, instruction: DecodedInstruction) -> Result<(), Exception> {
     match instruction.opcode {
         // R Type Instructions
         Opcode::Add => {
@@ -53,6 +57,113 @@ pub(crate) fn execute_instruction(vm: &mut VM, instruction: DecodedInstruction)
             }
         }
 
+        // M Extension Instructions
+        Opcode::Mul => {
+            *vm.reg_mut(instruction.rd) = vm
+                .reg(instruction.rs1)
+                .wrapping_mul(vm.reg(instruction.rs2));
+        }
+        Opcode::Mulh => {
+            let product =
+                vm.reg(instruction.rs1) as i32 as i64 * vm.reg(instruction.rs2) as i32 as i64;
+            *vm.reg_mut(instruction.rd) = (product >> 32) as u32;
+        }
+        Opcode::Mulhsu => {
+            let product = vm.reg(instruction.rs1) as i32 as i64 * vm.reg(instruction.rs2) as i64;
+            *vm.reg_mut(instruction.rd) = (product >> 32) as u32;
+        }
+        Opcode::Mulhu => {
+            let product = vm.reg(instruction.rs1) as u64 * vm.reg(instruction.rs2) as u64;
+            *vm.reg_mut(instruction.rd) = (product >> 32) as u32;
+        }
+        Opcode::Div => {
+            let dividend = vm.reg(instruction.rs1) as i32;
+            let divisor = vm.reg(instruction.rs2) as i32;
+            // division by zero returns -1, overflow (i32::MIN / -1) returns the dividend
+            *vm.reg_mut(instruction.rd) = if divisor == 0 {
+                u32::MAX
+            } else {
+                dividend.wrapping_div(divisor) as u32
+            };
+        }
+        Opcode::Divu => {
+            let dividend = vm.reg(instruction.rs1);
+            let divisor = vm.reg(instruction.rs2);
+            // division by zero returns 2^32 - 1
+            *vm.reg_mut(instruction.rd) = dividend.checked_div(divisor).unwrap_or(u32::MAX);
+        }
+        Opcode::Rem => {
+            let dividend = vm.reg(instruction.rs1) as i32;
+            let divisor = vm.reg(instruction.rs2) as i32;
+            // division by zero returns the dividend, overflow (i32::MIN % -1) returns 0
+            *vm.reg_mut(instruction.rd) = if divisor == 0 {
+                dividend as u32
+            } else {
+                dividend.wrapping_rem(divisor) as u32
+            };
+        }
+        Opcode::Remu => {
+            let dividend = vm.reg(instruction.rs1);
+            let divisor = vm.reg(instruction.rs2);
+            // division by zero returns the dividend
+            *vm.reg_mut(instruction.rd) = dividend.checked_rem(divisor).unwrap_or(dividend);
+        }
+
+        // A Extension Instructions
+        Opcode::LrW => {
+            let mem_addr = vm.reg(instruction.rs1);
+            if !mem_addr.is_multiple_of(4) {
+                return Err(Exception::LoadAddressMisaligned(mem_addr));
+            }
+            *vm.reg_mut(instruction.rd) = vm.load(mem_addr, 4)?;
+            vm.reservation = Some(mem_addr);
+        }
+        Opcode::ScW => {
+            let mem_addr = vm.reg(instruction.rs1);
+            if !mem_addr.is_multiple_of(4) {
+                return Err(Exception::StoreAddressMisaligned(mem_addr));
+            }
+            // the store only succeeds if the address is still reserved
+            // the reservation is invalidated either way
+            if vm.reservation.take() == Some(mem_addr) {
+                vm.store(mem_addr, 4, vm.reg(instruction.rs2))?;
+                *vm.reg_mut(instruction.rd) = 0;
+            } else {
+                *vm.reg_mut(instruction.rd) = 1;
+            }
+        }
+        Opcode::AmoswapW
+        | Opcode::AmoaddW
+        | Opcode::AmoxorW
+        | Opcode::AmoandW
+        | Opcode::AmoorW
+        | Opcode::AmominW
+        | Opcode::AmomaxW
+        | Opcode::AmominuW
+        | Opcode::AmomaxuW => {
+            let mem_addr = vm.reg(instruction.rs1);
+            // amos must be naturally aligned, they are reported as store faults
+            if !mem_addr.is_multiple_of(4) {
+                return Err(Exception::StoreAddressMisaligned(mem_addr));
+            }
+            let mem_data = vm.load_with(mem_addr, 4, AccessType::Store)?;
+            let reg_data = vm.reg(instruction.rs2);
+            let result = match instruction.opcode {
+                Opcode::AmoswapW => reg_data,
+                Opcode::AmoaddW => mem_data.wrapping_add(reg_data),
+                Opcode::AmoxorW => mem_data ^ reg_data,
+                Opcode::AmoandW => mem_data & reg_data,
+                Opcode::AmoorW => mem_data | reg_data,
+                Opcode::AmominW => (mem_data as i32).min(reg_data as i32) as u32,
+                Opcode::AmomaxW => (mem_data as i32).max(reg_data as i32) as u32,
+                Opcode::AmominuW => mem_data.min(reg_data),
+                Opcode::AmomaxuW => mem_data.max(reg_data),
+                _ => unreachable!(),
+            };
+            vm.store(mem_addr, 4, result)?;
+            *vm.reg_mut(instruction.rd) = mem_data;
+        }
+
         // I Arithmetic Instructions
         Opcode::Addi => {
             *vm.reg_mut(instruction.rd) = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
@@ -96,29 +207,29 @@ pub(crate) fn execute_instruction(vm: &mut VM, instruction: DecodedInstruction)
         // Load Instructions
         Opcode::Lb => {
             let mem_addr = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
-            let mem_data = u32_le(&vm.mem32(mem_addr));
+            let mem_data = vm.load(mem_addr, 1)?;
             let mem_half_data = sext(mem_data & mask(8), 8);
             *vm.reg_mut(instruction.rd) = mem_half_data;
         }
         Opcode::Lh => {
             let mem_addr = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
-            let mem_data = u32_le(&vm.mem32(mem_addr));
+            let mem_data = vm.load(mem_addr, 2)?;
             let mem_half_data = sext(mem_data & mask(16), 16);
             *vm.reg_mut(instruction.rd) = mem_half_data;
         }
         Opcode::Lw => {
             let mem_addr = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
-            *vm.reg_mut(instruction.rd) = u32_le(&vm.mem32(mem_addr));
+            *vm.reg_mut(instruction.rd) = vm.load(mem_addr, 4)?;
         }
         Opcode::Lbu => {
             let mem_addr = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
-            let mem_data = u32_le(&vm.mem32(mem_addr));
+            let mem_data = vm.load(mem_addr, 1)?;
             let mem_half_data = mem_data & mask(8);
             *vm.reg_mut(instruction.rd) = mem_half_data;
         }
         Opcode::Lhu => {
             let mem_addr = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
-            let mem_data = u32_le(&vm.mem32(mem_addr));
+            let mem_data = vm.load(mem_addr, 2)?;
             let mem_half_data = mem_data & mask(16);
             *vm.reg_mut(instruction.rd) = mem_half_data;
         }
@@ -126,122 +237,155 @@ pub(crate) fn execute_instruction(vm: &mut VM, instruction: DecodedInstruction)
         // Store Instructions
         Opcode::Sb => {
             let mem_addr = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
-            let reg_data = vm.reg(instruction.rs2).to_le_bytes();
-            *vm.mem_mut(mem_addr) = reg_data[0];
+            vm.store(mem_addr, 1, vm.reg(instruction.rs2))?;
         }
         Opcode::Sh => {
             let mem_addr = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
-            let reg_data = vm.reg(instruction.rs2).to_le_bytes();
-            for i in 0..2 {
-                *vm.mem_mut(mem_addr + i) = reg_data[i as usize];
-            }
+            vm.store(mem_addr, 2, vm.reg(instruction.rs2))?;
         }
         Opcode::Sw => {
             let mem_addr = vm.reg(instruction.rs1).wrapping_add(instruction.imm);
-            let reg_data = vm.reg(instruction.rs2).to_le_bytes();
-            for i in 0..4 {
-                *vm.mem_mut(mem_addr + i) = reg_data[i as usize];
-            }
+            vm.store(mem_addr, 4, vm.reg(instruction.rs2))?;
         }
 
         // Branch Instructions
         Opcode::Beq => {
             if vm.reg(instruction.rs1) == vm.reg(instruction.rs2) {
                 vm.pc = vm.pc.wrapping_add(instruction.imm);
-                return;
+                return Ok(());
             }
         }
         Opcode::Bne => {
             if vm.reg(instruction.rs1) != vm.reg(instruction.rs2) {
                 vm.pc = vm.pc.wrapping_add(instruction.imm);
-                return;
+                return Ok(());
             }
         }
         Opcode::Blt => {
             if (vm.reg(instruction.rs1) as i32) < (vm.reg(instruction.rs2) as i32) {
                 vm.pc = vm.pc.wrapping_add(instruction.imm);
-                return;
+                return Ok(());
             }
         }
         Opcode::Bge => {
             if (vm.reg(instruction.rs1) as i32) >= (vm.reg(instruction.rs2) as i32) {
                 vm.pc = vm.pc.wrapping_add(instruction.imm);
-                return;
+                return Ok(());
             }
         }
         Opcode::Bltu => {
             if vm.reg(instruction.rs1) < vm.reg(instruction.rs2) {
                 vm.pc = vm.pc.wrapping_add(instruction.imm);
-                return;
+                return Ok(());
             }
         }
         Opcode::Bgeu => {
             if vm.reg(instruction.rs1) >= vm.reg(instruction.rs2) {
                 vm.pc = vm.pc.wrapping_add(instruction.imm);
-                return;
+                return Ok(());
             }
         }
 
         // Jump Instructions
         Opcode::Jal => {
-            *vm.reg_mut(instruction.rd) = vm.pc.wrapping_add(4);
+            *vm.reg_mut(instruction.rd) = vm.pc.wrapping_add(instruction.size());
             vm.pc = vm.pc.wrapping_add(instruction.imm);
-            return;
+            return Ok(());
         }
         Opcode::Jalr => {
             let rs1_value = vm.reg(instruction.rs1);
-            *vm.reg_mut(instruction.rd) = vm.pc.wrapping_add(4);
-            vm.pc = rs1_value.wrapping_add(instruction.imm);
-            return;
+            *vm.reg_mut(instruction.rd) = vm.pc.wrapping_add(instruction.size());
+            vm.pc = rs1_value.wrapping_add(instruction.imm) & !1;
+            return Ok(());
         }
 
         Opcode::Lui => *vm.reg_mut(instruction.rd) = instruction.imm,
         Opcode::Auipc => *vm.reg_mut(instruction.rd) = vm.pc.wrapping_add(instruction.imm),
 
         // System Instructions
-        Opcode::Ecall => {
-            let function = vm.reg(Register::A7 as u32);
-            match function {
-                1 => {
-                    // write register as decimal
-                    let file_descriptor = vm.reg(Register::A0 as u32);
-                    let register_addr = vm.reg(Register::A1 as u32);
-                    match file_descriptor {
-                        1 => println!("{}", register_addr),
-                        2 => eprintln!("{}", register_addr),
-                        _ => panic!("invalid file descriptor to print"),
-                    }
-                }
-                64 => {
-                    // write string
-                    let file_descriptor = vm.reg(Register::A0 as u32);
-                    let addr = vm.reg(Register::A1 as u32) as usize;
-                    let len = vm.reg(Register::A2 as u32) as usize;
-                    let bytes: &[u8] = &vm.memory[addr..(addr + len)];
-                    let to_print =
-                        String::from_utf8(bytes.to_vec()).expect("invalid print argument");
-                    match file_descriptor {
-                        1 => println!("{}", to_print),
-                        2 => eprintln!("{}", to_print),
-                        _ => panic!("invalid file descriptor for print"),
-                    };
-                }
-                93 => {
-                    // halt
-                    let exit_code = vm.reg(Register::A0 as u32);
-                    vm.halted = true;
-                    vm.exit_code = exit_code;
+        Opcode::Ecall
+            if vm.environment == Environment::Sbi && vm.privilege == Privilege::Supervisor =>
+        {
+            vm.sbi_call();
+        }
+        Opcode::Ecall if vm.environment != Environment::Emulated => {
+            return Err(Exception::environment_call(vm.privilege));
+        }
+        Opcode::Ecall => vm.syscall()?,
+        Opcode::Ebreak => return Err(Exception::Breakpoint(vm.pc)),
+        Opcode::Mret => {
+            if vm.privilege != Privilege::Machine {
+                return Err(Exception::IllegalInstruction(0));
+            }
+            vm.mret();
+            return Ok(());
+        }
+        Opcode::Sret => {
+            // tsr traps sret in supervisor mode so machine mode can emulate it
+            let trapped =
+                vm.privilege == Privilege::Supervisor && vm.csr.mstatus & MSTATUS_TSR != 0;
+            if vm.privilege == Privilege::User || trapped {
+                return Err(Exception::IllegalInstruction(0));
+            }
+            vm.sret();
+            return Ok(());
+        }
+        Opcode::SfenceVma => {
+            // tvm traps sfence.vma so machine mode can emulate it
+            let trapped =
+                vm.privilege == Privilege::Supervisor && vm.csr.mstatus & MSTATUS_TVM != 0;
+            if vm.privilege == Privilege::User || trapped {
+                return Err(Exception::IllegalInstruction(0));
+            }
+            // cached entries are tagged with satp, so the asid operand is not needed
+            let vaddr = (instruction.rs1 != 0).then(|| vm.reg(instruction.rs1));
+            vm.tlb.flush(vaddr);
+        }
+        Opcode::Wfi => {
+            // wfi may complete at any time, so it is a nop and pending
+            // interrupts are delivered before the next instruction
+            // tw makes it illegal outside of machine mode
+            if vm.privilege != Privilege::Machine && vm.csr.mstatus & MSTATUS_TW != 0 {
+                return Err(Exception::IllegalInstruction(0));
+            }
+        }
+        // Zicsr Instructions
+        Opcode::Csrrw
+        | Opcode::Csrrs
+        | Opcode::Csrrc
+        | Opcode::Csrrwi
+        | Opcode::Csrrsi
+        | Opcode::Csrrci => {
+            let csr_addr = instruction.imm & mask(12);
+            // immediate variants use the rs1 field as a 5 bit zero extended immediate
+            let operand = match instruction.opcode {
+                Opcode::Csrrw | Opcode::Csrrs | Opcode::Csrrc => vm.reg(instruction.rs1),
+                _ => instruction.rs1,
+            };
+
+            if vm.csr.check_access(csr_addr, vm.privilege).is_err() {
+                return Err(Exception::IllegalInstruction(0));
+            }
+            let Ok(csr_value) = vm.csr.read(csr_addr) else {
+                return Err(Exception::IllegalInstruction(0));
+            };
+
+            // set and clear don't write the csr if rs1 is x0 (or the immediate is 0)
+            let new_value = match instruction.opcode {
+                Opcode::Csrrw | Opcode::Csrrwi => Some(operand),
+                Opcode::Csrrs | Opcode::Csrrsi => {
+                    (instruction.rs1 != 0).then_some(csr_value | operand)
                 }
-                _ => {
-                    eprintln!("skipping ecall a7 reg: {}", function);
+                _ => (instruction.rs1 != 0).then_some(csr_value & !operand),
+            };
+
+            if let Some(new_value) = new_value {
+                if vm.csr.write(csr_addr, new_value).is_err() {
+                    return Err(Exception::IllegalInstruction(0));
                 }
             }
-        }
-        Opcode::Ebreak => {
-            unimplemented!()
-        }
-        Opcode::Eother => {
-            // skipping execution of this instruction
+
+            *vm.reg_mut(instruction.rd) = csr_value;
         }
         Opcode::Fence => {
             // skipping execution of this instruction
@@ -249,5 +393,6 @@ pub(crate) fn execute_instruction(vm: &mut VM, instruction: DecodedInstruction)
     }
 
     // update pc
-    vm.pc = vm.pc.wrapping_add(4);
+    vm.pc = vm.pc.wrapping_add(instruction.size());
+    Ok(())
 }
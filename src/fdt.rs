@@ -0,0 +1,351 @@
+// Flattened device tree describing the emulated platform, passed to kernels in a1
+// Specification: https://github.com/devicetree-org/devicetree-specification
+
+use crate::clint::{CLINT_BASE, CLINT_SIZE};
+use crate::plic::{PLIC_BASE, PLIC_SIZE, PLIC_SOURCES};
+use crate::trap::Interrupt;
+use crate::uart::{UART_INTERRUPT, UART_SIZE};
+use crate::virtio::VIRTIO_SIZE;
+use crate::vm::VM;
+
+/// Start of ram on qemu's virt machine, where kernels and firmware are loaded
+pub const RAM_BASE: u32 = 0x8000_0000;
+/// Ram described to the guest when no memory limit is set
+pub const DEFAULT_RAM_SIZE: u32 = 128 << 20;
+
+/// Frequency of mtime reported in the device tree
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+// a single terminating entry
+const RESERVE_MAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+
+// the uart's input clock, only used by drivers to compute the divisor
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+const ISA: &str = "rv32imac_zicntr_zicsr_zifencei";
+const ISA_EXTENSIONS: &[&str] = &["i", "m", "a", "c", "zicntr", "zicsr", "zifencei"];
+
+/// Builds the structure and strings blocks of a device tree
+#[derive(Default)]
+pub(crate) struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    pub(crate) fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    pub(crate) fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    pub(crate) fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// A property with no value, like interrupt-controller
+    pub(crate) fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub(crate) fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    pub(crate) fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub(crate) fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub(crate) fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// Assembles the blob, every begin_node must have been matched by an end_node
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+
+        let structure_offset = HEADER_SIZE + RESERVE_MAP_SIZE;
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            // boot cpu
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob: Vec<u8> = header.iter().flat_map(|v| v.to_be_bytes()).collect();
+        blob.extend_from_slice(&[0; RESERVE_MAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+
+    fn token(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    /// Offset of name in the strings block, names are shared between properties
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for string in self.strings.split(|b| *b == 0) {
+            if string == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += string.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+}
+
+impl VM {
+    /// Amount of ram starting at `RAM_BASE` described to the guest
+    pub fn ram_size(&self) -> u32 {
+        let max = 0u32.wrapping_sub(RAM_BASE);
+        self.memory_limit
+            .map_or(DEFAULT_RAM_SIZE, |limit| limit.min(max as usize) as u32)
+    }
+
+    /// Generates a device tree describing the vm's memory, hart and devices
+    pub fn device_tree(&self) -> Vec<u8> {
+        let mut fdt = FdtWriter::default();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 1);
+        fdt.property_string("compatible", "riscv-virtio");
+        fdt.property_string("model", "riscv-vm");
+
+        fdt.begin_node("chosen");
+        if let Some(base) = self.uart {
+            fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", base));
+        }
+        if let Some(bootargs) = &self.bootargs {
+            fdt.property_string("bootargs", bootargs);
+        }
+        if let Some((start, end)) = self.initrd {
+            fdt.property_u32("linux,initrd-start", start);
+            fdt.property_u32("linux,initrd-end", end);
+        }
+        fdt.end_node();
+
+        fdt.begin_node(&format!("memory@{:x}", RAM_BASE));
+        fdt.property_string("device_type", "memory");
+        fdt.property_cells("reg", &[RAM_BASE, self.ram_size()]);
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+        fdt.begin_node("cpu@0");
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", 0);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", ISA);
+        fdt.property_string("riscv,isa-base", "rv32i");
+        fdt.property_strings("riscv,isa-extensions", ISA_EXTENSIONS);
+        fdt.property_string("mmu-type", "riscv,sv32");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 1);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+
+        fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_cells("reg", &[CLINT_BASE, CLINT_SIZE]);
+        fdt.property_cells(
+            "interrupts-extended",
+            &[
+                CPU_INTC_PHANDLE,
+                Interrupt::MachineSoftware.code(),
+                CPU_INTC_PHANDLE,
+                Interrupt::MachineTimer.code(),
+            ],
+        );
+        fdt.end_node();
+
+        // plic context 0 is the hart's machine mode, context 1 its supervisor mode
+        fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_cells("reg", &[PLIC_BASE, PLIC_SIZE]);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_u32("riscv,ndev", PLIC_SOURCES - 1);
+        fdt.property_cells(
+            "interrupts-extended",
+            &[
+                CPU_INTC_PHANDLE,
+                Interrupt::MachineExternal.code(),
+                CPU_INTC_PHANDLE,
+                Interrupt::SupervisorExternal.code(),
+            ],
+        );
+        fdt.property_u32("phandle", PLIC_PHANDLE);
+        fdt.end_node();
+
+        if let Some(base) = self.uart {
+            fdt.begin_node(&format!("serial@{:x}", base));
+            fdt.property_string("compatible", "ns16550a");
+            fdt.property_cells("reg", &[base, UART_SIZE]);
+            fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.property_u32("interrupts", UART_INTERRUPT);
+            fdt.end_node();
+        }
+
+        for (base, interrupt) in &self.virtio {
+            fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+            fdt.property_string("compatible", "virtio,mmio");
+            fdt.property_cells("reg", &[*base, VIRTIO_SIZE]);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.property_u32("interrupts", *interrupt);
+            fdt.end_node();
+        }
+
+        fdt.end_node();
+        fdt.end_node();
+        fdt.finish()
+    }
+
+    /// Places the device tree at the end of ram and passes it to the guest, see `load_device_tree_at`
+    pub fn load_device_tree(&mut self) -> u32 {
+        let addr = self.default_device_tree_address();
+        self.load_device_tree_at(addr);
+        addr
+    }
+
+    pub(crate) fn default_device_tree_address(&self) -> u32 {
+        let size = self.device_tree().len() as u32;
+        // like qemu, 2MiB aligned so the kernel can map it with a single megapage
+        let end = RAM_BASE.wrapping_add(self.ram_size());
+        end.wrapping_sub(size) & !((2 << 20) - 1)
+    }
+
+    /// Writes the device tree to addr and sets up the boot registers,
+    /// a0 holds the hart id and a1 the address of the device tree
+    pub fn load_device_tree_at(&mut self, addr: u32) {
+        let blob = self.device_tree();
+        self.write_memory(addr, &blob);
+        self.set_reg(10, 0);
+        self.set_reg(11, addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fdt::{FdtWriter, FDT_MAGIC, RAM_BASE};
+    use crate::uart::{Uart16550, UART_BASE};
+    use crate::vm::VM;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn contains(blob: &[u8], needle: &[u8]) -> bool {
+        blob.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_writer_layout() {
+        let mut fdt = FdtWriter::default();
+        fdt.begin_node("");
+        fdt.property_u32("a", 7);
+        fdt.property_string("b", "xyz");
+        fdt.property_u32("a", 8);
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        // "a" is stored once and shared by both properties
+        assert_eq!(&blob[be32(&blob, 12) as usize..], b"a\0b\0");
+        let structure = be32(&blob, 8) as usize;
+        assert_eq!(be32(&blob, 36), 4 + 4 + 16 + 16 + 16 + 4 + 4);
+        // begin node, empty name padded to 4 bytes, then the first property
+        assert_eq!(
+            &blob[structure..structure + 24],
+            &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 7]
+        );
+    }
+
+    #[test]
+    fn test_load_device_tree() {
+        let mut vm = VM::init();
+        vm.set_memory_limit(Some(64 << 20));
+        let addr = vm.load_device_tree();
+        assert_eq!(addr, RAM_BASE + (62 << 20));
+        assert_eq!(vm.reg(11), addr);
+        let blob = vm.read_memory(addr, vm.device_tree().len());
+        assert_eq!(blob, vm.device_tree());
+        assert!(contains(&blob, b"memory@80000000\0"));
+        assert!(contains(&blob, &[0x80, 0, 0, 0, 0x04, 0, 0, 0]));
+        assert!(!contains(&blob, b"serial@"));
+
+        vm.add_uart(UART_BASE, Uart16550::new(Box::new(Vec::new())))
+            .unwrap();
+        let blob = vm.device_tree();
+        assert!(contains(&blob, b"serial@10000000\0"));
+        assert!(contains(&blob, b"/soc/serial@10000000\0"));
+    }
+}
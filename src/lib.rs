@@ -1,4 +1,48 @@
+mod boot;
+mod bus;
+mod capture;
+mod clint;
+mod csr;
+mod decode_compressed_instruction;
 mod decode_instruction;
 mod elf;
 mod execute_instruction;
+mod fdt;
+mod memory;
+mod mmu;
+mod plic;
+mod pmp;
+mod process;
+mod sbi;
+mod syscall;
+mod trap;
+mod uart;
+mod vfs;
+mod virtio;
 mod vm;
+
+pub use crate::boot::{BootConfig, BootError};
+pub use crate::bus::{BusError, Device};
+pub use crate::capture::CaptureBuffer;
+pub use crate::clint::{TimerSource, CLINT_BASE};
+pub use crate::csr::Privilege;
+pub use crate::decode_compressed_instruction::{decode_compressed_instruction, is_compressed};
+pub use crate::decode_instruction::{
+    decode_instruction, DecodeError, DecodedInstruction, InstructionType, Opcode, Register,
+};
+pub use crate::elf::{
+    parse_elf, parse_elf_bytes, ElfError, ProgramInfo, Segment, PF_R, PF_W, PF_X,
+};
+pub use crate::execute_instruction::execute_instruction;
+pub use crate::fdt::{DEFAULT_RAM_SIZE, RAM_BASE, TIMEBASE_FREQUENCY};
+pub use crate::memory::{Memory, PagedMemory, PAGE_SIZE};
+pub use crate::plic::{PLIC_BASE, PLIC_SOURCES};
+pub use crate::process::{ProcessConfig, DEFAULT_STACK_TOP};
+pub use crate::syscall::{LinuxSyscalls, SyscallAction, SyscallHandler};
+pub use crate::trap::{Exception, Interrupt};
+pub use crate::uart::{Uart16550, UART_BASE, UART_INTERRUPT, UART_SIZE};
+pub use crate::vfs::{Filesystem, HostDir, MemoryFs};
+pub use crate::virtio::{
+    DiskImage, VirtioBlock, SECTOR_SIZE, VIRTIO_BASE, VIRTIO_INTERRUPT, VIRTIO_SIZE,
+};
+pub use crate::vm::{Environment, HaltReason, VM};
@@ -0,0 +1,365 @@
+use riscv::{
+    BootConfig, Filesystem, HaltReason, HostDir, ProcessConfig, Uart16550, VirtioBlock, UART_BASE,
+    VIRTIO_BASE, VIRTIO_INTERRUPT, VIRTIO_SIZE, VM,
+};
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+const USAGE: &str = "usage: riscv [options] <elf> [args...]
+       riscv [options] --kernel <Image>
+
+options:
+  --max-instructions <n>  halt after executing n instructions
+  --memory-size <bytes>   limit guest memory, accepts K, M and G suffixes
+  --trace                 print every executed instruction to stderr
+  --env <KEY=VALUE>       add an environment variable for the program, repeatable
+  --stack-top <addr>      grow the program's stack down from the hex address
+                          instead of 0x80000000
+  --root <dir>            host directory the program sees as its filesystem root, paths
+                          can't leave it, without it the program gets an empty in memory
+                          filesystem
+  --uart [addr]           map a 16550 uart console at 0x10000000 unless a hex address is
+                          given, it writes to stdout and reads stdin when booting a kernel,
+                          elf programs read stdin through read(0) instead
+  --disk <image>          attach a virtio block device backed by the image file,
+                          repeat for more disks
+  --device-tree           place a device tree describing the machine at the end of ram
+                          and pass its address in a1
+
+linux boot options:
+  --kernel <Image>        boot a raw linux kernel image in supervisor mode with the
+                          built in sbi firmware, maps a uart unless --uart is given
+  --initrd <file>         load an initramfs for the kernel
+  --append <cmdline>      kernel command line
+  --kernel-addr <addr>    load the kernel at the hex address instead of the default
+  --initrd-addr <addr>    load the initramfs at the hex address instead of the default
+  --dtb-addr <addr>       place the device tree at the hex address instead of the default
+  -h, --help              print this message";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    elf_path: String,
+    program_args: Vec<String>,
+    env: Vec<String>,
+    stack_top: Option<u32>,
+    root: Option<String>,
+    max_instructions: Option<u64>,
+    memory_size: Option<usize>,
+    trace: bool,
+    uart: Option<u32>,
+    disks: Vec<String>,
+    device_tree: bool,
+    kernel: Option<String>,
+    initrd: Option<String>,
+    bootargs: Option<String>,
+    kernel_addr: Option<u32>,
+    initrd_addr: Option<u32>,
+    device_tree_addr: Option<u32>,
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut vm = if options.kernel.is_some() {
+        VM::init()
+    } else {
+        let mut args = vec![options.elf_path.clone()];
+        args.extend(options.program_args.iter().cloned());
+        let config = ProcessConfig {
+            args,
+            env: options.env.clone(),
+            stack_top: options.stack_top,
+        };
+        match VM::init_from_elf_with_config(options.elf_path.clone(), &config) {
+            Ok(vm) => vm,
+            Err(err) => {
+                eprintln!("error: failed to load {}: {:?}", options.elf_path, err);
+                process::exit(2);
+            }
+        }
+    };
+
+    if options.kernel.is_none() {
+        // host files are only reachable when asked for
+        if let Some(root) = &options.root {
+            match HostDir::new(root) {
+                Ok(dir) => vm.set_filesystem(Filesystem::Host(dir)),
+                Err(err) => {
+                    eprintln!("error: failed to open {}: {}", root, err);
+                    process::exit(2);
+                }
+            }
+        }
+    }
+
+    vm.set_memory_limit(options.memory_size);
+    vm.set_trace(options.trace);
+
+    // disks take consecutive virtio slots, like qemu's virt machine
+    for (i, path) in options.disks.iter().enumerate() {
+        let block = match VirtioBlock::open(path) {
+            Ok(block) => block,
+            Err(err) => {
+                eprintln!("error: failed to open {}: {}", path, err);
+                process::exit(2);
+            }
+        };
+        let base = VIRTIO_BASE + i as u32 * VIRTIO_SIZE;
+        if let Err(err) = vm.add_virtio_block(base, VIRTIO_INTERRUPT + i as u32, block) {
+            eprintln!("error: failed to map {}: {:?}", path, err);
+            process::exit(2);
+        }
+    }
+
+    // a kernel always gets a console
+    let uart = match options.kernel {
+        Some(_) => options.uart.or(Some(UART_BASE)),
+        None => options.uart,
+    };
+    if let Some(base) = uart {
+        // an elf program reads stdin itself, two readers would race for its bytes
+        let device = match options.kernel {
+            Some(_) => Uart16550::stdio(),
+            None => Uart16550::new(Box::new(io::stdout())),
+        };
+        if let Err(err) = vm.add_uart(base, device) {
+            eprintln!("error: failed to map the uart at {:#x}: {:?}", base, err);
+            process::exit(2);
+        }
+    }
+
+    // generated last so it describes every mapped device
+    if let Some(kernel) = &options.kernel {
+        let config = BootConfig {
+            kernel_addr: options.kernel_addr,
+            initrd: options.initrd.as_ref().map(|path| read_file(path)),
+            initrd_addr: options.initrd_addr,
+            device_tree_addr: options.device_tree_addr,
+            bootargs: options.bootargs.clone(),
+        };
+        if let Err(err) = vm.boot_linux(&read_file(kernel), config) {
+            eprintln!("error: failed to boot {}: {:?}", kernel, err);
+            process::exit(2);
+        }
+    } else if options.device_tree {
+        vm.load_device_tree();
+    }
+
+    match options.max_instructions {
+        Some(limit) => vm.run_with_limit(limit),
+        None => vm.run(),
+    }
+
+    match vm.halt_reason() {
+        Some(HaltReason::Exit) | None => {}
+        Some(reason) => eprintln!("halted: {:?}", reason),
+    }
+
+    process::exit(vm.exit_code() as i32);
+}
+
+/// Parses the command line arguments (excluding the program name)
+/// returns None if help was requested
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter().peekable();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--trace" => options.trace = true,
+            "--device-tree" => options.device_tree = true,
+            "--max-instructions" => {
+                let value = args.next().ok_or("--max-instructions expects a value")?;
+                options.max_instructions = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid instruction count: {}", value))?,
+                );
+            }
+            "--uart" => {
+                // the address is optional, so only consume a hex value
+                let base = args.peek().and_then(|value| parse_hex(value));
+                if base.is_some() {
+                    args.next();
+                }
+                options.uart = Some(base.unwrap_or(UART_BASE));
+            }
+            "--kernel" => options.kernel = Some(args.next().ok_or("--kernel expects a value")?),
+            "--initrd" => options.initrd = Some(args.next().ok_or("--initrd expects a value")?),
+            "--append" => options.bootargs = Some(args.next().ok_or("--append expects a value")?),
+            "--env" => options
+                .env
+                .push(args.next().ok_or("--env expects a value")?),
+            "--root" => options.root = Some(args.next().ok_or("--root expects a value")?),
+            "--stack-top" => options.stack_top = Some(address_arg(&arg, args.next())?),
+            "--kernel-addr" => options.kernel_addr = Some(address_arg(&arg, args.next())?),
+            "--initrd-addr" => options.initrd_addr = Some(address_arg(&arg, args.next())?),
+            "--dtb-addr" => options.device_tree_addr = Some(address_arg(&arg, args.next())?),
+            "--disk" => {
+                let value = args.next().ok_or("--disk expects a value")?;
+                options.disks.push(value);
+            }
+            "--memory-size" => {
+                let value = args.next().ok_or("--memory-size expects a value")?;
+                options.memory_size = Some(parse_size(&value)?);
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+            _ => {
+                options.elf_path = arg;
+                break;
+            }
+        }
+    }
+
+    match (&options.kernel, options.elf_path.is_empty()) {
+        (None, true) => return Err("missing elf path".to_string()),
+        (Some(_), false) => return Err("--kernel can't be combined with an elf".to_string()),
+        _ => {}
+    }
+
+    // everything after the elf path belongs to the guest
+    options.program_args = args.collect();
+
+    Ok(Some(options))
+}
+
+/// Parses the hex address value of option
+fn address_arg(option: &str, value: Option<String>) -> Result<u32, String> {
+    let value = value.ok_or(format!("{} expects a value", option))?;
+    parse_hex(&value).ok_or(format!("invalid address: {}", value))
+}
+
+/// Reads a file passed on the command line, exits if it can't be read
+fn read_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("error: failed to read {}: {}", path, err);
+        process::exit(2);
+    })
+}
+
+/// Parses a 0x prefixed hex address
+fn parse_hex(value: &str) -> Option<u32> {
+    let digits = value.strip_prefix("0x")?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Parses a byte count with an optional K, M or G suffix
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or(format!("invalid memory size: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_args, parse_size, Options};
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(args(&[
+            "--trace",
+            "--max-instructions",
+            "100",
+            "--memory-size",
+            "16M",
+            "prog.elf",
+            "--not-an-option",
+            "foo",
+        ]))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            options,
+            Options {
+                elf_path: "prog.elf".to_string(),
+                program_args: args(&["--not-an-option", "foo"]),
+                max_instructions: Some(100),
+                memory_size: Some(16 << 20),
+                trace: true,
+                ..Options::default()
+            }
+        );
+
+        let options = parse_args(args(&["--uart", "prog.elf"])).unwrap().unwrap();
+        assert_eq!(options.uart, Some(0x1000_0000));
+        let options = parse_args(args(&["--uart", "0x4000", "prog.elf"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.uart, Some(0x4000));
+        let options = parse_args(args(&["--disk", "a.img", "--disk", "b.img", "prog.elf"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.disks, args(&["a.img", "b.img"]));
+        let options = parse_args(args(&[
+            "--env",
+            "HOME=/",
+            "--stack-top",
+            "0x40000000",
+            "--root",
+            "/srv",
+            "prog.elf",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.env, args(&["HOME=/"]));
+        assert_eq!(options.stack_top, Some(0x4000_0000));
+        assert_eq!(options.root.as_deref(), Some("/srv"));
+
+        let options = parse_args(args(&[
+            "--kernel",
+            "Image",
+            "--append",
+            "console=ttyS0",
+            "--initrd-addr",
+            "0x84000000",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.kernel.as_deref(), Some("Image"));
+        assert_eq!(options.bootargs.as_deref(), Some("console=ttyS0"));
+        assert_eq!(options.initrd_addr, Some(0x8400_0000));
+        assert!(parse_args(args(&["--kernel", "Image", "prog.elf"])).is_err());
+        assert!(parse_args(args(&["--kernel", "Image", "--dtb-addr", "84000000"])).is_err());
+
+        assert_eq!(parse_args(args(&["--help"])), Ok(None));
+        assert!(parse_args(args(&[])).is_err());
+        assert!(parse_args(args(&["--bogus", "prog.elf"])).is_err());
+        assert!(parse_args(args(&["--max-instructions", "x", "prog.elf"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+}
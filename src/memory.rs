@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+/// Size of a single memory page in bytes
+pub const PAGE_SIZE: usize = 1 << 12;
+
+type Page = Box<[u8; PAGE_SIZE]>;
+
+/// Byte addressable view of the 32 bit guest address space
+pub trait Memory {
+    /// Returns the byte stored at addr
+    fn byte(&self, addr: u32) -> u8;
+
+    /// Returns a mutable reference to the byte stored at addr
+    fn byte_mut(&mut self, addr: u32) -> &mut u8;
+
+    /// Number of bytes of host memory backing the guest memory
+    fn allocated_bytes(&self) -> usize;
+
+    /// Reads len bytes starting at addr, wrapping around the address space
+    fn read_bytes(&self, addr: u32, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.byte(addr.wrapping_add(i as u32)))
+            .collect()
+    }
+
+    /// Writes data starting at addr, wrapping around the address space
+    fn write_bytes(&mut self, addr: u32, data: &[u8]) {
+        for (i, value) in data.iter().enumerate() {
+            *self.byte_mut(addr.wrapping_add(i as u32)) = *value;
+        }
+    }
+
+    /// Sets len bytes starting at addr to zero, wrapping around the address space
+    fn zero(&mut self, addr: u32, len: u32) {
+        for i in 0..len {
+            *self.byte_mut(addr.wrapping_add(i)) = 0;
+        }
+    }
+}
+
+/// Sparse memory made of lazily allocated fixed size pages.
+/// Pages that have never been written to are not backed by any storage and read as zero.
+#[derive(Default)]
+pub struct PagedMemory {
+    pages: HashMap<u32, Page>,
+}
+
+impl PagedMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Splits an address into (page number, offset within page)
+fn split_addr(addr: u32) -> (u32, usize) {
+    (addr / PAGE_SIZE as u32, addr as usize & (PAGE_SIZE - 1))
+}
+
+impl Memory for PagedMemory {
+    fn byte(&self, addr: u32) -> u8 {
+        let (page_number, offset) = split_addr(addr);
+        self.pages.get(&page_number).map_or(0, |page| page[offset])
+    }
+
+    fn byte_mut(&mut self, addr: u32) -> &mut u8 {
+        let (page_number, offset) = split_addr(addr);
+        let page = self
+            .pages
+            .entry(page_number)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE]));
+        &mut page[offset]
+    }
+
+    fn allocated_bytes(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    /// Whole pages are released and partially covered ones cleared, nothing is allocated
+    fn zero(&mut self, addr: u32, len: u32) {
+        let mut addr = addr;
+        let mut remaining = len as usize;
+        while remaining > 0 {
+            let (page_number, offset) = split_addr(addr);
+            let count = (PAGE_SIZE - offset).min(remaining);
+            if count == PAGE_SIZE {
+                self.pages.remove(&page_number);
+            } else if let Some(page) = self.pages.get_mut(&page_number) {
+                page[offset..offset + count].fill(0);
+            }
+            addr = addr.wrapping_add(count as u32);
+            remaining -= count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{Memory, PagedMemory, PAGE_SIZE};
+
+    #[test]
+    fn test_untouched_memory_reads_zero() {
+        let memory = PagedMemory::new();
+        assert_eq!(memory.byte(0), 0);
+        assert_eq!(memory.byte(0x8000_0000), 0);
+        assert_eq!(memory.byte(u32::MAX), 0);
+        assert_eq!(memory.pages.len(), 0);
+    }
+
+    #[test]
+    fn test_pages_are_allocated_lazily() {
+        let mut memory = PagedMemory::new();
+        *memory.byte_mut(0x8000_0004) = 0xab;
+        assert_eq!(memory.byte(0x8000_0004), 0xab);
+        assert_eq!(memory.pages.len(), 1);
+
+        // same page, no new allocation
+        *memory.byte_mut(0x8000_0005) = 0xcd;
+        assert_eq!(memory.pages.len(), 1);
+
+        // reading does not allocate
+        assert_eq!(memory.byte(0x1000_0000), 0);
+        assert_eq!(memory.pages.len(), 1);
+    }
+
+    #[test]
+    fn test_bytes_across_page_boundary() {
+        let mut memory = PagedMemory::new();
+        let addr = (PAGE_SIZE - 2) as u32;
+        memory.write_bytes(addr, &[1, 2, 3, 4]);
+        assert_eq!(memory.read_bytes(addr, 4), vec![1, 2, 3, 4]);
+        assert_eq!(memory.pages.len(), 2);
+
+        // writes wrap around the end of the address space
+        memory.write_bytes(u32::MAX, &[5, 6]);
+        assert_eq!(memory.byte(u32::MAX), 5);
+        assert_eq!(memory.byte(0), 6);
+    }
+
+    #[test]
+    fn test_zero() {
+        let mut memory = PagedMemory::new();
+        memory.write_bytes(0, &[1; 3 * PAGE_SIZE]);
+
+        // the whole middle page is released, the edges are cleared in place
+        memory.zero(PAGE_SIZE as u32 - 1, PAGE_SIZE as u32 + 2);
+        assert_eq!(memory.pages.len(), 2);
+        assert_eq!(memory.byte(PAGE_SIZE as u32 - 2), 1);
+        assert!(memory
+            .read_bytes(PAGE_SIZE as u32 - 1, PAGE_SIZE + 2)
+            .iter()
+            .all(|byte| *byte == 0));
+        assert_eq!(memory.byte(2 * PAGE_SIZE as u32 + 1), 1);
+
+        // zeroing untouched memory allocates nothing
+        memory.zero(0x8000_0000, u32::MAX / 2);
+        assert_eq!(memory.pages.len(), 2);
+    }
+}
@@ -0,0 +1,353 @@
+// Sv32 virtual memory
+// Specification: The RISC-V Instruction Set Manual Volume II: Privileged Architecture (Sv32)
+
+use crate::csr::{Privilege, MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SUM};
+use crate::memory::PAGE_SIZE;
+use crate::trap::Exception;
+use crate::vm::VM;
+
+// satp fields
+const SATP_MODE_SV32: u32 = 1 << 31;
+const SATP_PPN: u32 = (1 << 22) - 1;
+
+// page table entry fields
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+const TLB_ENTRIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(self, vaddr: u32) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionPageFault(vaddr),
+            AccessType::Load => Exception::LoadPageFault(vaddr),
+            AccessType::Store => Exception::StorePageFault(vaddr),
+        }
+    }
+
+    pub(crate) fn access_fault(self, addr: u32) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionAccessFault(addr),
+            AccessType::Load => Exception::LoadAccessFault(addr),
+            AccessType::Store => Exception::StoreAccessFault(addr),
+        }
+    }
+}
+
+/// A cached leaf page table entry
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    // satp the entry was translated under
+    satp: u32,
+    vpn: u32,
+    // physical address of the start of the (super)page
+    base: u64,
+    // mask of the page offset bits, larger for megapages
+    offset_mask: u32,
+    pte: u32,
+}
+
+/// Direct mapped cache of recent translations, indexed by the low bits of the virtual page number
+pub(crate) struct Tlb {
+    entries: [Option<TlbEntry>; TLB_ENTRIES],
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self {
+            entries: [None; TLB_ENTRIES],
+        }
+    }
+}
+
+impl Tlb {
+    fn lookup(&self, satp: u32, vaddr: u32) -> Option<TlbEntry> {
+        let vpn = vaddr >> 12;
+        self.entries[vpn as usize % TLB_ENTRIES]
+            .filter(|entry| entry.satp == satp && entry.vpn == vpn)
+    }
+
+    fn insert(&mut self, entry: TlbEntry) {
+        self.entries[entry.vpn as usize % TLB_ENTRIES] = Some(entry);
+    }
+
+    /// Drops the translations for vaddr, or every translation if vaddr is None
+    pub(crate) fn flush(&mut self, vaddr: Option<u32>) {
+        match vaddr {
+            Some(vaddr) => {
+                // megapages are cached under the page that was accessed, so any
+                // entry whose range covers vaddr has to go
+                for entry in self.entries.iter_mut() {
+                    if let Some(cached) = entry {
+                        if (cached.vpn << 12) & !cached.offset_mask == vaddr & !cached.offset_mask {
+                            *entry = None;
+                        }
+                    }
+                }
+            }
+            None => self.entries = [None; TLB_ENTRIES],
+        }
+    }
+}
+
+impl VM {
+    /// Translates a virtual address to a physical address for the given access
+    pub(crate) fn translate(&mut self, vaddr: u32, access: AccessType) -> Result<u32, Exception> {
+        let satp = self.csr.satp;
+        if satp & SATP_MODE_SV32 == 0 || self.effective_privilege(access) == Privilege::Machine {
+            return Ok(vaddr);
+        }
+
+        let entry = match self.tlb.lookup(satp, vaddr) {
+            // a missing accessed or dirty bit takes the slow path so it gets set
+            Some(entry)
+                if entry.pte & PTE_A != 0
+                    && (access != AccessType::Store || entry.pte & PTE_D != 0) =>
+            {
+                entry
+            }
+            _ => {
+                let entry = self.walk(satp, vaddr, access)?;
+                self.tlb.insert(entry);
+                entry
+            }
+        };
+
+        self.check_permissions(entry.pte, access)
+            .ok_or(access.page_fault(vaddr))?;
+
+        let paddr = entry.base + (vaddr & entry.offset_mask) as u64;
+        // sv32 produces 34 bit physical addresses, only the lower 4GiB exist
+        u32::try_from(paddr).map_err(|_| access.access_fault(vaddr))
+    }
+
+    /// Privilege used for translation, mprv makes machine mode loads and stores use mpp
+    pub(crate) fn effective_privilege(&self, access: AccessType) -> Privilege {
+        if access != AccessType::Fetch
+            && self.privilege == Privilege::Machine
+            && self.csr.mstatus & MSTATUS_MPRV != 0
+        {
+            return Privilege::from_bits(self.csr.mstatus >> 11);
+        }
+        self.privilege
+    }
+
+    fn check_permissions(&self, pte: u32, access: AccessType) -> Option<()> {
+        let mstatus = self.csr.mstatus;
+        let allowed = match access {
+            AccessType::Fetch => pte & PTE_X != 0,
+            // mxr makes executable pages readable
+            AccessType::Load => {
+                pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0)
+            }
+            AccessType::Store => pte & PTE_W != 0,
+        };
+
+        let user_page = pte & PTE_U != 0;
+        let privilege_ok = match self.effective_privilege(access) {
+            Privilege::User => user_page,
+            // supervisor mode never executes user pages, sum allows loads and stores
+            Privilege::Supervisor => {
+                !user_page || (access != AccessType::Fetch && mstatus & MSTATUS_SUM != 0)
+            }
+            Privilege::Machine => true,
+        };
+
+        (allowed && privilege_ok).then_some(())
+    }
+
+    /// Walks the two level page table, setting the accessed and dirty bits of the leaf entry
+    fn walk(&mut self, satp: u32, vaddr: u32, access: AccessType) -> Result<TlbEntry, Exception> {
+        let page_fault = access.page_fault(vaddr);
+        let vpn = [(vaddr >> 12) & 0x3ff, vaddr >> 22];
+
+        let mut table = (satp & SATP_PPN) as u64 * PAGE_SIZE as u64;
+        let mut level = 1;
+        loop {
+            let pte_addr = u32::try_from(table + vpn[level] as u64 * 4)
+                .map_err(|_| access.access_fault(vaddr))?;
+            // page table reads are loads whatever the original access, and a failed
+            // pmp check is reported as an access fault of the original access
+            let mut pte = self
+                .physical_load(pte_addr, 4, AccessType::Load)
+                .map_err(|_| access.access_fault(vaddr))?;
+
+            // invalid, or the reserved write only encoding
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(page_fault);
+            }
+
+            let ppn = (pte >> 10) as u64;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // pointer to the next level
+                if level == 0 {
+                    return Err(page_fault);
+                }
+                level -= 1;
+                table = ppn * PAGE_SIZE as u64;
+                continue;
+            }
+
+            // megapages must be aligned to 4MiB
+            if level == 1 && ppn & 0x3ff != 0 {
+                return Err(page_fault);
+            }
+            self.check_permissions(pte, access).ok_or(page_fault)?;
+
+            let mut updated = pte | PTE_A;
+            if access == AccessType::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                pte = updated;
+                self.physical_store(pte_addr, 4, pte, AccessType::Store)
+                    .map_err(|_| access.access_fault(vaddr))?;
+            }
+
+            let offset_mask = if level == 1 {
+                (1 << 22) - 1
+            } else {
+                (1 << 12) - 1
+            };
+            return Ok(TlbEntry {
+                satp,
+                vpn: vaddr >> 12,
+                base: (ppn * PAGE_SIZE as u64) & !(offset_mask as u64),
+                offset_mask,
+                pte,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr::{Privilege, MSTATUS_SUM};
+    use crate::mmu::{AccessType, PTE_A, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X};
+    use crate::trap::Exception;
+    use crate::vm::VM;
+
+    const ROOT: u32 = 0x1000;
+    const LEAF_TABLE: u32 = 0x2000;
+
+    /// Maps 0x4000_0000 -> 0x5000 (4KiB page) and 0x8000_0000 -> 0x8000_0000 (megapage)
+    fn paged_vm(leaf_flags: u32) -> VM {
+        let mut vm = VM::init();
+        let pointer = ((LEAF_TABLE >> 12) << 10) | PTE_V;
+        vm.write_memory(ROOT + (0x4000_0000 >> 22) * 4, &pointer.to_le_bytes());
+        let leaf = ((0x5000 >> 12) << 10) | leaf_flags;
+        vm.write_memory(LEAF_TABLE, &leaf.to_le_bytes());
+        let megapage = ((0x8000_0000_u32 >> 12) << 10) | PTE_V | PTE_R | PTE_W | PTE_X;
+        vm.write_memory(ROOT + (0x8000_0000 >> 22) * 4, &megapage.to_le_bytes());
+
+        vm.csr.satp = (1 << 31) | (ROOT >> 12);
+        vm.privilege = Privilege::Supervisor;
+        // pmp napot entry covering all of memory
+        vm.csr.write(0x3b0, u32::MAX).unwrap();
+        vm.csr.write(0x3a0, 0x1f).unwrap();
+        vm
+    }
+
+    fn pte(vm: &VM) -> u32 {
+        u32::from_le_bytes(vm.mem32(LEAF_TABLE))
+    }
+
+    #[test]
+    fn test_translation_and_accessed_dirty_bits() {
+        let mut vm = paged_vm(PTE_V | PTE_R | PTE_W);
+
+        assert_eq!(vm.translate(0x4000_0123, AccessType::Load), Ok(0x5123));
+        assert_eq!(pte(&vm) & (PTE_A | PTE_D), PTE_A);
+
+        // the cached entry has no dirty bit so the store walks the table again
+        assert_eq!(vm.translate(0x4000_0456, AccessType::Store), Ok(0x5456));
+        assert_eq!(pte(&vm) & (PTE_A | PTE_D), PTE_A | PTE_D);
+
+        assert_eq!(
+            vm.translate(0x8012_3456, AccessType::Fetch),
+            Ok(0x8012_3456)
+        );
+
+        // machine mode is never translated
+        vm.privilege = Privilege::Machine;
+        assert_eq!(vm.translate(0x4000_0123, AccessType::Load), Ok(0x4000_0123));
+    }
+
+    #[test]
+    fn test_page_faults() {
+        let mut vm = paged_vm(PTE_V | PTE_R | PTE_U);
+
+        // unmapped
+        assert_eq!(
+            vm.translate(0x1000, AccessType::Load),
+            Err(Exception::LoadPageFault(0x1000))
+        );
+        // read only
+        assert_eq!(
+            vm.translate(0x4000_0000, AccessType::Store),
+            Err(Exception::StorePageFault(0x4000_0000))
+        );
+        // supervisor can only access user pages with sum
+        assert_eq!(
+            vm.translate(0x4000_0010, AccessType::Load),
+            Err(Exception::LoadPageFault(0x4000_0010))
+        );
+        vm.csr.mstatus |= MSTATUS_SUM;
+        assert_eq!(vm.translate(0x4000_0010, AccessType::Load), Ok(0x5010));
+        // user mode cannot access supervisor pages
+        vm.privilege = Privilege::User;
+        assert_eq!(
+            vm.translate(0x8000_0000, AccessType::Fetch),
+            Err(Exception::InstructionPageFault(0x8000_0000))
+        );
+    }
+
+    #[test]
+    fn test_page_table_pmp_checks() {
+        let mut vm = paged_vm(PTE_V | PTE_R | PTE_X | PTE_A);
+        // pmp entry 0 makes the page tables below 0x3000 read only,
+        // entry 1 is a napot entry covering the rest of memory
+        vm.csr.write(0x3b0, 0x3000 >> 2).unwrap();
+        vm.csr.write(0x3b1, u32::MAX).unwrap();
+        vm.csr.write(0x3a0, 0x1f09).unwrap();
+
+        // reading the page tables for a fetch only needs read permission
+        assert_eq!(vm.translate(0x4000_0010, AccessType::Fetch), Ok(0x5010));
+
+        // setting the accessed bit of the megapage is a store to the read only tables
+        assert_eq!(
+            vm.translate(0x8000_0000, AccessType::Load),
+            Err(Exception::LoadAccessFault(0x8000_0000))
+        );
+        assert_eq!(
+            vm.translate(0x8000_0000, AccessType::Fetch),
+            Err(Exception::InstructionAccessFault(0x8000_0000))
+        );
+    }
+
+    #[test]
+    fn test_sfence_flushes_stale_translations() {
+        let mut vm = paged_vm(PTE_V | PTE_R | PTE_A);
+        assert_eq!(vm.translate(0x4000_0000, AccessType::Load), Ok(0x5000));
+
+        // remap the page, the tlb still holds the old translation
+        let leaf = ((0x6000 >> 12) << 10) | PTE_V | PTE_R | PTE_A;
+        vm.write_memory(LEAF_TABLE, &leaf.to_le_bytes());
+        assert_eq!(vm.translate(0x4000_0000, AccessType::Load), Ok(0x5000));
+
+        vm.tlb.flush(Some(0x4000_0000));
+        assert_eq!(vm.translate(0x4000_0000, AccessType::Load), Ok(0x6000));
+    }
+}
@@ -0,0 +1,212 @@
+// Platform level interrupt controller, the sifive compatible layout used by qemu
+// Specification: https://github.com/riscv/riscv-plic-spec
+// context 0 is the hart's machine mode, context 1 its supervisor mode
+
+use crate::bus::Device;
+
+pub const PLIC_BASE: u32 = 0x0c00_0000;
+pub(crate) const PLIC_SIZE: u32 = 0x0400_0000;
+
+/// Number of interrupt sources, source 0 does not exist
+pub const PLIC_SOURCES: u32 = 32;
+const CONTEXTS: usize = 2;
+
+const PRIORITY: u32 = 0x0;
+const PENDING: u32 = 0x1000;
+const ENABLE: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+// priorities and thresholds are 3 bits wide
+const PRIORITY_MASK: u32 = 0b111;
+
+#[derive(Default)]
+pub(crate) struct Plic {
+    priority: [u32; PLIC_SOURCES as usize],
+    // one bit per source
+    pending: u32,
+    // claimed and not yet completed
+    claimed: u32,
+    // current level of the interrupt lines
+    level: u32,
+    enable: [u32; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+}
+
+impl Plic {
+    /// Sets the level of a device's interrupt line
+    pub(crate) fn set_level(&mut self, source: u32, high: bool) {
+        if source == 0 || source >= PLIC_SOURCES {
+            return;
+        }
+        let bit = 1 << source;
+        if high {
+            self.level |= bit;
+            // the gateway forwards one request at a time
+            if self.claimed & bit == 0 {
+                self.pending |= bit;
+            }
+        } else {
+            self.level &= !bit;
+        }
+    }
+
+    /// The highest priority pending source that is enabled for the context
+    /// and above its threshold, ties go to the lowest source id
+    fn best(&self, context: usize) -> Option<u32> {
+        let candidates = self.pending & self.enable[context];
+        let mut best: Option<(u32, u32)> = None;
+        for source in 1..PLIC_SOURCES {
+            let priority = self.priority[source as usize];
+            if candidates & (1 << source) == 0 || priority <= self.threshold[context] {
+                continue;
+            }
+            if best.is_none_or(|(_, best_priority)| priority > best_priority) {
+                best = Some((source, priority));
+            }
+        }
+        best.map(|(source, _)| source)
+    }
+
+    /// Whether the context's interrupt line (meip or seip) is raised
+    pub(crate) fn interrupt(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.best(context) else {
+            return 0;
+        };
+        self.pending &= !(1 << source);
+        self.claimed |= 1 << source;
+        source
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        // completions for sources that are not enabled are ignored
+        if source == 0 || source >= PLIC_SOURCES || self.enable[context] & (1 << source) == 0 {
+            return;
+        }
+        self.claimed &= !(1 << source);
+        // level triggered, a line that is still high is pending again
+        if self.level & (1 << source) != 0 {
+            self.pending |= 1 << source;
+        }
+    }
+}
+
+// only aligned 32 bit accesses are supported, anything else reads as zero and is ignored
+impl Device for Plic {
+    fn read(&mut self, offset: u32, size: u32) -> u32 {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return 0;
+        }
+        match offset {
+            PRIORITY..PENDING => self
+                .priority
+                .get((offset / 4) as usize)
+                .copied()
+                .unwrap_or(0),
+            PENDING => self.pending,
+            ENABLE..CONTEXT => match context_register(offset - ENABLE, ENABLE_STRIDE) {
+                Some((context, 0)) => self.enable[context],
+                _ => 0,
+            },
+            _ => match context_register(offset.wrapping_sub(CONTEXT), CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context],
+                Some((context, 4)) => self.claim(context),
+                _ => 0,
+            },
+        }
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: u32) {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return;
+        }
+        match offset {
+            PRIORITY..PENDING => {
+                // source 0 does not exist
+                let source = (offset / 4) as usize;
+                if (1..PLIC_SOURCES as usize).contains(&source) {
+                    self.priority[source] = value & PRIORITY_MASK;
+                }
+            }
+            ENABLE..CONTEXT => {
+                if let Some((context, 0)) = context_register(offset - ENABLE, ENABLE_STRIDE) {
+                    self.enable[context] = value & !1;
+                }
+            }
+            _ => match context_register(offset.wrapping_sub(CONTEXT), CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context] = value & PRIORITY_MASK,
+                Some((context, 4)) => self.complete(context, value),
+                _ => {}
+            },
+        }
+    }
+}
+
+/// Splits an offset into a per context block into (context, offset within the block)
+fn context_register(offset: u32, stride: u32) -> Option<(usize, u32)> {
+    let context = (offset / stride) as usize;
+    (context < CONTEXTS).then_some((context, offset % stride))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Device;
+    use crate::plic::{Plic, CONTEXT, CONTEXT_STRIDE, ENABLE, ENABLE_STRIDE, PENDING};
+
+    const CLAIM_M: u32 = CONTEXT + 4;
+    const CLAIM_S: u32 = CONTEXT + CONTEXT_STRIDE + 4;
+
+    #[test]
+    fn test_priority_threshold_and_claim() {
+        let mut plic = Plic::default();
+        // source priorities live at 4 * source
+        plic.write(4, 4, 1);
+        plic.write(8, 4, 5);
+        plic.write(ENABLE, 4, 0b110);
+
+        plic.set_level(1, true);
+        plic.set_level(2, true);
+        assert_eq!(plic.read(PENDING, 4), 0b110);
+        assert!(plic.interrupt(0));
+        // not enabled for the supervisor context
+        assert!(!plic.interrupt(1));
+
+        // the threshold masks source 1 but not source 2
+        plic.write(CONTEXT, 4, 1);
+        assert_eq!(plic.read(CLAIM_M, 4), 2);
+        assert!(!plic.interrupt(0));
+        assert_eq!(plic.read(CLAIM_M, 4), 0);
+
+        plic.write(CONTEXT, 4, 0);
+        assert_eq!(plic.read(CLAIM_M, 4), 1);
+        assert_eq!(plic.read(PENDING, 4), 0);
+    }
+
+    #[test]
+    fn test_complete_rearms_level_triggered_sources() {
+        let mut plic = Plic::default();
+        plic.write(12, 4, 1);
+        plic.write(ENABLE + ENABLE_STRIDE, 4, 1 << 3);
+
+        plic.set_level(3, true);
+        assert_eq!(plic.read(CLAIM_S, 4), 3);
+
+        // still in service, the line staying high does not retrigger
+        plic.set_level(3, true);
+        assert!(!plic.interrupt(1));
+
+        plic.write(CLAIM_S, 4, 3);
+        assert!(plic.interrupt(1));
+        assert_eq!(plic.read(CLAIM_S, 4), 3);
+
+        // after the device lowers its line completion leaves nothing pending
+        plic.set_level(3, false);
+        plic.write(CLAIM_S, 4, 3);
+        assert!(!plic.interrupt(1));
+    }
+}
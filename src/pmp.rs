@@ -0,0 +1,229 @@
+// Physical memory protection
+// Specification: The RISC-V Instruction Set Manual Volume II: Privileged Architecture (PMP)
+
+use crate::csr::Privilege;
+use crate::mmu::AccessType;
+
+pub(crate) const PMPCFG0: u32 = 0x3a0;
+pub(crate) const PMPADDR0: u32 = 0x3b0;
+
+const PMP_ENTRIES: usize = 16;
+
+// pmpcfg fields
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+
+// address matching modes
+const PMP_TOR: u8 = 1 << 3;
+const PMP_NA4: u8 = 2 << 3;
+const PMP_NAPOT: u8 = 3 << 3;
+
+#[derive(Default)]
+pub(crate) struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u32; PMP_ENTRIES],
+}
+
+impl Pmp {
+    /// Reads a pmpcfg or pmpaddr csr, None if addr is not a pmp csr
+    /// the csrs of the entries that are not implemented read as zero
+    pub(crate) fn read(&self, addr: u32) -> Option<u32> {
+        match addr {
+            0x3a0..=0x3af => {
+                let first = (addr - PMPCFG0) as usize * 4;
+                Some(u32::from_le_bytes(
+                    [0, 1, 2, 3].map(|i| self.cfg.get(first + i).copied().unwrap_or(0)),
+                ))
+            }
+            0x3b0..=0x3ef => Some(
+                self.addr
+                    .get((addr - PMPADDR0) as usize)
+                    .copied()
+                    .unwrap_or(0),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Writes a pmpcfg or pmpaddr csr, returns false if addr is not a pmp csr
+    /// writes to locked entries are ignored
+    pub(crate) fn write(&mut self, addr: u32, value: u32) -> bool {
+        match addr {
+            0x3a0..=0x3af => {
+                let first = (addr - PMPCFG0) as usize * 4;
+                for (i, cfg) in value.to_le_bytes().into_iter().enumerate() {
+                    let index = first + i;
+                    if index >= PMP_ENTRIES || self.locked(index) {
+                        continue;
+                    }
+                    // WARL, the reserved write without read combination clears write
+                    let cfg = if cfg & PMP_R == 0 { cfg & !PMP_W } else { cfg };
+                    // bits 6:5 are reserved
+                    self.cfg[index] = cfg & (PMP_R | PMP_W | PMP_X | PMP_A | PMP_L);
+                }
+            }
+            0x3b0..=0x3ef => {
+                let index = (addr - PMPADDR0) as usize;
+                if index >= PMP_ENTRIES || self.locked(index) {
+                    return true;
+                }
+                // a locked top of range entry also locks the address below it
+                let next = index + 1;
+                if next < PMP_ENTRIES && self.locked(next) && self.cfg[next] & PMP_A == PMP_TOR {
+                    return true;
+                }
+                self.addr[index] = value;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn locked(&self, index: usize) -> bool {
+        self.cfg[index] & PMP_L != 0
+    }
+
+    /// Address range [start, end) covered by an entry, None if the entry is off
+    fn range(&self, index: usize) -> Option<(u64, u64)> {
+        // pmpaddr holds bits 33:2 of the address
+        let addr = self.addr[index] as u64;
+        match self.cfg[index] & PMP_A {
+            PMP_TOR => {
+                let start = if index == 0 {
+                    0
+                } else {
+                    (self.addr[index - 1] as u64) << 2
+                };
+                Some((start, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_NAPOT => {
+                // the number of trailing ones encodes the size, 8 bytes and up
+                let ones = addr.trailing_ones();
+                let start = (addr & !((1 << ones) - 1)) << 2;
+                Some((start, start + (1 << (ones + 3))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks whether an access of size bytes at addr is allowed
+    pub(crate) fn check(
+        &self,
+        addr: u32,
+        size: u32,
+        access: AccessType,
+        privilege: Privilege,
+    ) -> bool {
+        // machine mode is only restricted by locked entries
+        if privilege == Privilege::Machine && !(0..PMP_ENTRIES).any(|i| self.locked(i)) {
+            return true;
+        }
+
+        let start = addr as u64;
+        let end = start + size as u64;
+        for index in 0..PMP_ENTRIES {
+            let Some((entry_start, entry_end)) = self.range(index) else {
+                continue;
+            };
+            // the lowest numbered entry that matches any byte decides
+            if start >= entry_end || end <= entry_start {
+                continue;
+            }
+            // partially matching accesses fail
+            if start < entry_start || end > entry_end {
+                return false;
+            }
+
+            if privilege == Privilege::Machine && !self.locked(index) {
+                return true;
+            }
+            let permission = match access {
+                AccessType::Fetch => PMP_X,
+                AccessType::Load => PMP_R,
+                AccessType::Store => PMP_W,
+            };
+            return self.cfg[index] & permission != 0;
+        }
+
+        // with entries implemented, unmatched accesses only succeed in machine mode
+        privilege == Privilege::Machine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr::Privilege;
+    use crate::mmu::AccessType;
+    use crate::pmp::{
+        Pmp, PMPADDR0, PMPCFG0, PMP_L, PMP_NA4, PMP_NAPOT, PMP_R, PMP_TOR, PMP_W, PMP_X,
+    };
+
+    #[test]
+    fn test_address_matching() {
+        let mut pmp = Pmp::default();
+        // entry 0: tor [0, 0x1000) r
+        // entry 1: na4 [0x2000, 0x2004) rw
+        // entry 2: napot [0x8000_0000, 0x8000_1000) rwx
+        pmp.write(PMPADDR0, 0x1000 >> 2);
+        pmp.write(PMPADDR0 + 1, 0x2000 >> 2);
+        pmp.write(PMPADDR0 + 2, (0x8000_0000 >> 2) | ((0x1000 >> 3) - 1));
+        let cfg = u32::from_le_bytes([
+            PMP_TOR | PMP_R,
+            PMP_NA4 | PMP_R | PMP_W,
+            PMP_NAPOT | PMP_R | PMP_W | PMP_X,
+            0,
+        ]);
+        pmp.write(PMPCFG0, cfg);
+        assert_eq!(pmp.read(PMPCFG0), Some(cfg));
+
+        let user = |pmp: &Pmp, addr: u32, size: u32, access: AccessType| {
+            pmp.check(addr, size, access, Privilege::User)
+        };
+        assert!(user(&pmp, 0x0ffc, 4, AccessType::Load));
+        assert!(!user(&pmp, 0x0ffc, 4, AccessType::Store));
+        // straddles the end of entry 0
+        assert!(!user(&pmp, 0x0ffe, 4, AccessType::Load));
+        assert!(user(&pmp, 0x2000, 4, AccessType::Store));
+        assert!(!user(&pmp, 0x2004, 1, AccessType::Load));
+        assert!(user(&pmp, 0x8000_0ffc, 4, AccessType::Fetch));
+        assert!(!user(&pmp, 0x8000_1000, 4, AccessType::Fetch));
+
+        // machine mode ignores unlocked entries and unmatched addresses
+        assert!(pmp.check(0x0ffc, 4, AccessType::Store, Privilege::Machine));
+        assert!(pmp.check(0x9000_0000, 4, AccessType::Store, Privilege::Machine));
+    }
+
+    #[test]
+    fn test_locked_entries() {
+        let mut pmp = Pmp::default();
+        pmp.write(PMPADDR0, 0x1000 >> 2);
+        pmp.write(PMPADDR0 + 1, 0x2000 >> 2);
+        pmp.write(
+            PMPCFG0,
+            u32::from_le_bytes([0, PMP_TOR | PMP_L | PMP_R, 0, 0]),
+        );
+
+        // locked entries apply to machine mode
+        assert!(pmp.check(0x1000, 4, AccessType::Load, Privilege::Machine));
+        assert!(!pmp.check(0x1000, 4, AccessType::Store, Privilege::Machine));
+
+        // the entry and the address below a locked tor entry can no longer change
+        pmp.write(PMPCFG0, 0);
+        pmp.write(PMPADDR0, 0);
+        pmp.write(PMPADDR0 + 1, 0);
+        assert_eq!(
+            pmp.read(PMPCFG0),
+            Some(((PMP_TOR | PMP_L | PMP_R) as u32) << 8)
+        );
+        assert_eq!(pmp.read(PMPADDR0), Some(0x1000 >> 2));
+        assert_eq!(pmp.read(PMPADDR0 + 1), Some(0x2000 >> 2));
+
+        // write without read is reserved
+        pmp.write(PMPCFG0 + 1, PMP_W as u32);
+        assert_eq!(pmp.read(PMPCFG0 + 1), Some(0));
+    }
+}
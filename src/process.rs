@@ -0,0 +1,173 @@
+// Initial stack of a linux user process: argc, argv, envp and the auxiliary vector
+// Specification: https://github.com/riscv-non-isa/riscv-elf-psabi-doc (process initialization)
+
+use crate::decode_instruction::Register;
+use crate::memory::PAGE_SIZE;
+use crate::syscall::random_bytes;
+use crate::vm::VM;
+
+/// Default address the stack grows down from, just below where the riscv-tests are linked
+pub const DEFAULT_STACK_TOP: u32 = 0x8000_0000;
+
+// the stack pointer is 16 byte aligned at process entry
+const STACK_ALIGNMENT: u32 = 16;
+const PROGRAM_HEADER_SIZE: u32 = 32;
+
+// auxiliary vector entry types
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_HWCAP: u32 = 16;
+const AT_SECURE: u32 = 23;
+const AT_RANDOM: u32 = 25;
+
+// one bit per single letter extension (bit 0 is A), the implemented i, m, a and c
+const HWCAP: u32 = 1 << 8 | 1 << 12 | 1 | 1 << 2;
+
+/// Arguments, environment and stack placement of a user program, see `VM::start_process`
+#[derive(Debug, Clone, Default)]
+pub struct ProcessConfig {
+    /// argv, including the program name as the first argument
+    pub args: Vec<String>,
+    /// Environment variables in KEY=VALUE form
+    pub env: Vec<String>,
+    /// Defaults to `DEFAULT_STACK_TOP`
+    pub stack_top: Option<u32>,
+}
+
+impl VM {
+    /// Lays out the initial stack below the stack top, points sp at argc and restarts at the
+    /// elf entry point, replacing the stack set up when the elf was loaded
+    pub fn start_process(&mut self, config: &ProcessConfig) {
+        let top = config.stack_top.unwrap_or(DEFAULT_STACK_TOP) & !(STACK_ALIGNMENT - 1);
+
+        // strings and the random bytes sit at the top, above the pointer arrays
+        let mut addr = top;
+        let mut push = |vm: &mut VM, bytes: &[u8]| {
+            addr = addr.wrapping_sub(bytes.len() as u32);
+            vm.write_memory(addr, bytes);
+            addr
+        };
+        let random = push(self, &random_bytes(16));
+        let mut string_pointers = |vm: &mut VM, strings: &[String]| {
+            strings
+                .iter()
+                .map(|string| push(vm, &[string.as_bytes(), &[0]].concat()))
+                .collect::<Vec<_>>()
+        };
+        let argv = string_pointers(self, &config.args);
+        let envp = string_pointers(self, &config.env);
+        let strings_bottom = addr;
+
+        let process = &self.process;
+        // an empty table when the headers aren't loaded, so nothing walks address 0
+        let (program_headers, program_header_count) = match process.program_headers {
+            Some(addr) => (addr, process.program_header_count),
+            None => (0, 0),
+        };
+        let auxv = [
+            (AT_PHDR, program_headers),
+            (AT_PHENT, PROGRAM_HEADER_SIZE),
+            (AT_PHNUM, program_header_count),
+            (AT_PAGESZ, PAGE_SIZE as u32),
+            (AT_ENTRY, process.entry_point),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, HWCAP),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_NULL, 0),
+        ];
+
+        // argc, argv and envp each terminated by a null pointer, then the auxv pairs
+        let mut words = vec![argv.len() as u32];
+        words.extend(&argv);
+        words.push(0);
+        words.extend(&envp);
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|(key, value)| [*key, *value]));
+
+        let size = words.len() as u32 * 4;
+        let sp = strings_bottom.wrapping_sub(size) & !(STACK_ALIGNMENT - 1);
+        let bytes = words
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.write_memory(sp, &bytes);
+
+        self.set_reg(Register::SP.into(), sp);
+        // no function for atexit to register
+        self.set_reg(Register::A0.into(), 0);
+        self.pc = self.process.entry_point;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decode_instruction::Register;
+    use crate::process::{
+        ProcessConfig, AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHNUM, AT_RANDOM,
+        DEFAULT_STACK_TOP,
+    };
+    use crate::vm::VM;
+
+    fn word(vm: &VM, addr: u32) -> u32 {
+        u32::from_le_bytes(vm.read_memory(addr, 4).try_into().unwrap())
+    }
+
+    fn string(vm: &VM, addr: u32) -> String {
+        let bytes = (addr..)
+            .map(|addr| vm.mem(addr))
+            .take_while(|byte| *byte != 0)
+            .collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_initial_stack() {
+        let config = ProcessConfig {
+            args: vec!["prog".to_string(), "hello".to_string()],
+            env: vec!["HOME=/".to_string()],
+            stack_top: Some(0x4000_0008),
+        };
+        let vm =
+            VM::init_from_elf_with_config("e2e-tests/rv32ui-p-add".to_string(), &config).unwrap();
+        // the stack is only laid out once, nothing is left at the default stack top
+        assert_eq!(vm.read_memory(DEFAULT_STACK_TOP - 256, 256), [0; 256]);
+
+        let sp = vm.reg(Register::SP.into());
+        assert!(sp < 0x4000_0000);
+        assert_eq!(sp % 16, 0);
+        assert_eq!(word(&vm, sp), 2);
+        assert_eq!(string(&vm, word(&vm, sp + 4)), "prog");
+        assert_eq!(string(&vm, word(&vm, sp + 8)), "hello");
+        assert_eq!(word(&vm, sp + 12), 0);
+        assert_eq!(string(&vm, word(&vm, sp + 16)), "HOME=/");
+        assert_eq!(word(&vm, sp + 20), 0);
+
+        // walk the auxiliary vector
+        let mut auxv = Vec::new();
+        let mut addr = sp + 24;
+        while word(&vm, addr) != AT_NULL {
+            auxv.push((word(&vm, addr), word(&vm, addr + 4)));
+            addr += 8;
+        }
+        let value = |key| auxv.iter().find(|(k, _)| *k == key).unwrap().1;
+        assert_eq!(value(AT_ENTRY), 0x8000_0000);
+        assert_eq!(value(AT_PAGESZ), 4096);
+        // the test's program headers aren't loaded
+        assert_eq!(value(AT_PHDR), 0);
+        assert_eq!(value(AT_PHNUM), 0);
+        assert!((sp..0x4000_0000).contains(&value(AT_RANDOM)));
+        assert_eq!(vm.pc(), 0x8000_0000);
+    }
+}
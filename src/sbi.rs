@@ -0,0 +1,277 @@
+// Built in supervisor binary interface firmware, handles ecalls from supervisor mode
+// so kernels can run without an m-mode firmware like opensbi
+// Specification: https://github.com/riscv-non-isa/riscv-sbi-doc
+
+use crate::csr::{MARCHID, MIMPID, MVENDORID, SSI};
+use crate::decode_instruction::Register;
+use crate::vm::{HaltReason, VM};
+use std::io::Write;
+
+// extension ids
+const EXT_BASE: u32 = 0x10;
+const EXT_TIME: u32 = 0x5449_4d45;
+const EXT_IPI: u32 = 0x0073_5049;
+const EXT_RFENCE: u32 = 0x5246_4e43;
+const EXT_HSM: u32 = 0x0048_534d;
+const EXT_SRST: u32 = 0x5352_5354;
+
+// legacy extensions, each one a single function
+const LEGACY_SET_TIMER: u32 = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: u32 = 0x01;
+const LEGACY_CONSOLE_GETCHAR: u32 = 0x02;
+const LEGACY_CLEAR_IPI: u32 = 0x03;
+const LEGACY_SEND_IPI: u32 = 0x04;
+const LEGACY_REMOTE_FENCE_I: u32 = 0x05;
+const LEGACY_REMOTE_SFENCE_VMA: u32 = 0x06;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u32 = 0x07;
+const LEGACY_SHUTDOWN: u32 = 0x08;
+
+const SPEC_VERSION: u32 = 2 << 24;
+// not one of the registered implementation ids
+const IMPL_ID: u32 = 0x7276;
+const IMPL_VERSION: u32 = 1;
+
+// hart states
+const HART_STARTED: u32 = 0;
+
+// system reset types
+const RESET_SHUTDOWN: u32 = 0;
+const RESET_COLD_REBOOT: u32 = 1;
+const RESET_WARM_REBOOT: u32 = 2;
+
+/// Error codes returned in a0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SbiError {
+    Failed = -1,
+    NotSupported = -2,
+    InvalidParam = -3,
+    AlreadyAvailable = -6,
+}
+
+type SbiResult = Result<u32, SbiError>;
+
+impl VM {
+    /// Handles an ecall from supervisor mode, a7 selects the extension and a6 the function,
+    /// the error is returned in a0 and the value in a1
+    pub(crate) fn sbi_call(&mut self) {
+        let extension = self.reg(Register::A7.into());
+        let function = self.reg(Register::A6.into());
+        let args: [u32; 6] = std::array::from_fn(|i| self.reg(Register::A0 as u32 + i as u32));
+
+        if extension <= LEGACY_SHUTDOWN {
+            // legacy calls only return a value in a0
+            let value = self.sbi_legacy(extension, args);
+            self.set_reg(Register::A0.into(), value);
+            return;
+        }
+
+        let result = match extension {
+            EXT_BASE => self.sbi_base(function, args),
+            EXT_TIME => self.sbi_time(function, args),
+            EXT_IPI => self.sbi_ipi(function, args),
+            EXT_RFENCE => self.sbi_rfence(function),
+            EXT_HSM => self.sbi_hsm(function, args),
+            EXT_SRST => self.sbi_srst(function, args),
+            _ => Err(SbiError::NotSupported),
+        };
+        let (error, value) = match result {
+            Ok(value) => (0, value),
+            Err(error) => (error as i32 as u32, 0),
+        };
+        self.set_reg(Register::A0.into(), error);
+        self.set_reg(Register::A1.into(), value);
+    }
+
+    fn sbi_legacy(&mut self, extension: u32, args: [u32; 6]) -> u32 {
+        match extension {
+            LEGACY_SET_TIMER => self.sbi_set_timer(args[0], args[1]),
+            LEGACY_CONSOLE_PUTCHAR => {
+                let stdout = &mut self.process.stdout;
+                let _ = stdout.write_all(&[args[0] as u8]);
+                let _ = stdout.flush();
+            }
+            // there is no console input
+            LEGACY_CONSOLE_GETCHAR => return SbiError::Failed as i32 as u32,
+            LEGACY_CLEAR_IPI => self.csr.mip &= !SSI,
+            // the hart mask is a pointer, the only hart is always in it
+            LEGACY_SEND_IPI => self.csr.mip |= SSI,
+            LEGACY_REMOTE_FENCE_I => {}
+            LEGACY_REMOTE_SFENCE_VMA | LEGACY_REMOTE_SFENCE_VMA_ASID => self.tlb.flush(None),
+            _ => self.halt(HaltReason::Exit, 0),
+        }
+        0
+    }
+
+    fn sbi_base(&mut self, function: u32, args: [u32; 6]) -> SbiResult {
+        match function {
+            0 => Ok(SPEC_VERSION),
+            1 => Ok(IMPL_ID),
+            2 => Ok(IMPL_VERSION),
+            3 => {
+                let supported = matches!(
+                    args[0],
+                    EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST
+                ) || args[0] <= LEGACY_SHUTDOWN;
+                Ok(supported as u32)
+            }
+            4 => Ok(self.csr.read(MVENDORID).unwrap_or(0)),
+            5 => Ok(self.csr.read(MARCHID).unwrap_or(0)),
+            6 => Ok(self.csr.read(MIMPID).unwrap_or(0)),
+            _ => Err(SbiError::NotSupported),
+        }
+    }
+
+    fn sbi_time(&mut self, function: u32, args: [u32; 6]) -> SbiResult {
+        match function {
+            0 => {
+                self.sbi_set_timer(args[0], args[1]);
+                Ok(0)
+            }
+            _ => Err(SbiError::NotSupported),
+        }
+    }
+
+    /// Programs the next supervisor timer interrupt, which also clears the pending one
+    fn sbi_set_timer(&mut self, low: u32, high: u32) {
+        self.clint.set_mtimecmp(((high as u64) << 32) | low as u64);
+    }
+
+    fn sbi_ipi(&mut self, function: u32, args: [u32; 6]) -> SbiResult {
+        match function {
+            0 => {
+                if hart_mask_contains(args[0], args[1], 0) {
+                    self.csr.mip |= SSI;
+                }
+                Ok(0)
+            }
+            _ => Err(SbiError::NotSupported),
+        }
+    }
+
+    fn sbi_rfence(&mut self, function: u32) -> SbiResult {
+        match function {
+            // fence.i is a no-op as instructions are never cached
+            0 => Ok(0),
+            1 | 2 => {
+                self.tlb.flush(None);
+                Ok(0)
+            }
+            // the hypervisor fences
+            _ => Err(SbiError::NotSupported),
+        }
+    }
+
+    fn sbi_hsm(&mut self, function: u32, args: [u32; 6]) -> SbiResult {
+        let hart_id = args[0];
+        match function {
+            // hart_start, the only hart is already running
+            0 if hart_id == 0 => Err(SbiError::AlreadyAvailable),
+            // hart_stop, stopping the only hart would leave nothing running
+            1 => Err(SbiError::Failed),
+            // hart_get_status
+            2 if hart_id == 0 => Ok(HART_STARTED),
+            0 | 2 => Err(SbiError::InvalidParam),
+            // hart_suspend, a default retentive suspend returns like wfi
+            3 if args[0] == 0 => Ok(0),
+            _ => Err(SbiError::NotSupported),
+        }
+    }
+
+    fn sbi_srst(&mut self, function: u32, args: [u32; 6]) -> SbiResult {
+        let (reset_type, reason) = (args[0], args[1]);
+        match function {
+            // reboots halt as well, the host decides whether to start the vm again
+            0 if matches!(
+                reset_type,
+                RESET_SHUTDOWN | RESET_COLD_REBOOT | RESET_WARM_REBOOT
+            ) =>
+            {
+                // reason 1 is a system failure
+                self.halt(HaltReason::Exit, (reason == 1) as u32);
+                Ok(0)
+            }
+            0 => Err(SbiError::InvalidParam),
+            _ => Err(SbiError::NotSupported),
+        }
+    }
+}
+
+/// True if hart_id is selected by the mask, a base of -1 selects every hart
+fn hart_mask_contains(mask: u32, base: u32, hart_id: u32) -> bool {
+    if base == u32::MAX {
+        return true;
+    }
+    hart_id
+        .checked_sub(base)
+        .is_some_and(|bit| bit < 32 && mask & (1 << bit) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::CaptureBuffer;
+    use crate::csr::{Privilege, SSI, STI};
+    use crate::decode_instruction::Register;
+    use crate::sbi::{EXT_BASE, EXT_HSM, EXT_IPI, EXT_SRST, EXT_TIME, SPEC_VERSION};
+    use crate::vm::tests::ecall;
+    use crate::vm::{Environment, HaltReason, VM};
+
+    /// Runs a supervisor mode ecall with the given extension, function and arguments
+    fn call(vm: &mut VM, extension: u32, function: u32, args: &[u32]) -> (u32, u32) {
+        // the function goes in a6, after the six arguments
+        let mut registers = [0; 7];
+        registers[..args.len()].copy_from_slice(args);
+        registers[6] = function;
+        let error = ecall(vm, extension, &registers);
+        (error, vm.reg(Register::A1.into()))
+    }
+
+    #[test]
+    fn test_base_and_hsm() {
+        let mut vm = VM::init_from_image(0x8000_0000, &[], 0x8000_0000);
+        vm.set_environment(Environment::Sbi);
+        assert_eq!(vm.privilege(), Privilege::Supervisor);
+
+        assert_eq!(call(&mut vm, EXT_BASE, 0, &[]), (0, SPEC_VERSION));
+        assert_eq!(call(&mut vm, EXT_BASE, 3, &[EXT_TIME]), (0, 1));
+        assert_eq!(call(&mut vm, EXT_BASE, 3, &[0x4442_434e]), (0, 0));
+        assert_eq!(call(&mut vm, 0x1234_5678, 0, &[]), (-2i32 as u32, 0));
+        assert_eq!(call(&mut vm, EXT_HSM, 2, &[0]), (0, 0));
+        assert_eq!(call(&mut vm, EXT_HSM, 0, &[1]), (-3i32 as u32, 0));
+        assert_eq!(vm.pc(), 0x8000_0000 + 6 * 4);
+    }
+
+    #[test]
+    fn test_timer_ipi_and_reset() {
+        let mut vm = VM::init_from_image(0x8000_0000, &[], 0x8000_0000);
+        vm.set_environment(Environment::Sbi);
+
+        // the supervisor timer fires once mtime reaches the programmed value
+        call(&mut vm, EXT_TIME, 0, &[10, 0]);
+        call(&mut vm, EXT_BASE, 0, &[]);
+        assert_eq!(vm.read_csr(0x144).unwrap() & STI, 0);
+        // legacy set_timer
+        call(&mut vm, 0, 0, &[2, 0]);
+        call(&mut vm, EXT_BASE, 0, &[]);
+        assert_ne!(vm.read_csr(0x144).unwrap() & STI, 0);
+
+        call(&mut vm, EXT_IPI, 0, &[0b10, u32::MAX]);
+        assert_ne!(vm.read_csr(0x144).unwrap() & SSI, 0);
+
+        call(&mut vm, EXT_SRST, 0, &[0, 1]);
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::Exit));
+        assert_eq!(vm.exit_code(), 1);
+    }
+
+    #[test]
+    fn test_console_putchar() {
+        let mut vm = VM::init_from_image(0x8000_0000, &[], 0x8000_0000);
+        vm.set_environment(Environment::Sbi);
+        let output = CaptureBuffer::new();
+        vm.set_stdout(Box::new(output.clone()));
+
+        // legacy putchar writes the low byte of a0 to the vm's stdout
+        assert_eq!(call(&mut vm, 1, 0, &[0x168]).0, 0);
+        assert_eq!(call(&mut vm, 1, 0, &[b'i' as u32]).0, 0);
+        assert_eq!(output.contents(), b"hi");
+    }
+}
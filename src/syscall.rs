@@ -0,0 +1,791 @@
+// Linux rv32 user abi emulation for `Environment::Emulated`
+// a7 holds the syscall number, a0-a5 the arguments and the result (or -errno) is returned in a0
+// Syscall numbers: include/uapi/asm-generic/unistd.h
+
+use crate::decode_instruction::Register;
+use crate::memory::PAGE_SIZE;
+use crate::trap::Exception;
+use crate::vfs::{FileStat, FileTable, Filesystem, OpenFile, OpenFlags};
+use crate::vm::{HaltReason, VM};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::{Read, SeekFrom, Write};
+use std::mem;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// custom syscall, prints a1 as a decimal to the file descriptor in a0
+const PRINT_REGISTER: u32 = 1;
+
+const GETCWD: u32 = 17;
+const DUP: u32 = 23;
+const DUP3: u32 = 24;
+const FCNTL64: u32 = 25;
+const IOCTL: u32 = 29;
+const MKDIRAT: u32 = 34;
+const UNLINKAT: u32 = 35;
+const FACCESSAT: u32 = 48;
+const OPENAT: u32 = 56;
+const CLOSE: u32 = 57;
+const LLSEEK: u32 = 62;
+const READ: u32 = 63;
+const WRITE: u32 = 64;
+const READV: u32 = 65;
+const WRITEV: u32 = 66;
+const EXIT: u32 = 93;
+const EXIT_GROUP: u32 = 94;
+const SET_TID_ADDRESS: u32 = 96;
+const SET_ROBUST_LIST: u32 = 99;
+const SIGALTSTACK: u32 = 132;
+const RT_SIGACTION: u32 = 134;
+const RT_SIGPROCMASK: u32 = 135;
+const UNAME: u32 = 160;
+const GETPID: u32 = 172;
+const GETPPID: u32 = 173;
+const GETUID: u32 = 174;
+const GETEUID: u32 = 175;
+const GETGID: u32 = 176;
+const GETEGID: u32 = 177;
+const GETTID: u32 = 178;
+const BRK: u32 = 214;
+const MUNMAP: u32 = 215;
+const MMAP2: u32 = 222;
+const MPROTECT: u32 = 226;
+const MADVISE: u32 = 233;
+const GETRANDOM: u32 = 278;
+const STATX: u32 = 291;
+const CLOCK_GETTIME64: u32 = 403;
+
+// errno values
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const ENOMEM: u32 = 12;
+const EACCES: u32 = 13;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const EMFILE: u32 = 24;
+const ENOTTY: u32 = 25;
+const EFBIG: u32 = 27;
+const ENOSPC: u32 = 28;
+const ESPIPE: u32 = 29;
+const ERANGE: u32 = 34;
+const ENOSYS: u32 = 38;
+const ENOTEMPTY: u32 = 39;
+
+// openat flags
+const O_ACCMODE: u32 = 0b11;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const AT_FDCWD: u32 = -100i32 as u32;
+const AT_REMOVEDIR: u32 = 0x200;
+const AT_EMPTY_PATH: u32 = 0x1000;
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const CLOCK_REALTIME: u32 = 0;
+
+// longest path read from guest memory
+const PATH_MAX: usize = 4096;
+// reads and writes are copied through host buffers of at most this size
+const IO_CHUNK: u32 = 64 * 1024;
+// most entries in a readv or writev vector
+const UIO_MAXIOV: u32 = 1024;
+const UTSNAME_FIELD: usize = 65;
+
+/// Top of the region mmap allocations are taken from, growing down towards the heap
+pub(crate) const MMAP_TOP: u32 = 0x7000_0000;
+
+type SyscallResult = Result<u32, u32>;
+
+/// What the vm does once a syscall has been handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyscallAction {
+    /// Resume at the instruction after the ecall
+    Continue,
+    /// Stop the vm with `HaltReason::Exit` and the given exit code
+    Halt(u32),
+    /// Raise the exception at the ecall, as if the instruction had faulted
+    Trap(Exception),
+}
+
+/// Host interface behind the ecall instruction in `Environment::Emulated`,
+/// the arguments and results are passed through the vm's registers and memory
+pub trait SyscallHandler {
+    fn syscall(&mut self, vm: &mut VM) -> SyscallAction;
+}
+
+/// The default handler, emulates the linux rv32 user syscalls along with the custom
+/// print register call (a7 = 1), other handlers can delegate to it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinuxSyscalls;
+
+impl SyscallHandler for LinuxSyscalls {
+    fn syscall(&mut self, vm: &mut VM) -> SyscallAction {
+        vm.linux_syscall()
+    }
+}
+
+/// State of the emulated linux process
+pub(crate) struct Process {
+    pub(crate) brk_start: u32,
+    pub(crate) brk: u32,
+    // entry point and program headers reported in the auxiliary vector
+    pub(crate) entry_point: u32,
+    pub(crate) program_headers: Option<u32>,
+    pub(crate) program_header_count: u32,
+    // lowest address handed out by mmap so far
+    mmap_bottom: u32,
+    files: FileTable,
+    pub(crate) filesystem: Filesystem,
+    // what reads from file descriptor 0 return
+    pub(crate) stdin: Box<dyn Read>,
+    // where writes to file descriptors 1 and 2 go
+    pub(crate) stdout: Box<dyn Write>,
+    pub(crate) stderr: Box<dyn Write>,
+    epoch: Instant,
+}
+
+impl Default for Process {
+    fn default() -> Self {
+        Self {
+            brk_start: 0,
+            brk: 0,
+            entry_point: 0,
+            program_headers: None,
+            program_header_count: 0,
+            mmap_bottom: MMAP_TOP,
+            files: FileTable::default(),
+            filesystem: Filesystem::default(),
+            stdin: Box::new(io::stdin()),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Process {
+    /// Starts the heap at the first page after end
+    pub(crate) fn set_program_end(&mut self, end: u32) {
+        self.brk_start = page_align(end);
+        self.brk = self.brk_start;
+    }
+
+    /// True if the heap [brk_start, brk) would overlap mappings starting at mmap_bottom
+    fn collides(&self, brk_start: u32, brk: u32, mmap_bottom: u32) -> bool {
+        brk_start < MMAP_TOP && brk > mmap_bottom
+    }
+
+    fn file(&mut self, fd: u32) -> Result<&mut OpenFile, u32> {
+        self.files.get(fd).ok_or(EBADF)
+    }
+}
+
+impl VM {
+    /// Passes an ecall in `Environment::Emulated` to the installed syscall handler
+    pub(crate) fn syscall(&mut self) -> Result<(), Exception> {
+        // the handler is taken out so it can borrow the vm, LinuxSyscalls holds no state
+        let mut handler = mem::replace(&mut self.syscall_handler, Box::new(LinuxSyscalls));
+        let action = handler.syscall(self);
+        self.syscall_handler = handler;
+
+        match action {
+            SyscallAction::Continue => Ok(()),
+            SyscallAction::Halt(exit_code) => {
+                self.halt(HaltReason::Exit, exit_code);
+                Ok(())
+            }
+            SyscallAction::Trap(exception) => Err(exception),
+        }
+    }
+
+    fn linux_syscall(&mut self) -> SyscallAction {
+        let number = self.reg(Register::A7.into());
+        let args: [u32; 6] = std::array::from_fn(|i| self.reg(Register::A0 as u32 + i as u32));
+
+        let result = match number {
+            // registers are left untouched unless the print fails
+            PRINT_REGISTER => match self.write_fd(args[0], args[1].to_string().as_bytes()) {
+                Ok(_) => return SyscallAction::Continue,
+                Err(errno) => Err(errno),
+            },
+            EXIT | EXIT_GROUP => return SyscallAction::Halt(args[0]),
+            READ => self.sys_read(args[0], args[1], args[2]),
+            WRITE => self.sys_write(args[0], args[1], args[2]),
+            READV => self.sys_vectored(args[0], args[1], args[2], false),
+            WRITEV => self.sys_vectored(args[0], args[1], args[2], true),
+            OPENAT => self.sys_openat(args[0], args[1], args[2]),
+            CLOSE => self.sys_close(args[0]),
+            LLSEEK => self.sys_llseek(args[0], args[1], args[2], args[3], args[4]),
+            DUP => self.sys_dup(args[0]),
+            DUP3 => self.sys_dup3(args[0], args[1]),
+            STATX => self.sys_statx(args[0], args[1], args[2], args[4]),
+            GETCWD => self.sys_getcwd(args[0], args[1]),
+            FACCESSAT => self.path_arg(args[0], args[1]).and_then(|path| {
+                self.process
+                    .filesystem
+                    .stat(&path)
+                    .map_err(|err| errno(&err))?;
+                Ok(0)
+            }),
+            MKDIRAT => self.path_arg(args[0], args[1]).and_then(|path| {
+                let filesystem = &self.process.filesystem;
+                filesystem.create_dir(&path).map_err(|err| errno(&err))?;
+                Ok(0)
+            }),
+            UNLINKAT => self.path_arg(args[0], args[1]).and_then(|path| {
+                let filesystem = &self.process.filesystem;
+                let dir = args[2] & AT_REMOVEDIR != 0;
+                filesystem.remove(&path, dir).map_err(|err| errno(&err))?;
+                Ok(0)
+            }),
+            BRK => Ok(self.sys_brk(args[0])),
+            MMAP2 => self.sys_mmap(args[0], args[1], args[3], args[4], args[5]),
+            CLOCK_GETTIME64 => self.sys_clock_gettime(args[0], args[1]),
+            // larger requests return fewer bytes, which callers must handle
+            GETRANDOM => {
+                let len = args[1].min(IO_CHUNK);
+                self.memory
+                    .write_bytes(args[0], &random_bytes(len as usize));
+                Ok(len)
+            }
+            UNAME => {
+                self.sys_uname(args[0]);
+                Ok(0)
+            }
+            // fcntl flags, memory protection and signals are not emulated
+            FCNTL64 | MUNMAP | MPROTECT | MADVISE | SIGALTSTACK | RT_SIGACTION | RT_SIGPROCMASK
+            | SET_ROBUST_LIST => Ok(0),
+            // the only process, running as root
+            GETPID | GETTID | SET_TID_ADDRESS => Ok(1),
+            GETPPID | GETUID | GETEUID | GETGID | GETEGID => Ok(0),
+            // no file is a terminal
+            IOCTL => Err(ENOTTY),
+            _ => {
+                if self.trace {
+                    eprintln!("unimplemented syscall {}", number);
+                }
+                Err(ENOSYS)
+            }
+        };
+
+        let value = result.unwrap_or_else(|errno| errno.wrapping_neg());
+        self.set_reg(Register::A0.into(), value);
+        SyscallAction::Continue
+    }
+
+    fn sys_read(&mut self, fd: u32, buf: u32, count: u32) -> SyscallResult {
+        let mut bytes = vec![0; count.min(IO_CHUNK) as usize];
+        let mut total = 0;
+        while total < count {
+            let len = (count - total).min(IO_CHUNK) as usize;
+            let result = match self.process.file(fd)? {
+                OpenFile::Stdin => self.process.stdin.read(&mut bytes[..len]),
+                OpenFile::File(description) if description.readable => {
+                    description.file.borrow_mut().read(&mut bytes[..len])
+                }
+                _ => return Err(EBADF),
+            };
+            let read = match result {
+                Ok(read) => read,
+                // the bytes already read are returned, the error shows up on the next call
+                Err(_) if total > 0 => break,
+                Err(err) => return Err(errno(&err)),
+            };
+            self.memory
+                .write_bytes(buf.wrapping_add(total), &bytes[..read]);
+            total += read as u32;
+            // a short read means there is nothing more to read for now
+            if read < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn sys_write(&mut self, fd: u32, buf: u32, count: u32) -> SyscallResult {
+        let mut total = 0;
+        while total < count {
+            let len = (count - total).min(IO_CHUNK);
+            let bytes = self
+                .memory
+                .read_bytes(buf.wrapping_add(total), len as usize);
+            match self.write_fd(fd, &bytes) {
+                Ok(written) => total += written,
+                Err(_) if total > 0 => break,
+                Err(errno) => return Err(errno),
+            }
+        }
+        Ok(total)
+    }
+
+    /// Writes the exact bytes to fd, the standard streams go to the vm's output sinks
+    fn write_fd(&mut self, fd: u32, bytes: &[u8]) -> SyscallResult {
+        let process = &mut self.process;
+        match process.files.get(fd).ok_or(EBADF)? {
+            OpenFile::Stdout => process
+                .stdout
+                .write_all(bytes)
+                .and_then(|_| process.stdout.flush()),
+            OpenFile::Stderr => process
+                .stderr
+                .write_all(bytes)
+                .and_then(|_| process.stderr.flush()),
+            OpenFile::File(description) if description.writable => {
+                description.file.borrow_mut().write_all(bytes)
+            }
+            _ => return Err(EBADF),
+        }
+        .map_err(|err| errno(&err))?;
+        Ok(bytes.len() as u32)
+    }
+
+    /// readv and writev, iov points to count (base, len) pairs
+    fn sys_vectored(&mut self, fd: u32, iov: u32, count: u32, write: bool) -> SyscallResult {
+        if count > UIO_MAXIOV {
+            return Err(EINVAL);
+        }
+        let mut total = 0u32;
+        for i in 0..count {
+            let entry = self.memory.read_bytes(iov.wrapping_add(8 * i), 8);
+            let base = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+            let done = match write {
+                true => self.sys_write(fd, base, len)?,
+                false => self.sys_read(fd, base, len)?,
+            };
+            total = total.wrapping_add(done);
+            // a short read means there is nothing more to read for now
+            if done < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn sys_openat(&mut self, dirfd: u32, path: u32, flags: u32) -> SyscallResult {
+        let path = self.path_arg(dirfd, path)?;
+        let access = flags & O_ACCMODE;
+        let flags = OpenFlags {
+            read: access != O_WRONLY,
+            write: access == O_WRONLY || access == O_RDWR,
+            append: flags & O_APPEND != 0,
+            truncate: flags & O_TRUNC != 0,
+            create: flags & O_CREAT != 0,
+            exclusive: flags & O_EXCL != 0,
+        };
+        let description = self
+            .process
+            .filesystem
+            .open(&path, flags)
+            .map_err(|err| errno(&err))?;
+        let file = OpenFile::File(description);
+        self.process.files.insert(file).ok_or(EMFILE)
+    }
+
+    fn sys_close(&mut self, fd: u32) -> SyscallResult {
+        self.process.files.remove(fd).ok_or(EBADF)?;
+        Ok(0)
+    }
+
+    /// llseek, the 64 bit offset is split across two registers and the result stored at result
+    fn sys_llseek(
+        &mut self,
+        fd: u32,
+        offset_high: u32,
+        offset_low: u32,
+        result: u32,
+        whence: u32,
+    ) -> SyscallResult {
+        let offset = (((offset_high as u64) << 32) | offset_low as u64) as i64;
+        let position = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        let position = match self.process.file(fd)? {
+            OpenFile::File(description) => description
+                .file
+                .borrow_mut()
+                .seek(position)
+                .map_err(|err| errno(&err))?,
+            _ => return Err(ESPIPE),
+        };
+        self.memory.write_bytes(result, &position.to_le_bytes());
+        Ok(0)
+    }
+
+    fn sys_dup(&mut self, fd: u32) -> SyscallResult {
+        let file = self.process.file(fd)?.clone();
+        self.process.files.insert(file).ok_or(EMFILE)
+    }
+
+    fn sys_dup3(&mut self, fd: u32, new_fd: u32) -> SyscallResult {
+        if fd == new_fd {
+            return Err(EINVAL);
+        }
+        let file = self.process.file(fd)?.clone();
+        self.process.files.insert_at(new_fd, file).ok_or(EBADF)?;
+        Ok(new_fd)
+    }
+
+    fn file_stat(&mut self, fd: u32) -> Result<FileStat, u32> {
+        match self.process.file(fd)? {
+            OpenFile::File(description) => {
+                description.file.borrow().stat().map_err(|err| errno(&err))
+            }
+            _ => Ok(FileStat::terminal()),
+        }
+    }
+
+    /// rv32 has no fstat, statx with AT_EMPTY_PATH and an empty path describes dirfd itself
+    fn sys_statx(&mut self, dirfd: u32, path: u32, flags: u32, buf: u32) -> SyscallResult {
+        let stat = if flags & AT_EMPTY_PATH != 0 && self.memory.byte(path) == 0 {
+            self.file_stat(dirfd)?
+        } else {
+            let path = self.path_arg(dirfd, path)?;
+            let filesystem = &self.process.filesystem;
+            filesystem.stat(&path).map_err(|err| errno(&err))?
+        };
+        self.memory.write_bytes(buf, &stat.statx());
+        Ok(0)
+    }
+
+    /// The working directory is always the root of the sandboxed filesystem
+    fn sys_getcwd(&mut self, buf: u32, size: u32) -> SyscallResult {
+        if size < 2 {
+            return Err(ERANGE);
+        }
+        self.memory.write_bytes(buf, b"/\0");
+        Ok(2)
+    }
+
+    /// Moves the end of the heap, returns the current end if the request can't be satisfied
+    fn sys_brk(&mut self, addr: u32) -> u32 {
+        let process = &mut self.process;
+        if addr < process.brk_start
+            || process.collides(process.brk_start, addr, process.mmap_bottom)
+        {
+            return process.brk;
+        }
+        // memory released by shrinking the heap must read as zero when it grows back
+        if addr < process.brk {
+            self.memory.zero(addr, process.brk - addr);
+        }
+        self.process.brk = addr;
+        addr
+    }
+
+    /// mmap2, the offset is in pages, mappings are never reused so they start out zeroed
+    fn sys_mmap(
+        &mut self,
+        addr: u32,
+        len: u32,
+        flags: u32,
+        fd: u32,
+        page_offset: u32,
+    ) -> SyscallResult {
+        if len == 0 || !addr.is_multiple_of(PAGE_SIZE as u32) && flags & MAP_FIXED != 0 {
+            return Err(EINVAL);
+        }
+        let size = page_align(len);
+        if size < len {
+            return Err(ENOMEM);
+        }
+        let addr = if flags & MAP_FIXED != 0 {
+            self.memory.zero(addr, size);
+            addr
+        } else {
+            let addr = self.process.mmap_bottom.checked_sub(size).ok_or(ENOMEM)?;
+            let process = &self.process;
+            if process.collides(process.brk_start, process.brk, addr) {
+                return Err(ENOMEM);
+            }
+            self.process.mmap_bottom = addr;
+            addr
+        };
+
+        if flags & MAP_ANONYMOUS == 0 {
+            let OpenFile::File(description) = self.process.file(fd)? else {
+                return Err(EACCES);
+            };
+            // mapping a file leaves its offset where it was
+            let mut file = description.file.borrow_mut();
+            let mut bytes = Vec::new();
+            let offset = page_offset as u64 * PAGE_SIZE as u64;
+            file.stream_position()
+                .and_then(|position| {
+                    file.seek(SeekFrom::Start(offset))?;
+                    (&mut *file).take(len as u64).read_to_end(&mut bytes)?;
+                    file.seek(SeekFrom::Start(position))
+                })
+                .map_err(|err| errno(&err))?;
+            drop(file);
+            self.memory.write_bytes(addr, &bytes);
+        }
+        Ok(addr)
+    }
+
+    /// clock_gettime64, rv32 only has the 64 bit timespec variant
+    fn sys_clock_gettime(&mut self, clock: u32, buf: u32) -> SyscallResult {
+        let time = match clock {
+            CLOCK_REALTIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            _ => self.process.epoch.elapsed(),
+        };
+        let mut bytes = time.as_secs().to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+        self.memory.write_bytes(buf, &bytes);
+        Ok(0)
+    }
+
+    fn sys_uname(&mut self, buf: u32) {
+        let fields = ["Linux", "riscv-vm", "6.1.0", "#1", "riscv32", "(none)"];
+        for (i, field) in fields.iter().enumerate() {
+            let mut bytes = field.as_bytes().to_vec();
+            bytes.resize(UTSNAME_FIELD, 0);
+            self.memory
+                .write_bytes(buf.wrapping_add((i * UTSNAME_FIELD) as u32), &bytes);
+        }
+    }
+
+    /// Reads the nul terminated path at addr, relative paths must be relative to the cwd (the root)
+    fn path_arg(&self, dirfd: u32, addr: u32) -> Result<String, u32> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.memory.byte(addr.wrapping_add(bytes.len() as u32));
+            if byte == 0 {
+                break;
+            }
+            if bytes.len() == PATH_MAX {
+                return Err(ERANGE);
+            }
+            bytes.push(byte);
+        }
+        if bytes.is_empty() {
+            return Err(ENOENT);
+        }
+        if bytes[0] != b'/' && dirfd != AT_FDCWD {
+            return Err(ENOTDIR);
+        }
+        String::from_utf8(bytes).map_err(|_| EINVAL)
+    }
+}
+
+fn page_align(addr: u32) -> u32 {
+    addr.wrapping_add(PAGE_SIZE as u32 - 1) & !(PAGE_SIZE as u32 - 1)
+}
+
+/// Maps a host io error to the errno the guest sees
+fn errno(err: &io::Error) -> u32 {
+    match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::NotADirectory => ENOTDIR,
+        io::ErrorKind::IsADirectory => EISDIR,
+        io::ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
+        io::ErrorKind::FileTooLarge => EFBIG,
+        io::ErrorKind::StorageFull => ENOSPC,
+        _ => EIO,
+    }
+}
+
+/// Bytes from the host's randomly seeded hasher keys
+pub(crate) fn random_bytes(len: usize) -> Vec<u8> {
+    let state = std::collections::hash_map::RandomState::new();
+    let mut bytes = Vec::with_capacity(len + 8);
+    let mut counter = 0u64;
+    while bytes.len() < len {
+        let mut hasher = state.build_hasher();
+        hasher.write_u64(counter);
+        bytes.extend_from_slice(&hasher.finish().to_le_bytes());
+        counter += 1;
+    }
+    bytes.truncate(len);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::CaptureBuffer;
+    use crate::decode_instruction::Register;
+    use crate::memory::PAGE_SIZE;
+    use crate::syscall::{LinuxSyscalls, SyscallAction, SyscallHandler};
+    use crate::syscall::{
+        AT_EMPTY_PATH, BRK, CLOSE, DUP, DUP3, EBADF, EINVAL, ENOENT, ENOSYS, LLSEEK, MMAP2,
+        MMAP_TOP, OPENAT, O_CREAT, O_RDWR, READ, STATX, UIO_MAXIOV, UNAME, WRITE, WRITEV,
+    };
+    use crate::trap::Exception;
+    use crate::vfs::{Filesystem, MemoryFs};
+    use crate::vm::tests::ecall;
+    use crate::vm::{HaltReason, VM};
+
+    const AT_FDCWD: u32 = -100i32 as u32;
+
+    #[test]
+    fn test_brk_and_mmap() {
+        let mut vm = VM::init_from_image(0x1_0000, &[], 0x1_0000);
+        vm.process.set_program_end(0x8000_0123);
+
+        assert_eq!(ecall(&mut vm, BRK, &[0]), 0x8000_1000);
+        assert_eq!(ecall(&mut vm, BRK, &[0x8000_3000]), 0x8000_3000);
+        // below the start of the heap, the current end is returned
+        assert_eq!(ecall(&mut vm, BRK, &[0x1000]), 0x8000_3000);
+
+        // anonymous private mapping
+        let addr = ecall(&mut vm, MMAP2, &[0, 0x1800, 3, 0x22, u32::MAX, 0]);
+        assert_eq!(addr, MMAP_TOP - 0x2000);
+        let next = ecall(&mut vm, MMAP2, &[0, 0x1000, 3, 0x22, u32::MAX, 0]);
+        assert_eq!(next, addr - 0x1000);
+
+        // a fixed mapping zeroes its pages in place without allocating them
+        vm.write_memory(0x1000_0010, &[1, 2, 3]);
+        let allocated = vm.memory.allocated_bytes();
+        let fixed = ecall(&mut vm, MMAP2, &[0x1000_0000, 0x4000_0000, 3, 0x32, 0, 0]);
+        assert_eq!(fixed, 0x1000_0000);
+        assert_eq!(vm.read_memory(0x1000_0010, 3), [0, 0, 0]);
+        assert_eq!(vm.memory.allocated_bytes(), allocated - PAGE_SIZE);
+
+        assert_eq!(ecall(&mut vm, 0x7fff, &[]), ENOSYS.wrapping_neg());
+        assert_eq!(ecall(&mut vm, UNAME, &[0x2000]), 0);
+        assert_eq!(vm.read_memory(0x2000, 6), b"Linux\0");
+        assert_eq!(vm.read_memory(0x2000 + 4 * 65, 8), b"riscv32\0");
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let tree = MemoryFs::new();
+        let mut vm = VM::init_from_image(0x1_0000, &[], 0x1_0000);
+        vm.set_filesystem(Filesystem::Memory(tree.clone()));
+        vm.write_memory(0x2000, b"../out\0");
+        vm.write_memory(0x3000, b"hello");
+
+        let fd = ecall(
+            &mut vm,
+            OPENAT,
+            &[AT_FDCWD, 0x2000, O_CREAT | O_RDWR, 0o644],
+        );
+        assert_eq!(fd, 3);
+        assert_eq!(ecall(&mut vm, WRITE, &[fd, 0x3000, 5]), 5);
+        assert_eq!(ecall(&mut vm, LLSEEK, &[fd, 0, 1, 0x4000, 0]), 0);
+        assert_eq!(vm.read_memory(0x4000, 8), 1u64.to_le_bytes());
+        // a duplicate shares the offset
+        let dup = ecall(&mut vm, DUP, &[fd]);
+        assert_eq!(ecall(&mut vm, READ, &[dup, 0x5000, 2]), 2);
+        assert_eq!(ecall(&mut vm, READ, &[fd, 0x5002, 16]), 2);
+        assert_eq!(vm.read_memory(0x5000, 4), b"ello");
+        // descriptors are capped, dup3 can't grow the table without bound
+        assert_eq!(
+            ecall(&mut vm, DUP3, &[fd, u32::MAX, 0]),
+            EBADF.wrapping_neg()
+        );
+
+        // statx of the fd itself, 0x2100 holds an empty path
+        let stat = ecall(&mut vm, STATX, &[fd, 0x2100, AT_EMPTY_PATH, 0x7ff, 0x6000]);
+        assert_eq!(stat, 0);
+        assert_eq!(vm.read_memory(0x6000 + 40, 8), 5u64.to_le_bytes());
+
+        assert_eq!(ecall(&mut vm, CLOSE, &[fd]), 0);
+        assert_eq!(
+            ecall(&mut vm, READ, &[fd, 0x5000, 16]),
+            EBADF.wrapping_neg()
+        );
+        assert_eq!(tree.read_file("/out").unwrap(), b"hello");
+
+        vm.write_memory(0x2000, b"/missing\0");
+        assert_eq!(
+            ecall(&mut vm, OPENAT, &[AT_FDCWD, 0x2000, 0, 0]),
+            ENOENT.wrapping_neg()
+        );
+    }
+
+    #[test]
+    fn test_stdin_bytes() {
+        let mut vm = VM::init_from_image(0x1_0000, &[], 0x1_0000);
+        vm.set_stdin_bytes(b"abc");
+        assert_eq!(ecall(&mut vm, READ, &[0, 0x2000, 2]), 2);
+        // the buffer for a read is bounded, not sized by the count
+        assert_eq!(ecall(&mut vm, READ, &[0, 0x2002, u32::MAX]), 1);
+        assert_eq!(vm.read_memory(0x2000, 3), b"abc");
+        // end of file
+        assert_eq!(ecall(&mut vm, READ, &[0, 0x2000, 16]), 0);
+        // stdout can't be read
+        assert_eq!(ecall(&mut vm, READ, &[1, 0x2000, 16]), EBADF.wrapping_neg());
+    }
+
+    #[test]
+    fn test_writev() {
+        let mut vm = VM::init_from_image(0x1_0000, &[], 0x1_0000);
+        let output = CaptureBuffer::new();
+        vm.set_stdout(Box::new(output.clone()));
+        vm.write_memory(0x3000, b"hello world");
+        // two entries, then an empty one
+        for (i, (base, len)) in [(0x3000u32, 5u32), (0x3005, 6), (0, 0)].iter().enumerate() {
+            vm.write_memory(0x2000 + 8 * i as u32, &base.to_le_bytes());
+            vm.write_memory(0x2004 + 8 * i as u32, &len.to_le_bytes());
+        }
+        assert_eq!(ecall(&mut vm, WRITEV, &[1, 0x2000, 3]), 11);
+        assert_eq!(output.contents(), b"hello world");
+
+        // the vector length is bounded, like linux
+        assert_eq!(
+            ecall(&mut vm, WRITEV, &[1, 0x2000, UIO_MAXIOV + 1]),
+            EINVAL.wrapping_neg()
+        );
+        assert_eq!(
+            ecall(&mut vm, WRITEV, &[1, 0x2000, u32::MAX]),
+            EINVAL.wrapping_neg()
+        );
+    }
+
+    /// Adds a0 and a1 for a7 = 42, traps for a7 = 7 and leaves the rest to linux
+    struct AddHandler;
+
+    impl SyscallHandler for AddHandler {
+        fn syscall(&mut self, vm: &mut VM) -> SyscallAction {
+            match vm.reg(Register::A7.into()) {
+                42 => {
+                    let sum = vm.reg(Register::A0.into()) + vm.reg(Register::A1.into());
+                    vm.set_reg(Register::A0.into(), sum);
+                    SyscallAction::Continue
+                }
+                7 => SyscallAction::Trap(Exception::EnvironmentCallFromMMode),
+                _ => LinuxSyscalls.syscall(vm),
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_handler() {
+        let mut vm = VM::init_from_image(0x1_0000, &[], 0x1_0000);
+        vm.set_syscall_handler(Box::new(AddHandler));
+        assert_eq!(ecall(&mut vm, 42, &[2, 3]), 5);
+        assert_eq!(ecall(&mut vm, UNAME, &[0x2000]), 0);
+        assert_eq!(vm.read_memory(0x2000, 6), b"Linux\0");
+
+        // without a trap handler installed the exception halts the vm
+        ecall(&mut vm, 7, &[]);
+        assert_eq!(
+            vm.halt_reason(),
+            Some(&HaltReason::Exception(Exception::EnvironmentCallFromMMode))
+        );
+
+        let mut vm = VM::init_from_image(0x1_0000, &[], 0x1_0000);
+        vm.set_syscall_handler(Box::new(AddHandler));
+        ecall(&mut vm, 93, &[3]);
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::Exit));
+        assert_eq!(vm.exit_code(), 3);
+    }
+}
@@ -0,0 +1,372 @@
+// Synchronous exceptions and interrupts
+// Specification: The RISC-V Instruction Set Manual Volume II: Privileged Architecture (mcause)
+
+use crate::csr::{
+    Privilege, MEI, MSI, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_SIE,
+    MSTATUS_SPIE, MSTATUS_SPP, MTI, SEI, SSI, STI,
+};
+use crate::vm::VM;
+
+/// An exception raised while executing an instruction,
+/// the payload is the value written to mtval (or stval)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u32),
+    InstructionAccessFault(u32),
+    IllegalInstruction(u32),
+    Breakpoint(u32),
+    LoadAddressMisaligned(u32),
+    LoadAccessFault(u32),
+    StoreAddressMisaligned(u32),
+    StoreAccessFault(u32),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault(u32),
+    LoadPageFault(u32),
+    StorePageFault(u32),
+}
+
+impl Exception {
+    /// Exception code written to mcause
+    pub fn cause(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    /// Value written to mtval or stval
+    pub fn tval(&self) -> u32 {
+        match *self {
+            Exception::InstructionAddressMisaligned(tval)
+            | Exception::InstructionAccessFault(tval)
+            | Exception::IllegalInstruction(tval)
+            | Exception::Breakpoint(tval)
+            | Exception::LoadAddressMisaligned(tval)
+            | Exception::LoadAccessFault(tval)
+            | Exception::StoreAddressMisaligned(tval)
+            | Exception::StoreAccessFault(tval)
+            | Exception::InstructionPageFault(tval)
+            | Exception::LoadPageFault(tval)
+            | Exception::StorePageFault(tval) => tval,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
+
+    /// The environment call exception for ecalls made from the given privilege level
+    pub(crate) fn environment_call(privilege: Privilege) -> Self {
+        match privilege {
+            Privilege::User => Exception::EnvironmentCallFromUMode,
+            Privilege::Supervisor => Exception::EnvironmentCallFromSMode,
+            Privilege::Machine => Exception::EnvironmentCallFromMMode,
+        }
+    }
+}
+
+// set in mcause/scause when the trap is an interrupt
+const INTERRUPT_BIT: u32 = 1 << 31;
+
+/// An asynchronous interrupt, delivered between instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+}
+
+impl Interrupt {
+    // highest priority first
+    const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+
+    /// Interrupt code written to mcause, also the bit position in mip and mie
+    pub fn code(&self) -> u32 {
+        self.bit().trailing_zeros()
+    }
+
+    fn bit(&self) -> u32 {
+        match self {
+            Interrupt::SupervisorSoftware => SSI,
+            Interrupt::MachineSoftware => MSI,
+            Interrupt::SupervisorTimer => STI,
+            Interrupt::MachineTimer => MTI,
+            Interrupt::SupervisorExternal => SEI,
+            Interrupt::MachineExternal => MEI,
+        }
+    }
+}
+
+impl VM {
+    /// Privilege level that handles the exception, delegated exceptions
+    /// are handled in supervisor mode unless they were raised in machine mode
+    pub(crate) fn trap_target(&self, exception: Exception) -> Privilege {
+        self.delegation_target(self.csr.medeleg, exception.cause())
+    }
+
+    fn delegation_target(&self, delegated: u32, code: u32) -> Privilege {
+        if self.privilege < Privilege::Machine && delegated & (1 << code) != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::Machine
+        }
+    }
+
+    /// The highest priority interrupt that is pending, enabled in mie and enabled
+    /// for the privilege level that would handle it
+    pub(crate) fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.csr.pending() & self.csr.mie;
+        if pending == 0 {
+            return None;
+        }
+
+        let mstatus = self.csr.mstatus;
+        // interrupts for a higher privilege level are always enabled,
+        // interrupts for the current level depend on the global enable bit
+        let machine_enabled = self.privilege < Privilege::Machine || mstatus & MSTATUS_MIE != 0;
+        let supervisor_enabled = self.privilege < Privilege::Supervisor
+            || (self.privilege == Privilege::Supervisor && mstatus & MSTATUS_SIE != 0);
+
+        let mut enabled = 0;
+        if machine_enabled {
+            enabled |= pending & !self.csr.mideleg;
+        }
+        if supervisor_enabled {
+            enabled |= pending & self.csr.mideleg;
+        }
+
+        Interrupt::PRIORITY
+            .into_iter()
+            .find(|interrupt| enabled & interrupt.bit() != 0)
+    }
+
+    /// Takes an exception, transferring control to the handler at mtvec or stvec
+    /// exceptions always use the base address, even in vectored mode
+    pub(crate) fn trap(&mut self, exception: Exception) {
+        let target = self.trap_target(exception);
+        self.enter_trap(target, exception.cause(), exception.tval());
+    }
+
+    /// Takes an interrupt, the pc of the interrupted instruction is saved
+    pub(crate) fn interrupt(&mut self, interrupt: Interrupt) {
+        let target = self.delegation_target(self.csr.mideleg, interrupt.code());
+        self.enter_trap(target, INTERRUPT_BIT | interrupt.code(), 0);
+    }
+
+    fn enter_trap(&mut self, target: Privilege, cause: u32, tval: u32) {
+        let previous = self.privilege;
+        self.reservation = None;
+
+        let tvec = if target == Privilege::Supervisor {
+            self.csr.sepc = self.pc;
+            self.csr.scause = cause;
+            self.csr.stval = tval;
+
+            // spie = sie, sie = 0, spp = previous privilege
+            let sie = self.csr.mstatus & MSTATUS_SIE != 0;
+            self.csr.mstatus &= !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+            if sie {
+                self.csr.mstatus |= MSTATUS_SPIE;
+            }
+            if previous == Privilege::Supervisor {
+                self.csr.mstatus |= MSTATUS_SPP;
+            }
+            self.csr.stvec
+        } else {
+            self.csr.mepc = self.pc;
+            self.csr.mcause = cause;
+            self.csr.mtval = tval;
+
+            // mpie = mie, mie = 0, mpp = previous privilege
+            let mie = self.csr.mstatus & MSTATUS_MIE != 0;
+            self.csr.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+            if mie {
+                self.csr.mstatus |= MSTATUS_MPIE;
+            }
+            self.csr.mstatus |= (previous as u32) << 11;
+            self.csr.mtvec
+        };
+
+        self.privilege = target;
+        self.pc = tvec & !0b11;
+        // vectored mode sends interrupts to base + 4 * cause
+        if tvec & 0b11 == 1 && cause & INTERRUPT_BIT != 0 {
+            self.pc = self.pc.wrapping_add(4 * (cause & !INTERRUPT_BIT));
+        }
+    }
+
+    /// Returns from a machine mode trap handler
+    pub(crate) fn mret(&mut self) {
+        let previous = Privilege::from_bits(self.csr.mstatus >> 11);
+
+        // mie = mpie, mpie = 1, mpp = user
+        let mpie = self.csr.mstatus & MSTATUS_MPIE != 0;
+        self.csr.mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        if mpie {
+            self.csr.mstatus |= MSTATUS_MIE;
+        }
+        self.csr.mstatus |= MSTATUS_MPIE;
+        if previous != Privilege::Machine {
+            self.csr.mstatus &= !MSTATUS_MPRV;
+        }
+
+        self.reservation = None;
+        self.privilege = previous;
+        self.pc = self.csr.mepc;
+    }
+
+    /// Returns from a supervisor mode trap handler
+    pub(crate) fn sret(&mut self) {
+        let previous = if self.csr.mstatus & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+
+        // sie = spie, spie = 1, spp = user
+        let spie = self.csr.mstatus & MSTATUS_SPIE != 0;
+        self.csr.mstatus &= !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV);
+        if spie {
+            self.csr.mstatus |= MSTATUS_SIE;
+        }
+        self.csr.mstatus |= MSTATUS_SPIE;
+
+        self.reservation = None;
+        self.privilege = previous;
+        self.pc = self.csr.sepc;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr::{
+        Privilege, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_SIE, MSTATUS_SPP, MTI, SSI,
+    };
+    use crate::trap::{Exception, Interrupt};
+    use crate::vm::VM;
+
+    #[test]
+    fn test_trap_and_mret() {
+        let mut vm = VM::init();
+        vm.csr.mtvec = 0x8000_0101;
+        vm.csr.mstatus |= MSTATUS_MIE;
+        vm.pc = 0x1234;
+
+        vm.trap(Exception::LoadAccessFault(0xdead));
+        assert_eq!(vm.pc, 0x8000_0100);
+        assert_eq!(vm.csr.mepc, 0x1234);
+        assert_eq!(vm.csr.mcause, 5);
+        assert_eq!(vm.csr.mtval, 0xdead);
+        assert_eq!(vm.csr.mstatus & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+
+        vm.csr.mepc = 0x2000;
+        vm.mret();
+        assert_eq!(vm.pc, 0x2000);
+        assert_eq!(
+            vm.csr.mstatus & (MSTATUS_MIE | MSTATUS_MPIE),
+            MSTATUS_MIE | MSTATUS_MPIE
+        );
+        // mpp was machine mode, mret leaves mpp at user mode
+        assert_eq!(vm.privilege, Privilege::Machine);
+        assert_eq!(vm.csr.mstatus & MSTATUS_MPP, 0);
+    }
+
+    #[test]
+    fn test_delegated_trap_and_sret() {
+        let mut vm = VM::init();
+        vm.csr.mtvec = 0x8000_0000;
+        vm.csr.stvec = 0x9000_0000;
+        vm.csr.medeleg = 1 << 8;
+
+        // user ecalls are delegated to supervisor mode
+        vm.privilege = Privilege::User;
+        vm.pc = 0x100;
+        vm.trap(Exception::EnvironmentCallFromUMode);
+        assert_eq!(vm.privilege, Privilege::Supervisor);
+        assert_eq!(vm.pc, 0x9000_0000);
+        assert_eq!(vm.csr.sepc, 0x100);
+        assert_eq!(vm.csr.scause, 8);
+        assert_eq!(vm.csr.mstatus & MSTATUS_SPP, 0);
+
+        // supervisor ecalls are not delegated, mpp records supervisor mode
+        vm.pc = 0x9000_0010;
+        vm.trap(Exception::EnvironmentCallFromSMode);
+        assert_eq!(vm.privilege, Privilege::Machine);
+        assert_eq!(vm.pc, 0x8000_0000);
+        assert_eq!(vm.csr.mcause, 9);
+        assert_eq!(vm.csr.mstatus & MSTATUS_MPP, 1 << 11);
+
+        // delegation never applies to traps taken from machine mode
+        vm.csr.medeleg = u32::MAX;
+        assert_eq!(vm.trap_target(Exception::Breakpoint(0)), Privilege::Machine);
+
+        vm.csr.mepc = 0x9000_0014;
+        vm.mret();
+        assert_eq!(vm.privilege, Privilege::Supervisor);
+        vm.csr.sepc = 0x104;
+        vm.sret();
+        assert_eq!(vm.privilege, Privilege::User);
+        assert_eq!(vm.pc, 0x104);
+    }
+
+    #[test]
+    fn test_interrupt_enables_and_vectoring() {
+        let mut vm = VM::init();
+        vm.csr.mtvec = 0x8000_0001;
+        vm.csr.stvec = 0x9000_0000;
+        vm.csr.mip = MTI | SSI;
+        assert_eq!(vm.pending_interrupt(), None);
+
+        // enabled in mie but machine interrupts are globally disabled in machine mode
+        vm.csr.mie = MTI | SSI;
+        assert_eq!(vm.pending_interrupt(), None);
+        vm.csr.mstatus |= MSTATUS_MIE;
+        assert_eq!(vm.pending_interrupt(), Some(Interrupt::MachineTimer));
+
+        vm.pc = 0x40;
+        vm.interrupt(Interrupt::MachineTimer);
+        assert_eq!(vm.pc, 0x8000_001c);
+        assert_eq!(vm.csr.mcause, 0x8000_0007);
+        assert_eq!(vm.csr.mepc, 0x40);
+        assert_eq!(vm.pending_interrupt(), None);
+
+        // delegated interrupts are taken in supervisor mode from user mode,
+        // regardless of sie
+        vm.csr.mip = SSI;
+        vm.csr.mideleg = SSI;
+        vm.privilege = Privilege::User;
+        assert_eq!(vm.pending_interrupt(), Some(Interrupt::SupervisorSoftware));
+        vm.interrupt(Interrupt::SupervisorSoftware);
+        assert_eq!(vm.privilege, Privilege::Supervisor);
+        assert_eq!(vm.pc, 0x9000_0000);
+        assert_eq!(vm.csr.scause, 0x8000_0001);
+
+        // in supervisor mode they need sie
+        assert_eq!(vm.pending_interrupt(), None);
+        vm.csr.mstatus |= MSTATUS_SIE;
+        assert_eq!(vm.pending_interrupt(), Some(Interrupt::SupervisorSoftware));
+    }
+}
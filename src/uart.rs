@@ -0,0 +1,283 @@
+// NS16550A compatible uart, the console of qemu's virt machine
+// Specification: https://www.ti.com/lit/ds/symlink/pc16550d.pdf
+
+use crate::bus::Device;
+use crate::memory::Memory;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+pub const UART_BASE: u32 = 0x1000_0000;
+pub const UART_SIZE: u32 = 0x100;
+/// Plic source the uart is wired to on qemu's virt machine
+pub const UART_INTERRUPT: u32 = 10;
+
+const FIFO_SIZE: usize = 16;
+
+// register offsets
+const RBR_THR_DLL: u32 = 0;
+const IER_DLM: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+// interrupt enable register
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+
+// interrupt identification register, bits 7:6 report the fifos as enabled
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+// fifo control register
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+// line status register
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_EMPTY: u8 = 1 << 6;
+
+pub struct Uart16550 {
+    output: Box<dyn Write>,
+    // bytes waiting to enter the receive fifo
+    input: VecDeque<u8>,
+    host_input: Option<Receiver<u8>>,
+
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    // the transmitter emptied since iir last reported it
+    tx_empty_pending: bool,
+}
+
+impl Uart16550 {
+    /// Creates a uart that transmits to output and has nothing to receive
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Self {
+            output,
+            input: VecDeque::new(),
+            host_input: None,
+            rx_fifo: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            tx_empty_pending: false,
+        }
+    }
+
+    /// Creates a uart connected to the host's stdout and stdin
+    pub fn stdio() -> Self {
+        Self::new(Box::new(io::stdout())).with_stdin()
+    }
+
+    /// Feeds the host's stdin into the receive fifo
+    /// stdin is read on a separate thread so the guest never blocks on it
+    pub fn with_stdin(mut self) -> Self {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else {
+                    break;
+                };
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        self.host_input = Some(receiver);
+        self
+    }
+
+    /// Queues bytes for the guest to receive
+    pub fn with_input(mut self, bytes: &[u8]) -> Self {
+        self.input.extend(bytes);
+        self
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOPBACK != 0 {
+            if self.rx_fifo.len() < FIFO_SIZE {
+                self.rx_fifo.push_back(byte);
+            }
+        } else {
+            // the guest has no way to observe host write errors
+            let _ = self.output.write_all(&[byte]);
+            let _ = self.output.flush();
+        }
+        // transmission is instant, so the holding register is empty again
+        self.tx_empty_pending = true;
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx_fifo.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_TX_EMPTY != 0 && self.tx_empty_pending {
+            IIR_TX_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut lsr = LSR_THR_EMPTY | LSR_TX_EMPTY;
+        if !self.rx_fifo.is_empty() {
+            lsr |= LSR_DATA_READY;
+        }
+        lsr
+    }
+}
+
+impl Device for Uart16550 {
+    fn read(&mut self, offset: u32, _size: u32) -> u32 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR_THR_DLL if dlab => self.divisor as u8,
+            RBR_THR_DLL => self.rx_fifo.pop_front().unwrap_or(0),
+            IER_DLM if dlab => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                // reading iir acknowledges the transmitter empty interrupt
+                if id == IIR_TX_EMPTY {
+                    self.tx_empty_pending = false;
+                }
+                if self.fcr & FCR_ENABLE != 0 {
+                    id | IIR_FIFO_ENABLED
+                } else {
+                    id
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => self.line_status(),
+            // clear to send, data set ready and carrier detect are always asserted
+            MSR => 0xb0,
+            SCR => self.scr,
+            _ => 0,
+        };
+        value as u32
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32) {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            RBR_THR_DLL => self.transmit(value),
+            IER_DLM if dlab => self.divisor = (self.divisor & 0xff) | ((value as u16) << 8),
+            IER_DLM => {
+                // enabling the transmitter interrupt fires it as the holding register is empty
+                if value & IER_TX_EMPTY != 0 && self.ier & IER_TX_EMPTY == 0 {
+                    self.tx_empty_pending = true;
+                }
+                self.ier = value & 0x0f;
+            }
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx_fifo.clear();
+                }
+                self.fcr = value & !(FCR_CLEAR_RX | 0b100);
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1f,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, _memory: &mut dyn Memory) {
+        if let Some(receiver) = &self.host_input {
+            self.input.extend(receiver.try_iter());
+        }
+        while self.rx_fifo.len() < FIFO_SIZE {
+            let Some(byte) = self.input.pop_front() else {
+                break;
+            };
+            self.rx_fifo.push_back(byte);
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_id() != IIR_NO_INTERRUPT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Device;
+    use crate::capture::CaptureBuffer;
+    use crate::memory::PagedMemory;
+    use crate::uart::{
+        Uart16550, IER_DLM, IIR_FCR, IIR_NO_INTERRUPT, IIR_RX_AVAILABLE, IIR_TX_EMPTY, LCR,
+        LCR_DLAB, LSR, LSR_DATA_READY, RBR_THR_DLL,
+    };
+
+    #[test]
+    fn test_transmit_and_receive() {
+        let output = CaptureBuffer::new();
+        let mut uart = Uart16550::new(Box::new(output.clone())).with_input(b"hi");
+        let mut memory = PagedMemory::new();
+
+        for byte in b"ok\n" {
+            uart.write(RBR_THR_DLL, 1, *byte as u32);
+        }
+        assert_eq!(output.contents(), b"ok\n");
+
+        assert_eq!(uart.read(LSR, 1) as u8 & LSR_DATA_READY, 0);
+        uart.tick(&mut memory);
+        assert_eq!(uart.read(LSR, 1) as u8 & LSR_DATA_READY, LSR_DATA_READY);
+        assert_eq!(uart.read(RBR_THR_DLL, 1), b'h' as u32);
+        assert_eq!(uart.read(RBR_THR_DLL, 1), b'i' as u32);
+        assert_eq!(uart.read(LSR, 1) as u8 & LSR_DATA_READY, 0);
+
+        // the divisor latch shadows the data and interrupt enable registers
+        uart.write(LCR, 1, LCR_DLAB as u32);
+        uart.write(RBR_THR_DLL, 1, 0x34);
+        uart.write(IER_DLM, 1, 0x12);
+        assert_eq!(uart.divisor, 0x1234);
+        uart.write(LCR, 1, 0x03);
+        assert_eq!(output.contents(), b"ok\n");
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut uart = Uart16550::new(Box::new(CaptureBuffer::new())).with_input(b"x");
+        let mut memory = PagedMemory::new();
+        assert!(!uart.interrupt());
+
+        // receive interrupts last until the fifo is drained
+        uart.write(IER_DLM, 1, 0x01);
+        uart.tick(&mut memory);
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(IIR_FCR, 1) as u8, IIR_RX_AVAILABLE);
+        uart.read(RBR_THR_DLL, 1);
+        assert!(!uart.interrupt());
+
+        // the transmitter empty interrupt is acknowledged by reading iir
+        uart.write(IER_DLM, 1, 0x03);
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(IIR_FCR, 1) as u8, IIR_TX_EMPTY);
+        assert!(!uart.interrupt());
+        assert_eq!(uart.read(IIR_FCR, 1) as u8, IIR_NO_INTERRUPT);
+        uart.write(RBR_THR_DLL, 1, b'a' as u32);
+        assert!(uart.interrupt());
+    }
+}
@@ -0,0 +1,817 @@
+// Sandboxed filesystem behind the file syscalls, guest paths never resolve outside of it
+// the guest's working directory is always the root
+
+use crate::memory::PAGE_SIZE;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+// file types in st_mode
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const STATX_BASIC_STATS: u32 = 0x7ff;
+
+// descriptors per vm, the usual RLIMIT_NOFILE soft limit
+pub(crate) const MAX_FILES: u32 = 1024;
+// largest file in a `MemoryFs`, the guest sets the write position
+const MAX_MEMORY_FILE_SIZE: u64 = 256 << 20;
+// default total size and number of entries of a `MemoryFs`, shared by all of its files
+const MEMORY_FS_CAPACITY: u64 = 512 << 20;
+const MAX_MEMORY_FS_ENTRIES: u64 = 64 * 1024;
+
+/// Where guest paths are resolved, see `VM::set_filesystem`
+#[derive(Debug, Clone)]
+pub enum Filesystem {
+    /// A tree of files kept in memory, nothing on the host is visible
+    Memory(MemoryFs),
+    /// A host directory acting as the guest's root
+    Host(HostDir),
+}
+
+impl Default for Filesystem {
+    fn default() -> Self {
+        Filesystem::Memory(MemoryFs::new())
+    }
+}
+
+/// How a file is opened, decoded from the openat flags
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OpenFlags {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) append: bool,
+    pub(crate) truncate: bool,
+    pub(crate) create: bool,
+    pub(crate) exclusive: bool,
+}
+
+/// An open file, shared by every descriptor duplicated from it so they share the offset
+pub(crate) trait GuestFile: Read + Write + Seek {
+    fn stat(&self) -> io::Result<FileStat>;
+}
+
+impl GuestFile for File {
+    fn stat(&self) -> io::Result<FileStat> {
+        Ok(FileStat::from_metadata(&self.metadata()?))
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct FileDescription {
+    pub(crate) file: Rc<RefCell<dyn GuestFile>>,
+    pub(crate) readable: bool,
+    pub(crate) writable: bool,
+}
+
+pub(crate) enum OpenFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(FileDescription),
+}
+
+impl Clone for OpenFile {
+    fn clone(&self) -> Self {
+        match self {
+            OpenFile::Stdin => OpenFile::Stdin,
+            OpenFile::Stdout => OpenFile::Stdout,
+            OpenFile::Stderr => OpenFile::Stderr,
+            OpenFile::File(description) => OpenFile::File(description.clone()),
+        }
+    }
+}
+
+/// The file descriptors of a vm, 0 to 2 start out as the standard streams
+pub(crate) struct FileTable {
+    files: Vec<Option<OpenFile>>,
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self {
+            files: vec![
+                Some(OpenFile::Stdin),
+                Some(OpenFile::Stdout),
+                Some(OpenFile::Stderr),
+            ],
+        }
+    }
+}
+
+impl FileTable {
+    pub(crate) fn get(&mut self, fd: u32) -> Option<&mut OpenFile> {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(|file| file.as_mut())
+    }
+
+    /// Stores file in the lowest free descriptor, None if all `MAX_FILES` are in use
+    pub(crate) fn insert(&mut self, file: OpenFile) -> Option<u32> {
+        match self.files.iter().position(|file| file.is_none()) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                Some(fd as u32)
+            }
+            None if self.files.len() < MAX_FILES as usize => {
+                self.files.push(Some(file));
+                Some(self.files.len() as u32 - 1)
+            }
+            None => None,
+        }
+    }
+
+    /// Stores file in fd, closing whatever it referred to, None if fd is past `MAX_FILES`
+    pub(crate) fn insert_at(&mut self, fd: u32, file: OpenFile) -> Option<()> {
+        if fd >= MAX_FILES {
+            return None;
+        }
+        if self.files.len() <= fd as usize {
+            self.files.resize_with(fd as usize + 1, || None);
+        }
+        self.files[fd as usize] = Some(file);
+        Some(())
+    }
+
+    /// Removes fd, None if it was not open
+    pub(crate) fn remove(&mut self, fd: u32) -> Option<OpenFile> {
+        self.files.get_mut(fd as usize).and_then(|file| file.take())
+    }
+}
+
+impl Filesystem {
+    pub(crate) fn open(&self, path: &str, flags: OpenFlags) -> io::Result<FileDescription> {
+        let file: Rc<RefCell<dyn GuestFile>> = match self {
+            Filesystem::Memory(tree) => Rc::new(RefCell::new(tree.open(path, flags)?)),
+            Filesystem::Host(dir) => {
+                let file = OpenOptions::new()
+                    .read(flags.read)
+                    .write(flags.write)
+                    .append(flags.append)
+                    .truncate(flags.truncate)
+                    .create(flags.create)
+                    .create_new(flags.create && flags.exclusive)
+                    .open(dir.resolve(path)?)?;
+                Rc::new(RefCell::new(file))
+            }
+        };
+        Ok(FileDescription {
+            file,
+            readable: flags.read,
+            writable: flags.write,
+        })
+    }
+
+    pub(crate) fn stat(&self, path: &str) -> io::Result<FileStat> {
+        match self {
+            Filesystem::Memory(tree) => tree.stat(path),
+            Filesystem::Host(dir) => {
+                Ok(FileStat::from_metadata(&fs::metadata(dir.resolve(path)?)?))
+            }
+        }
+    }
+
+    pub(crate) fn create_dir(&self, path: &str) -> io::Result<()> {
+        match self {
+            Filesystem::Memory(tree) => tree.create_dir(path),
+            Filesystem::Host(dir) => fs::create_dir(dir.resolve(path)?),
+        }
+    }
+
+    /// Removes the file, or the empty directory if dir is set
+    pub(crate) fn remove(&self, path: &str, dir: bool) -> io::Result<()> {
+        match self {
+            Filesystem::Memory(tree) => tree.remove(path, dir),
+            Filesystem::Host(host) if dir => fs::remove_dir(host.resolve(path)?),
+            Filesystem::Host(host) => fs::remove_file(host.resolve(path)?),
+        }
+    }
+}
+
+/// Splits a guest path into its components, resolving `.` and `..` without leaving the root
+fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    components
+}
+
+/// A host directory the guest sees as its root, `..` and symlinks can't leave it
+#[derive(Debug, Clone)]
+pub struct HostDir {
+    root: PathBuf,
+}
+
+impl HostDir {
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        Ok(Self { root })
+    }
+
+    /// The host path of a guest path, permission is denied if a symlink points outside the root
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let path = components(path)
+            .iter()
+            .fold(self.root.clone(), |path, component| path.join(component));
+
+        // symlinks are resolved on the longest prefix that exists, the rest is created later
+        let mut existing = path.as_path();
+        let mut missing = Vec::new();
+        let real = loop {
+            match existing.canonicalize() {
+                Ok(real) => break real,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    // a dangling symlink would be followed when creating the file
+                    if existing.symlink_metadata().is_ok() {
+                        return Err(io::ErrorKind::PermissionDenied.into());
+                    }
+                    missing.extend(existing.file_name());
+                    existing = existing.parent().ok_or(err)?;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        if !real.starts_with(&self.root) {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        Ok(missing
+            .iter()
+            .rev()
+            .fold(real, |path, component| path.join(component)))
+    }
+}
+
+type Directory = BTreeMap<String, Node>;
+
+#[derive(Debug)]
+enum Node {
+    File(Rc<RefCell<FileData>>),
+    Directory(Directory),
+}
+
+impl Node {
+    /// Number of entries in the tree rooted at this node, including itself
+    fn entries(&self) -> u64 {
+        match self {
+            Node::File(_) => 1,
+            Node::Directory(entries) => 1 + entries.values().map(Node::entries).sum::<u64>(),
+        }
+    }
+}
+
+/// Space used by a `MemoryFs`, shared by its clones and open files
+#[derive(Debug)]
+struct Usage {
+    bytes: Cell<u64>,
+    entries: Cell<u64>,
+    capacity: u64,
+}
+
+impl Usage {
+    /// Counts a new directory entry, fails once the tree holds too many
+    fn add_entry(&self) -> io::Result<()> {
+        if self.entries.get() >= MAX_MEMORY_FS_ENTRIES {
+            return Err(io::ErrorKind::StorageFull.into());
+        }
+        self.entries.set(self.entries.get() + 1);
+        Ok(())
+    }
+
+    fn remove_entry(&self) {
+        self.entries.set(self.entries.get() - 1);
+    }
+}
+
+/// Contents of a file, counted against the capacity until the last reference is dropped
+#[derive(Debug)]
+struct FileData {
+    bytes: Vec<u8>,
+    usage: Rc<Usage>,
+}
+
+impl FileData {
+    fn new(bytes: Vec<u8>, usage: &Rc<Usage>) -> Self {
+        usage.bytes.set(usage.bytes.get() + bytes.len() as u64);
+        FileData {
+            bytes,
+            usage: usage.clone(),
+        }
+    }
+
+    /// Grows or shrinks the file, growing fails once the tree is out of space
+    fn resize(&mut self, len: usize) -> io::Result<()> {
+        let used = self.usage.bytes.get() - self.bytes.len() as u64;
+        if len > self.bytes.len() && used + len as u64 > self.usage.capacity {
+            return Err(io::ErrorKind::StorageFull.into());
+        }
+        self.bytes.resize(len, 0);
+        self.usage.bytes.set(used + len as u64);
+        Ok(())
+    }
+}
+
+impl Drop for FileData {
+    fn drop(&mut self) {
+        let bytes = self.usage.bytes.get() - self.bytes.len() as u64;
+        self.usage.bytes.set(bytes);
+    }
+}
+
+/// A tree of files kept in memory, clones share the same tree so files written by the guest
+/// can be read back through `MemoryFs::read_file`
+#[derive(Debug, Clone)]
+pub struct MemoryFs {
+    root: Rc<RefCell<Directory>>,
+    usage: Rc<Usage>,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::with_capacity(MEMORY_FS_CAPACITY)
+    }
+
+    /// A tree whose files can hold at most capacity bytes in total, writes past it fail with
+    /// ENOSPC. Files added with `add_file` count towards it but are never refused
+    pub fn with_capacity(capacity: u64) -> Self {
+        MemoryFs {
+            root: Rc::default(),
+            usage: Rc::new(Usage {
+                bytes: Cell::new(0),
+                entries: Cell::new(0),
+                capacity,
+            }),
+        }
+    }
+
+    /// Adds a file at path, creating its parent directories and replacing an existing file
+    pub fn add_file(&self, path: &str, contents: &[u8]) {
+        let components = components(path);
+        let Some((name, parents)) = components.split_last() else {
+            return;
+        };
+        let mut root = self.root.borrow_mut();
+        let mut directory = &mut *root;
+        for parent in parents {
+            let node = directory.entry(parent.to_string()).or_insert_with(|| {
+                self.usage.entries.set(self.usage.entries.get() + 1);
+                Node::Directory(Directory::new())
+            });
+            if let Node::File(_) = node {
+                *node = Node::Directory(Directory::new());
+            }
+            let Node::Directory(next) = node else {
+                unreachable!()
+            };
+            directory = next;
+        }
+        let file = Node::File(Rc::new(RefCell::new(FileData::new(
+            contents.to_vec(),
+            &self.usage,
+        ))));
+        // a replaced directory takes its entries with it
+        let removed = directory
+            .insert(name.to_string(), file)
+            .map_or(0, |node| node.entries());
+        self.usage
+            .entries
+            .set(self.usage.entries.get() + 1 - removed);
+    }
+
+    /// Contents of the file at path, None if there is no such file
+    pub fn read_file(&self, path: &str) -> Option<Vec<u8>> {
+        let components = components(path);
+        let (name, parents) = components.split_last()?;
+        let root = self.root.borrow();
+        let mut directory = &*root;
+        for parent in parents {
+            match directory.get(*parent)? {
+                Node::Directory(next) => directory = next,
+                Node::File(_) => return None,
+            }
+        }
+        match directory.get(*name)? {
+            Node::File(contents) => Some(contents.borrow().bytes.clone()),
+            Node::Directory(_) => None,
+        }
+    }
+
+    /// Runs f on the directory holding the last component of path and the component's name,
+    /// the name is None for the root
+    fn with_parent<T>(
+        &self,
+        path: &str,
+        f: impl FnOnce(&mut Directory, Option<&str>) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let components = components(path);
+        let mut root = self.root.borrow_mut();
+        let Some((name, parents)) = components.split_last() else {
+            return f(&mut root, None);
+        };
+        let mut directory = &mut *root;
+        for parent in parents {
+            match directory.get_mut(*parent) {
+                Some(Node::Directory(next)) => directory = next,
+                Some(Node::File(_)) => return Err(io::ErrorKind::NotADirectory.into()),
+                None => return Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+        f(directory, Some(name))
+    }
+
+    fn open(&self, path: &str, flags: OpenFlags) -> io::Result<MemoryFile> {
+        let contents = self.with_parent(path, |directory, name| {
+            let Some(name) = name else {
+                return Err(io::ErrorKind::IsADirectory.into());
+            };
+            match directory.get(name) {
+                Some(Node::Directory(_)) => Err(io::ErrorKind::IsADirectory.into()),
+                Some(Node::File(_)) if flags.create && flags.exclusive => {
+                    Err(io::ErrorKind::AlreadyExists.into())
+                }
+                Some(Node::File(contents)) => Ok(contents.clone()),
+                None if flags.create => {
+                    self.usage.add_entry()?;
+                    let contents = Rc::new(RefCell::new(FileData::new(Vec::new(), &self.usage)));
+                    directory.insert(name.to_string(), Node::File(contents.clone()));
+                    Ok(contents)
+                }
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        })?;
+        if flags.truncate && flags.write {
+            contents.borrow_mut().resize(0)?;
+        }
+        Ok(MemoryFile {
+            contents,
+            position: 0,
+            append: flags.append,
+        })
+    }
+
+    fn stat(&self, path: &str) -> io::Result<FileStat> {
+        self.with_parent(path, |directory, name| {
+            let node = match name {
+                Some(name) => directory.get(name).ok_or(io::ErrorKind::NotFound)?,
+                None => return Ok(FileStat::directory()),
+            };
+            Ok(match node {
+                Node::File(contents) => FileStat::file(contents.borrow().bytes.len() as u64),
+                Node::Directory(_) => FileStat::directory(),
+            })
+        })
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.with_parent(path, |directory, name| {
+            let name = name.ok_or(io::ErrorKind::AlreadyExists)?;
+            if directory.contains_key(name) {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            self.usage.add_entry()?;
+            directory.insert(name.to_string(), Node::Directory(Directory::new()));
+            Ok(())
+        })
+    }
+
+    fn remove(&self, path: &str, dir: bool) -> io::Result<()> {
+        self.with_parent(path, |directory, name| {
+            // the root can't be removed
+            let name = name.ok_or(io::ErrorKind::PermissionDenied)?;
+            match (directory.get(name), dir) {
+                (None, _) => return Err(io::ErrorKind::NotFound.into()),
+                (Some(Node::File(_)), true) => return Err(io::ErrorKind::NotADirectory.into()),
+                (Some(Node::Directory(_)), false) => return Err(io::ErrorKind::IsADirectory.into()),
+                (Some(Node::Directory(entries)), true) if !entries.is_empty() => {
+                    return Err(io::ErrorKind::DirectoryNotEmpty.into())
+                }
+                _ => {}
+            }
+            // open descriptors keep the contents of a removed file alive
+            directory.remove(name);
+            self.usage.remove_entry();
+            Ok(())
+        })
+    }
+}
+
+/// An open file of a `MemoryFs`
+struct MemoryFile {
+    contents: Rc<RefCell<FileData>>,
+    position: u64,
+    append: bool,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let contents = &self.contents.borrow().bytes;
+        let start = (self.position as usize).min(contents.len());
+        let len = buf.len().min(contents.len() - start);
+        buf[..len].copy_from_slice(&contents[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut contents = self.contents.borrow_mut();
+        if self.append {
+            self.position = contents.bytes.len() as u64;
+        }
+        // like a file size rlimit, a write that crosses the limit is cut short
+        if self.position >= MAX_MEMORY_FILE_SIZE && !buf.is_empty() {
+            return Err(io::ErrorKind::FileTooLarge.into());
+        }
+        let len = buf
+            .len()
+            .min((MAX_MEMORY_FILE_SIZE - self.position) as usize);
+        let start = self.position as usize;
+        // writing past the end leaves a hole of zeros
+        if contents.bytes.len() < start + len {
+            contents.resize(start + len)?;
+        }
+        contents.bytes[start..start + len].copy_from_slice(&buf[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(offset) => (self.position, offset),
+            SeekFrom::End(offset) => (self.contents.borrow().bytes.len() as u64, offset),
+        };
+        self.position = base
+            .checked_add_signed(offset)
+            .ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl GuestFile for MemoryFile {
+    fn stat(&self) -> io::Result<FileStat> {
+        Ok(FileStat::file(self.contents.borrow().bytes.len() as u64))
+    }
+}
+
+/// The parts of a stat structure the guest gets to see
+#[derive(Debug)]
+pub(crate) struct FileStat {
+    mode: u32,
+    size: u64,
+    mtime: (i64, u32),
+}
+
+impl FileStat {
+    fn from_metadata(metadata: &Metadata) -> Self {
+        let permissions = if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        };
+        let mode = if metadata.is_dir() {
+            S_IFDIR | permissions | 0o111
+        } else {
+            S_IFREG | permissions
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or((0, 0), |time| (time.as_secs() as i64, time.subsec_nanos()));
+        Self {
+            mode,
+            size: metadata.len(),
+            mtime,
+        }
+    }
+
+    fn file(size: u64) -> Self {
+        Self {
+            mode: S_IFREG | 0o644,
+            size,
+            mtime: (0, 0),
+        }
+    }
+
+    fn directory() -> Self {
+        Self {
+            mode: S_IFDIR | 0o755,
+            size: 0,
+            mtime: (0, 0),
+        }
+    }
+
+    pub(crate) fn terminal() -> Self {
+        Self {
+            mode: S_IFCHR | 0o620,
+            size: 0,
+            mtime: (0, 0),
+        }
+    }
+
+    /// struct statx from include/uapi/linux/stat.h
+    pub(crate) fn statx(&self) -> Vec<u8> {
+        let mut buf = vec![0; 256];
+        buf[0..4].copy_from_slice(&STATX_BASIC_STATS.to_le_bytes());
+        buf[4..8].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        buf[16..20].copy_from_slice(&1u32.to_le_bytes());
+        buf[28..30].copy_from_slice(&(self.mode as u16).to_le_bytes());
+        buf[40..48].copy_from_slice(&self.size.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.size.div_ceil(512).to_le_bytes());
+        // atime, btime, ctime and mtime
+        for time in [64, 80, 96, 112] {
+            buf[time..time + 8].copy_from_slice(&self.mtime.0.to_le_bytes());
+            buf[time + 8..time + 12].copy_from_slice(&self.mtime.1.to_le_bytes());
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vfs::{
+        components, FileTable, Filesystem, HostDir, MemoryFs, OpenFile, OpenFlags, MAX_FILES,
+        MAX_MEMORY_FILE_SIZE, MAX_MEMORY_FS_ENTRIES,
+    };
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::io::SeekFrom;
+
+    #[test]
+    fn test_components() {
+        assert_eq!(components("/a/./b//c"), vec!["a", "b", "c"]);
+        assert_eq!(components("a/../../b"), vec!["b"]);
+        assert!(components("/../..").is_empty());
+    }
+
+    #[test]
+    fn test_file_table_limit() {
+        let mut files = FileTable::default();
+        assert_eq!(files.insert_at(MAX_FILES, OpenFile::Stdin), None);
+        assert_eq!(files.insert_at(MAX_FILES - 1, OpenFile::Stdin), Some(()));
+
+        // the free descriptors below the highest one fill up first
+        for fd in 3..MAX_FILES - 1 {
+            assert_eq!(files.insert(OpenFile::Stdin), Some(fd));
+        }
+        assert_eq!(files.insert(OpenFile::Stdin), None);
+        files.remove(10);
+        assert_eq!(files.insert(OpenFile::Stdin), Some(10));
+    }
+
+    #[test]
+    fn test_memory_fs() {
+        let tree = MemoryFs::new();
+        tree.add_file("/etc/motd", b"hello");
+        let fs = Filesystem::Memory(tree.clone());
+
+        let read = OpenFlags {
+            read: true,
+            ..OpenFlags::default()
+        };
+        let description = fs.open("/etc/../etc/motd", read).unwrap();
+        let mut contents = String::new();
+        description
+            .file
+            .borrow_mut()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "hello");
+
+        let create = OpenFlags {
+            write: true,
+            create: true,
+            ..OpenFlags::default()
+        };
+        let err = fs.open("/tmp/out", create).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        fs.create_dir("/tmp").unwrap();
+        let description = fs.open("/tmp/out", create).unwrap();
+        let mut file = description.file.borrow_mut();
+        file.seek(SeekFrom::Start(2)).unwrap();
+        file.write_all(b"xy").unwrap();
+        assert_eq!(tree.read_file("/tmp/out").unwrap(), b"\0\0xy");
+        assert_eq!(file.stat().unwrap().size, 4);
+
+        // seeking far past the end doesn't allow an unbounded write
+        file.seek(SeekFrom::Start(MAX_MEMORY_FILE_SIZE)).unwrap();
+        let err = file.write(b"z").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        file.seek(SeekFrom::Start(u64::MAX / 2)).unwrap();
+        let err = file.write(b"z").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        assert_eq!(file.stat().unwrap().size, 4);
+
+        let err = fs.remove("/tmp", true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::DirectoryNotEmpty);
+        fs.remove("/tmp/out", false).unwrap();
+        fs.remove("/tmp", true).unwrap();
+        assert_eq!(tree.read_file("/tmp/out"), None);
+    }
+
+    #[test]
+    fn test_memory_fs_capacity() {
+        let tree = MemoryFs::with_capacity(8);
+        tree.add_file("/a", b"abc");
+        let fs = Filesystem::Memory(tree.clone());
+        let create = OpenFlags {
+            write: true,
+            create: true,
+            ..OpenFlags::default()
+        };
+
+        // the capacity is shared by every file in the tree
+        let description = fs.open("/b", create).unwrap();
+        let mut file = description.file.borrow_mut();
+        assert_eq!(file.write(b"defgh").unwrap(), 5);
+        let err = file.write(b"i").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(tree.usage.bytes.get(), 8);
+
+        // removing an open file only frees its space once it's closed
+        fs.remove("/b", false).unwrap();
+        assert_eq!(tree.usage.bytes.get(), 8);
+        assert_eq!(tree.usage.entries.get(), 1);
+        drop(file);
+        drop(description);
+        assert_eq!(tree.usage.bytes.get(), 3);
+        fs.remove("/a", false).unwrap();
+        assert_eq!(tree.usage.bytes.get(), 0);
+
+        let description = fs.open("/c", create).unwrap();
+        description
+            .file
+            .borrow_mut()
+            .write_all(b"12345678")
+            .unwrap();
+
+        // so do directories and files, counted as entries
+        tree.usage.entries.set(MAX_MEMORY_FS_ENTRIES);
+        let err = fs.create_dir("/d").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        let err = fs.open("/e", create).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    }
+
+    #[test]
+    fn test_host_dir_jail() {
+        let base = env::temp_dir().join(format!("riscv-vfs-{}", std::process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(base.join("secret"), b"secret").unwrap();
+        fs::write(root.join("sub/file"), b"file").unwrap();
+        std::os::unix::fs::symlink("../secret", root.join("escape")).unwrap();
+        std::os::unix::fs::symlink("sub/file", root.join("inside")).unwrap();
+        std::os::unix::fs::symlink("../new", root.join("dangling")).unwrap();
+
+        let fs = Filesystem::Host(HostDir::new(&root).unwrap());
+        assert_eq!(fs.stat("/sub/file").unwrap().size, 4);
+        assert_eq!(fs.stat("inside").unwrap().size, 4);
+        // .. stops at the root instead of reaching the secret
+        let err = fs.stat("/../secret").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = fs.stat("/escape").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let create = OpenFlags {
+            write: true,
+            create: true,
+            ..OpenFlags::default()
+        };
+        let err = fs.open("/dangling", create).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!base.join("new").exists());
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
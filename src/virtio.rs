@@ -0,0 +1,613 @@
+// Virtio block device over the mmio transport (version 2) with split virtqueues
+// Specification: https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+
+use crate::bus::Device;
+use crate::memory::Memory;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Address of the first virtio-mmio slot on qemu's virt machine, further slots follow every `VIRTIO_SIZE` bytes
+pub const VIRTIO_BASE: u32 = 0x1000_1000;
+pub const VIRTIO_SIZE: u32 = 0x1000;
+/// Plic source of the first virtio-mmio slot on qemu's virt machine
+pub const VIRTIO_INTERRUPT: u32 = 1;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+// mmio registers
+const MAGIC_VALUE: u32 = 0x000;
+const VERSION: u32 = 0x004;
+const DEVICE_ID: u32 = 0x008;
+const VENDOR_ID: u32 = 0x00c;
+const DEVICE_FEATURES: u32 = 0x010;
+const DEVICE_FEATURES_SEL: u32 = 0x014;
+const DRIVER_FEATURES: u32 = 0x020;
+const DRIVER_FEATURES_SEL: u32 = 0x024;
+const QUEUE_SEL: u32 = 0x030;
+const QUEUE_NUM_MAX: u32 = 0x034;
+const QUEUE_NUM: u32 = 0x038;
+const QUEUE_READY: u32 = 0x044;
+const QUEUE_NOTIFY: u32 = 0x050;
+const INTERRUPT_STATUS: u32 = 0x060;
+const INTERRUPT_ACK: u32 = 0x064;
+const STATUS: u32 = 0x070;
+const QUEUE_DESC_LOW: u32 = 0x080;
+const QUEUE_DESC_HIGH: u32 = 0x084;
+const QUEUE_DRIVER_LOW: u32 = 0x090;
+const QUEUE_DRIVER_HIGH: u32 = 0x094;
+const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+const CONFIG_GENERATION: u32 = 0x0fc;
+const CONFIG: u32 = 0x100;
+
+// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+const BLOCK_DEVICE_ID: u32 = 2;
+// "QEMU", linux doesn't care about the vendor
+const VENDOR: u32 = 0x554d_4551;
+
+const QUEUE_SIZE_MAX: u32 = 256;
+const INTERRUPT_USED_BUFFER: u32 = 1;
+
+// feature bits
+const BLK_F_FLUSH: u64 = 1 << 9;
+const F_VERSION_1: u64 = 1 << 32;
+const FEATURES: u64 = BLK_F_FLUSH | F_VERSION_1;
+
+// descriptor flags
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+// block request types and status
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_T_FLUSH: u32 = 4;
+const BLK_T_GET_ID: u32 = 8;
+const BLK_S_OK: u8 = 0;
+const BLK_S_IOERR: u8 = 1;
+const BLK_S_UNSUPP: u8 = 2;
+
+const REQUEST_HEADER_SIZE: usize = 16;
+// request data is copied between guest memory and the disk in chunks of at most this size
+const IO_CHUNK: u32 = 64 * 1024;
+const DEVICE_ID_STRING: &[u8] = b"riscv-vm-disk";
+
+/// Storage backing a block device, a host file or an in memory image
+pub trait DiskImage: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> DiskImage for T {}
+
+/// Split virtqueue state, addresses are guest physical
+#[derive(Default)]
+struct Queue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    // next available ring entry to process
+    last_avail: u16,
+}
+
+/// One buffer of a descriptor chain
+struct Buffer {
+    addr: u32,
+    len: u32,
+    writable: bool,
+}
+
+pub struct VirtioBlock {
+    disk: Box<dyn DiskImage>,
+    capacity: u64,
+
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queue: Queue,
+    interrupt_status: u32,
+    status: u32,
+    // the driver made new buffers available
+    notified: bool,
+}
+
+impl VirtioBlock {
+    /// Creates a block device backed by disk, whose size is rounded down to whole sectors
+    pub fn new(mut disk: Box<dyn DiskImage>) -> io::Result<Self> {
+        let size = disk.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            disk,
+            capacity: size / SECTOR_SIZE,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queue: Queue::default(),
+            interrupt_status: 0,
+            status: 0,
+            notified: false,
+        })
+    }
+
+    /// Creates a block device backed by the image file at path, guest writes go to the file
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::new(Box::new(file))
+    }
+
+    /// Size of the disk in sectors
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queue = Queue::default();
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.notified = false;
+    }
+
+    fn config(&self) -> [u8; 8] {
+        self.capacity.to_le_bytes()
+    }
+
+    /// Processes every buffer the driver made available since the last notification
+    fn process_queue(&mut self, memory: &mut dyn Memory) {
+        if !self.queue.ready || self.queue.num == 0 {
+            return;
+        }
+        let num = self.queue.num;
+        let driver = self.queue.driver as u32;
+        let device = self.queue.device as u32;
+
+        let avail_idx = read_u16(memory, driver.wrapping_add(2));
+        while self.queue.last_avail != avail_idx {
+            let slot = self.queue.last_avail as u32 % num;
+            let head = read_u16(memory, driver.wrapping_add(4 + 2 * slot));
+            let written = self.handle_request(memory, head);
+
+            let used_idx = read_u16(memory, device.wrapping_add(2));
+            let entry = device.wrapping_add(4 + 8 * (used_idx as u32 % num));
+            write_u32(memory, entry, head as u32);
+            write_u32(memory, entry.wrapping_add(4), written);
+            write_u16(memory, device.wrapping_add(2), used_idx.wrapping_add(1));
+
+            self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
+
+    /// Follows the descriptor chain starting at head, None if it is malformed
+    fn chain(&self, memory: &dyn Memory, head: u16) -> Option<Vec<Buffer>> {
+        let mut buffers = Vec::new();
+        let mut index = head as u32;
+        loop {
+            // a chain can't be longer than the queue, anything else is a loop
+            if index >= self.queue.num || buffers.len() as u32 >= self.queue.num {
+                return None;
+            }
+            let desc = (self.queue.desc as u32).wrapping_add(16 * index);
+            let flags = read_u16(memory, desc.wrapping_add(12));
+            buffers.push(Buffer {
+                addr: read_u32(memory, desc),
+                len: read_u32(memory, desc.wrapping_add(8)),
+                writable: flags & DESC_F_WRITE != 0,
+            });
+            if flags & DESC_F_NEXT == 0 {
+                return Some(buffers);
+            }
+            index = read_u16(memory, desc.wrapping_add(14)) as u32;
+        }
+    }
+
+    /// Executes a block request, returns the number of bytes written to guest memory
+    fn handle_request(&mut self, memory: &mut dyn Memory, head: u16) -> u32 {
+        let Some(buffers) = self.chain(memory, head) else {
+            return 0;
+        };
+        // the status byte is the last byte of the last buffer
+        let Some(status_buffer) = buffers.last().filter(|b| b.writable && b.len > 0) else {
+            return 0;
+        };
+        let status_addr = status_buffer.addr.wrapping_add(status_buffer.len - 1);
+
+        let readable: Vec<(u32, u32)> = buffers
+            .iter()
+            .filter(|b| !b.writable)
+            .map(|b| (b.addr, b.len))
+            .collect();
+        let mut targets: Vec<(u32, u32)> = buffers
+            .iter()
+            .filter(|b| b.writable)
+            .map(|b| (b.addr, b.len))
+            .collect();
+        if let Some(last) = targets.last_mut() {
+            last.1 -= 1;
+        }
+
+        // the header can be split across buffers, the data follows it
+        let header = gather(memory, &readable, REQUEST_HEADER_SIZE);
+        let data = skip(&readable, REQUEST_HEADER_SIZE as u32);
+        let mut written = 0;
+        let status = if header.len() < REQUEST_HEADER_SIZE {
+            BLK_S_IOERR
+        } else {
+            let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            match kind {
+                BLK_T_IN => match self.read_disk(memory, sector, &targets) {
+                    Ok(len) => {
+                        written = len;
+                        BLK_S_OK
+                    }
+                    Err(_) => BLK_S_IOERR,
+                },
+                BLK_T_OUT => match self.write_disk(memory, sector, &data) {
+                    Ok(()) => BLK_S_OK,
+                    Err(_) => BLK_S_IOERR,
+                },
+                BLK_T_FLUSH => match self.disk.flush() {
+                    Ok(()) => BLK_S_OK,
+                    Err(_) => BLK_S_IOERR,
+                },
+                BLK_T_GET_ID => {
+                    written = scatter(memory, &targets, DEVICE_ID_STRING);
+                    BLK_S_OK
+                }
+                _ => BLK_S_UNSUPP,
+            }
+        };
+
+        *memory.byte_mut(status_addr) = status;
+        written + 1
+    }
+
+    fn check_range(&self, sector: u64, len: u64) -> io::Result<u64> {
+        let offset = sector.checked_mul(SECTOR_SIZE);
+        let end = offset.and_then(|offset| offset.checked_add(len));
+        match (offset, end) {
+            (Some(offset), Some(end)) if end <= self.capacity * SECTOR_SIZE => Ok(offset),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "access past the end of the disk",
+            )),
+        }
+    }
+
+    /// Reads from sector into the guest buffers, returns the number of bytes read
+    fn read_disk(
+        &mut self,
+        memory: &mut dyn Memory,
+        sector: u64,
+        targets: &[(u32, u32)],
+    ) -> io::Result<u32> {
+        let len = total_len(targets);
+        // the used ring reports the length as a u32
+        let written = u32::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "request too large"))?;
+        let offset = self.check_range(sector, len)?;
+        self.disk.seek(SeekFrom::Start(offset))?;
+        let mut chunk = vec![0; len.min(IO_CHUNK as u64) as usize];
+        for &(addr, len) in targets {
+            for done in (0..len).step_by(IO_CHUNK as usize) {
+                let chunk = &mut chunk[..(len - done).min(IO_CHUNK) as usize];
+                self.disk.read_exact(chunk)?;
+                memory.write_bytes(addr.wrapping_add(done), chunk);
+            }
+        }
+        Ok(written)
+    }
+
+    /// Writes the guest buffers to the disk starting at sector
+    fn write_disk(
+        &mut self,
+        memory: &dyn Memory,
+        sector: u64,
+        sources: &[(u32, u32)],
+    ) -> io::Result<()> {
+        let offset = self.check_range(sector, total_len(sources))?;
+        self.disk.seek(SeekFrom::Start(offset))?;
+        for &(addr, len) in sources {
+            for done in (0..len).step_by(IO_CHUNK as usize) {
+                let chunk_len = (len - done).min(IO_CHUNK) as usize;
+                self.disk
+                    .write_all(&memory.read_bytes(addr.wrapping_add(done), chunk_len))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for VirtioBlock {
+    fn read(&mut self, offset: u32, size: u32) -> u32 {
+        if offset >= CONFIG {
+            let config = self.config();
+            let start = (offset - CONFIG) as usize;
+            return (0..size as usize)
+                .map(|i| config.get(start + i).copied().unwrap_or(0) as u32)
+                .enumerate()
+                .fold(0, |value, (i, byte)| value | byte << (8 * i));
+        }
+
+        let queue_selected = self.queue_sel == 0;
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => BLOCK_DEVICE_ID,
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => FEATURES as u32,
+                1 => (FEATURES >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX if queue_selected => QUEUE_SIZE_MAX,
+            QUEUE_READY if queue_selected => self.queue.ready as u32,
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            // the configuration never changes
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32) {
+        let queue_selected = self.queue_sel == 0;
+        let low = |field: u64| (field & !(u32::MAX as u64)) | value as u64;
+        let high = |field: u64| (field & u32::MAX as u64) | ((value as u64) << 32);
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = low(self.driver_features) & FEATURES,
+                1 => self.driver_features = high(self.driver_features) & FEATURES,
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM if queue_selected => self.queue.num = value.min(QUEUE_SIZE_MAX),
+            QUEUE_READY if queue_selected => self.queue.ready = value & 1 == 1,
+            QUEUE_NOTIFY => self.notified = true,
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS if value == 0 => self.reset(),
+            STATUS => self.status = value,
+            QUEUE_DESC_LOW if queue_selected => self.queue.desc = low(self.queue.desc),
+            QUEUE_DESC_HIGH if queue_selected => self.queue.desc = high(self.queue.desc),
+            QUEUE_DRIVER_LOW if queue_selected => self.queue.driver = low(self.queue.driver),
+            QUEUE_DRIVER_HIGH if queue_selected => self.queue.driver = high(self.queue.driver),
+            QUEUE_DEVICE_LOW if queue_selected => self.queue.device = low(self.queue.device),
+            QUEUE_DEVICE_HIGH if queue_selected => self.queue.device = high(self.queue.device),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, memory: &mut dyn Memory) {
+        if self.notified {
+            self.notified = false;
+            self.process_queue(memory);
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+}
+
+/// Copies bytes into the (addr, len) targets in order, returns the number of bytes copied
+fn scatter(memory: &mut dyn Memory, targets: &[(u32, u32)], bytes: &[u8]) -> u32 {
+    let mut copied = 0;
+    for (addr, len) in targets {
+        let chunk = &bytes[copied..bytes.len().min(copied + *len as usize)];
+        memory.write_bytes(*addr, chunk);
+        copied += chunk.len();
+    }
+    copied as u32
+}
+
+/// The first len bytes of the buffers, fewer if they are shorter
+fn gather(memory: &dyn Memory, buffers: &[(u32, u32)], len: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (addr, buffer_len) in buffers {
+        let take = (*buffer_len as usize).min(len - bytes.len());
+        bytes.extend(memory.read_bytes(*addr, take));
+    }
+    bytes
+}
+
+/// The buffers without their first len bytes
+fn skip(buffers: &[(u32, u32)], mut len: u32) -> Vec<(u32, u32)> {
+    let mut rest = Vec::new();
+    for &(addr, buffer_len) in buffers {
+        let skipped = buffer_len.min(len);
+        len -= skipped;
+        if skipped < buffer_len {
+            rest.push((addr.wrapping_add(skipped), buffer_len - skipped));
+        }
+    }
+    rest
+}
+
+fn total_len(buffers: &[(u32, u32)]) -> u64 {
+    buffers.iter().map(|(_, len)| *len as u64).sum()
+}
+
+fn read_u16(memory: &dyn Memory, addr: u32) -> u16 {
+    u16::from_le_bytes([memory.byte(addr), memory.byte(addr.wrapping_add(1))])
+}
+
+fn read_u32(memory: &dyn Memory, addr: u32) -> u32 {
+    let bytes = memory.read_bytes(addr, 4);
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn write_u16(memory: &mut dyn Memory, addr: u32, value: u16) {
+    memory.write_bytes(addr, &value.to_le_bytes());
+}
+
+fn write_u32(memory: &mut dyn Memory, addr: u32, value: u32) {
+    memory.write_bytes(addr, &value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Device;
+    use crate::memory::{Memory, PagedMemory};
+    use crate::virtio::{
+        VirtioBlock, BLK_S_IOERR, BLK_S_OK, BLK_T_IN, BLK_T_OUT, CONFIG, DESC_F_NEXT, DESC_F_WRITE,
+        DEVICE_FEATURES, DEVICE_FEATURES_SEL, INTERRUPT_ACK, MAGIC, MAGIC_VALUE, QUEUE_DESC_LOW,
+        QUEUE_DEVICE_LOW, QUEUE_DRIVER_LOW, QUEUE_NOTIFY, QUEUE_NUM, QUEUE_READY,
+    };
+    use std::io::Cursor;
+
+    const DESC: u32 = 0x1000;
+    const DRIVER: u32 = 0x2000;
+    const DEVICE: u32 = 0x3000;
+    const HEADER: u32 = 0x4000;
+    const DATA: u32 = 0x5000;
+    const STATUS: u32 = 0x6000;
+
+    fn write_descriptor(memory: &mut PagedMemory, index: u32, addr: u32, len: u32, flags: u16) {
+        let desc = DESC + 16 * index;
+        memory.write_bytes(desc, &(addr as u64).to_le_bytes());
+        memory.write_bytes(desc + 8, &len.to_le_bytes());
+        memory.write_bytes(desc + 12, &flags.to_le_bytes());
+        memory.write_bytes(desc + 14, &(index as u16 + 1).to_le_bytes());
+    }
+
+    /// Makes a three descriptor request available and notifies the device
+    fn submit(
+        block: &mut VirtioBlock,
+        memory: &mut PagedMemory,
+        kind: u32,
+        sector: u64,
+        avail_idx: u16,
+    ) {
+        memory.write_bytes(HEADER, &kind.to_le_bytes());
+        memory.write_bytes(HEADER + 8, &sector.to_le_bytes());
+        let data_flags = if kind == BLK_T_IN { DESC_F_WRITE } else { 0 };
+        write_descriptor(memory, 0, HEADER, 16, DESC_F_NEXT);
+        write_descriptor(memory, 1, DATA, 512, DESC_F_NEXT | data_flags);
+        write_descriptor(memory, 2, STATUS, 1, DESC_F_WRITE);
+        notify(block, memory, avail_idx);
+    }
+
+    /// Makes the chain at descriptor 0 available and notifies the device
+    fn notify(block: &mut VirtioBlock, memory: &mut PagedMemory, avail_idx: u16) {
+        memory.write_bytes(DRIVER + 4 + 2 * (avail_idx as u32 % 8), &0u16.to_le_bytes());
+        memory.write_bytes(DRIVER + 2, &(avail_idx + 1).to_le_bytes());
+        block.write(QUEUE_NOTIFY, 4, 0);
+        block.tick(memory);
+    }
+
+    #[test]
+    fn test_registers() {
+        let disk = Cursor::new(vec![0; 4 * 512 + 100]);
+        let mut block = VirtioBlock::new(Box::new(disk)).unwrap();
+        assert_eq!(block.read(MAGIC_VALUE, 4), MAGIC);
+        // capacity in whole sectors
+        assert_eq!(block.read(CONFIG, 4), 4);
+        assert_eq!(block.read(CONFIG + 4, 4), 0);
+        block.write(DEVICE_FEATURES_SEL, 4, 1);
+        assert_eq!(block.read(DEVICE_FEATURES, 4), 1);
+    }
+
+    /// A block device over a disk of the given number of sectors with its queue set up
+    fn ready_block(sectors: usize) -> VirtioBlock {
+        let disk = Cursor::new(vec![0; sectors * 512]);
+        let mut block = VirtioBlock::new(Box::new(disk)).unwrap();
+        block.write(QUEUE_NUM, 4, 8);
+        block.write(QUEUE_DESC_LOW, 4, DESC);
+        block.write(QUEUE_DRIVER_LOW, 4, DRIVER);
+        block.write(QUEUE_DEVICE_LOW, 4, DEVICE);
+        block.write(QUEUE_READY, 4, 1);
+        block
+    }
+
+    #[test]
+    fn test_write_and_read_back() {
+        let mut block = ready_block(2);
+        let mut memory = PagedMemory::new();
+
+        memory.write_bytes(DATA, &[0xab; 512]);
+        submit(&mut block, &mut memory, BLK_T_OUT, 1, 0);
+        assert_eq!(memory.byte(STATUS), BLK_S_OK);
+        assert!(block.interrupt());
+        block.write(INTERRUPT_ACK, 4, 1);
+        assert!(!block.interrupt());
+
+        memory.write_bytes(DATA, &[0; 512]);
+        memory.write_bytes(STATUS, &[0xff]);
+        submit(&mut block, &mut memory, BLK_T_IN, 1, 1);
+        assert_eq!(memory.byte(STATUS), BLK_S_OK);
+        assert_eq!(memory.read_bytes(DATA, 512), vec![0xab; 512]);
+
+        // used ring: idx 2, second entry is head 0 with 513 bytes written
+        assert_eq!(memory.read_bytes(DEVICE + 2, 2), [2, 0]);
+        assert_eq!(memory.read_bytes(DEVICE + 12, 8), [0, 0, 0, 0, 1, 2, 0, 0]);
+
+        // reading past the end of the disk fails
+        submit(&mut block, &mut memory, BLK_T_IN, 2, 2);
+        assert_eq!(memory.byte(STATUS), 1);
+    }
+
+    #[test]
+    fn test_oversized_requests() {
+        let mut block = ready_block(2);
+        let mut memory = PagedMemory::new();
+
+        // a write whose data buffer is far larger than the disk
+        memory.write_bytes(HEADER, &BLK_T_OUT.to_le_bytes());
+        write_descriptor(&mut memory, 0, HEADER, 16, DESC_F_NEXT);
+        write_descriptor(&mut memory, 1, DATA, 0xffff_fff0, DESC_F_NEXT);
+        write_descriptor(&mut memory, 2, STATUS, 1, DESC_F_WRITE);
+        notify(&mut block, &mut memory, 0);
+        assert_eq!(memory.byte(STATUS), BLK_S_IOERR);
+
+        // a read whose buffer lengths overflow a u32 when summed
+        memory.write_bytes(HEADER, &BLK_T_IN.to_le_bytes());
+        write_descriptor(&mut memory, 1, DATA, u32::MAX, DESC_F_NEXT | DESC_F_WRITE);
+        write_descriptor(&mut memory, 2, STATUS, 2, DESC_F_WRITE);
+        memory.write_bytes(STATUS + 1, &[0xff]);
+        notify(&mut block, &mut memory, 1);
+        assert_eq!(memory.byte(STATUS + 1), BLK_S_IOERR);
+        // only the status byte was written
+        assert_eq!(memory.read_bytes(DEVICE + 12, 8), [0, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_split_and_chunked_requests() {
+        let mut block = ready_block(256);
+        let mut memory = PagedMemory::new();
+        let pattern: Vec<u8> = (0..0x18000).map(|i| (i % 251) as u8).collect();
+
+        // a header split in two and more data than one copy chunk, over two buffers
+        memory.write_bytes(HEADER, &BLK_T_OUT.to_le_bytes());
+        memory.write_bytes(HEADER + 8, &1u64.to_le_bytes());
+        memory.write_bytes(0x10_0000, &pattern[..0x11000]);
+        memory.write_bytes(0x20_0000, &pattern[0x11000..]);
+        write_descriptor(&mut memory, 0, HEADER, 8, DESC_F_NEXT);
+        write_descriptor(&mut memory, 1, HEADER + 8, 8, DESC_F_NEXT);
+        write_descriptor(&mut memory, 2, 0x10_0000, 0x11000, DESC_F_NEXT);
+        write_descriptor(&mut memory, 3, 0x20_0000, 0x7000, DESC_F_NEXT);
+        write_descriptor(&mut memory, 4, STATUS, 1, DESC_F_WRITE);
+        notify(&mut block, &mut memory, 0);
+        assert_eq!(memory.byte(STATUS), BLK_S_OK);
+
+        // read it back into differently sized buffers
+        memory.write_bytes(HEADER, &BLK_T_IN.to_le_bytes());
+        let flags = DESC_F_NEXT | DESC_F_WRITE;
+        write_descriptor(&mut memory, 2, 0x30_0000, 0x8000, flags);
+        write_descriptor(&mut memory, 3, 0x40_0000, 0x10000, flags);
+        notify(&mut block, &mut memory, 1);
+        assert_eq!(memory.byte(STATUS), BLK_S_OK);
+        let mut read = memory.read_bytes(0x30_0000, 0x8000);
+        read.extend(memory.read_bytes(0x40_0000, 0x10000));
+        assert_eq!(read, pattern);
+        // the data and the status byte
+        assert_eq!(memory.read_bytes(DEVICE + 16, 4), 0x18001u32.to_le_bytes());
+    }
+}
@@ -1,59 +1,178 @@
+use crate::bus::{device_offset, ranges_overlap, Bus, BusError, Device};
+use crate::clint::{Clint, TimerSource, CLINT_BASE, CLINT_SIZE};
+use crate::csr::{
+    CsrFile, Privilege, DELEGABLE_EXCEPTIONS, DELEGABLE_INTERRUPTS, MEI, MSI, MTI, SEI, STI,
+};
+use crate::decode_compressed_instruction::{decode_compressed_instruction, is_compressed};
 use crate::decode_instruction::decode_instruction;
-use crate::elf::{parse_elf, u32_le, ProgramInfo};
+use crate::elf::{parse_elf, parse_elf_bytes, u32_le, ElfError, ProgramInfo};
 use crate::execute_instruction::execute_instruction;
+use crate::memory::{Memory, PagedMemory, PAGE_SIZE};
+use crate::mmu::{AccessType, Tlb};
+use crate::plic::{Plic, PLIC_BASE, PLIC_SIZE, PLIC_SOURCES};
+use crate::pmp::{PMPADDR0, PMPCFG0};
+use crate::process::ProcessConfig;
+use crate::syscall::{LinuxSyscalls, Process, SyscallHandler};
+use crate::trap::Exception;
+use crate::uart::{Uart16550, UART_INTERRUPT, UART_SIZE};
+use crate::vfs::Filesystem;
+use crate::virtio::{VirtioBlock, VIRTIO_SIZE};
+use std::io::{Cursor, Read, Write};
 
-// TODO: consider using paged memory
-pub(crate) struct VM {
+/// Reason the vm stopped executing instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HaltReason {
+    /// The guest requested to exit, see `VM::exit_code`
+    Exit,
+    /// The guest raised an exception that has no handler, see `Environment`
+    Exception(Exception),
+    /// The instruction limit passed to `VM::run_with_limit` was reached
+    InstructionLimit,
+    /// The guest allocated more memory than allowed by `VM::set_memory_limit`
+    OutOfMemory,
+}
+
+/// What the guest is running on top of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Environment {
+    /// ecalls are handled by the host, see `VM::set_syscall_handler`, other exceptions trap to mtvec (or stvec)
+    /// if the guest installed a handler and halt the vm otherwise
+    #[default]
+    Emulated,
+    /// Every exception including ecalls traps to mtvec
+    BareMetal,
+    /// Supervisor mode ecalls are handled by the built in sbi firmware, see `VM::set_environment`,
+    /// other exceptions trap like on bare metal and halt the vm if no handler is installed
+    Sbi,
+}
+
+pub struct VM {
     pub(crate) registers: [u32; 32],
-    pub(crate) memory: Vec<u8>,
+    pub(crate) memory: Box<dyn Memory>,
     pub(crate) pc: u32,
     pub(crate) halted: bool,
     pub(crate) exit_code: u32,
+    pub(crate) halt_reason: Option<HaltReason>,
+    pub(crate) instret: u64,
+    pub(crate) memory_limit: Option<usize>,
+    pub(crate) trace: bool,
+    // address reserved by the last lr.w
+    pub(crate) reservation: Option<u32>,
+    pub(crate) csr: CsrFile,
+    pub(crate) privilege: Privilege,
+    pub(crate) tlb: Tlb,
+    pub(crate) clint: Clint,
+    pub(crate) plic: Plic,
+    pub(crate) bus: Bus,
+    pub(crate) environment: Environment,
+    // writes to this address report the exit code (riscv-tests htif)
+    pub(crate) tohost: Option<u32>,
+    // base address of the console uart
+    pub(crate) uart: Option<u32>,
+    // base address and plic source of every virtio device
+    pub(crate) virtio: Vec<(u32, u32)>,
+    // kernel command line and initrd range passed in the device tree
+    pub(crate) bootargs: Option<String>,
+    pub(crate) initrd: Option<(u32, u32)>,
+    // heap, mappings and open files of the emulated linux process
+    pub(crate) process: Process,
+    // receives ecalls in the emulated environment
+    pub(crate) syscall_handler: Box<dyn SyscallHandler>,
 
     blackhole: u32,
 }
 
 impl VM {
-    fn init() -> Self {
+    /// Creates a vm with zeroed registers and memory, starting execution at address 0
+    pub fn init() -> Self {
         Self {
             registers: [0; 32],
-            memory: vec![0; 1 << 32],
+            memory: Box::new(PagedMemory::new()),
             pc: 0,
             halted: false,
             exit_code: 0,
+            halt_reason: None,
+            instret: 0,
+            memory_limit: None,
+            trace: false,
+            reservation: None,
+            csr: CsrFile::new(),
+            privilege: Privilege::Machine,
+            tlb: Tlb::default(),
+            clint: Clint::default(),
+            plic: Plic::default(),
+            bus: Bus::default(),
+            environment: Environment::default(),
+            tohost: None,
+            uart: None,
+            virtio: Vec::new(),
+            bootargs: None,
+            initrd: None,
+            process: Process::default(),
+            syscall_handler: Box::new(LinuxSyscalls),
             blackhole: 0,
         }
     }
 
-    fn init_from_elf(path: String) -> Self {
-        let program = parse_elf(path);
+    /// Creates a vm from the elf file at path, with a stack holding the path as argv[0]
+    pub fn init_from_elf(path: String) -> Result<Self, ElfError> {
+        let config = ProcessConfig {
+            args: vec![path.clone()],
+            ..ProcessConfig::default()
+        };
+        Self::init_from_elf_with_config(path, &config)
+    }
 
-        let mut memory = vec![0; 1 << 32];
+    /// Creates a vm from the elf file at path, with the stack described by config
+    pub fn init_from_elf_with_config(
+        path: String,
+        config: &ProcessConfig,
+    ) -> Result<Self, ElfError> {
+        Ok(Self::init_from_program(parse_elf(path)?, config))
+    }
 
-        // load code
-        let code_start = program.code.0 as usize;
-        let code_end = code_start + program.code.1.len();
-        memory[code_start..code_end].copy_from_slice(&program.code.1);
+    /// Creates a vm from the contents of an elf file, with a stack holding no arguments
+    pub fn init_from_elf_bytes(bytes: &[u8]) -> Result<Self, ElfError> {
+        Ok(Self::init_from_program(
+            parse_elf_bytes(bytes)?,
+            &ProcessConfig::default(),
+        ))
+    }
 
-        // load data
-        let data_start = program.data.0 as usize;
-        let data_end = data_start + program.data.1.len();
-        memory[data_start..data_end].copy_from_slice(&program.data.1);
+    /// Creates a vm with a raw memory image loaded at base
+    pub fn init_from_image(base: u32, image: &[u8], entry_point: u32) -> Self {
+        let mut vm = Self::init();
+        vm.write_memory(base, image);
+        vm.pc = entry_point;
+        vm
+    }
 
-        Self {
-            registers: [0; 32],
-            memory,
-            pc: program.entry_point,
-            halted: false,
-            exit_code: 0,
-            blackhole: 0,
+    fn init_from_program(program: ProgramInfo, config: &ProcessConfig) -> Self {
+        let mut vm = Self::init();
+
+        // load every segment, fresh memory already zero fills the rest of memory_size
+        for segment in &program.segments {
+            vm.write_memory(segment.address, &segment.data);
         }
+
+        vm.tohost = program.tohost;
+        vm.process.set_program_end(program.end);
+        vm.process.entry_point = program.entry_point;
+        vm.process.program_headers = program.program_headers;
+        vm.process.program_header_count = program.program_header_count;
+        vm.start_process(config);
+        vm
     }
 
-    pub(crate) fn reg(&self, addr: u32) -> u32 {
+    pub fn reg(&self, addr: u32) -> u32 {
         self.registers[addr as usize]
     }
 
+    /// Sets the value of a register, writes to the zero register are ignored
+    pub fn set_reg(&mut self, addr: u32, value: u32) {
+        *self.reg_mut(addr) = value;
+    }
+
     pub(crate) fn reg_mut(&mut self, addr: u32) -> &mut u32 {
         if addr == 0 {
             &mut self.blackhole
@@ -62,69 +181,500 @@ impl VM {
         }
     }
 
-    pub(crate) fn mem(&self, addr: u32) -> u8 {
-        self.memory[addr as usize]
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+    }
+
+    pub fn mem(&self, addr: u32) -> u8 {
+        self.memory.byte(addr)
     }
 
     pub(crate) fn mem_mut(&mut self, addr: u32) -> &mut u8 {
-        &mut self.memory[addr as usize]
+        self.memory.byte_mut(addr)
     }
 
-    pub(crate) fn mem32(&self, addr: u32) -> [u8; 4] {
-        let addr = addr as usize;
+    pub fn mem32(&self, addr: u32) -> [u8; 4] {
         [
-            self.memory[addr],
-            self.memory[addr + 1],
-            self.memory[addr + 2],
-            self.memory[addr + 3],
+            self.mem(addr),
+            self.mem(addr.wrapping_add(1)),
+            self.mem(addr.wrapping_add(2)),
+            self.mem(addr.wrapping_add(3)),
         ]
     }
 
-    fn load_instruction(&self, pc: u32) -> [u8; 4] {
-        self.mem32(pc)
+    /// Reads len bytes of guest memory starting at addr
+    pub fn read_memory(&self, addr: u32, len: usize) -> Vec<u8> {
+        self.memory.read_bytes(addr, len)
+    }
+
+    /// Writes data into guest memory starting at addr
+    pub fn write_memory(&mut self, addr: u32, data: &[u8]) {
+        self.memory.write_bytes(addr, data);
+    }
+
+    /// Reads a control and status register, None if the csr is not implemented
+    pub fn read_csr(&self, addr: u32) -> Option<u32> {
+        self.csr.read(addr).ok()
+    }
+
+    /// Privilege level the hart is currently executing in
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    /// Loads size (1, 2 or 4) bytes from the virtual address addr, zero extended
+    pub(crate) fn load(&mut self, addr: u32, size: u32) -> Result<u32, Exception> {
+        self.load_with(addr, size, AccessType::Load)
+    }
+
+    /// Loads from addr with the permissions of the given access,
+    /// amos read with store permissions and fetches with execute permissions
+    pub(crate) fn load_with(
+        &mut self,
+        addr: u32,
+        size: u32,
+        access: AccessType,
+    ) -> Result<u32, Exception> {
+        // physical faults report the virtual address
+        if !crosses_page(addr, size) {
+            let paddr = self.translate(addr, access)?;
+            return self
+                .physical_load(paddr, size, access)
+                .map_err(|_| access.access_fault(addr));
+        }
+
+        // misaligned accesses that cross a page are translated a byte at a time
+        let mut value = 0;
+        for i in 0..size {
+            let vaddr = addr.wrapping_add(i);
+            let paddr = self.translate(vaddr, access)?;
+            let byte = self
+                .physical_load(paddr, 1, access)
+                .map_err(|_| access.access_fault(vaddr))?;
+            value |= byte << (8 * i);
+        }
+        Ok(value)
+    }
+
+    /// Stores the lower size (1, 2 or 4) bytes of value at the virtual address addr
+    pub(crate) fn store(&mut self, addr: u32, size: u32, value: u32) -> Result<(), Exception> {
+        let access = AccessType::Store;
+        if !crosses_page(addr, size) {
+            let paddr = self.translate(addr, access)?;
+            return self
+                .physical_store(paddr, size, value, access)
+                .map_err(|_| access.access_fault(addr));
+        }
+
+        // translate and check every byte before writing so a fault leaves memory untouched
+        let mut paddrs = [0; 4];
+        for i in 0..size {
+            let vaddr = addr.wrapping_add(i);
+            let paddr = self.translate(vaddr, access)?;
+            if !self.pmp_allows(paddr, 1, access) {
+                return Err(access.access_fault(vaddr));
+            }
+            paddrs[i as usize] = paddr;
+        }
+        for i in 0..size {
+            let byte = (value >> (8 * i)) & 0xff;
+            self.physical_store(paddrs[i as usize], 1, byte, access)?;
+        }
+        Ok(())
+    }
+
+    fn pmp_allows(&self, addr: u32, size: u32, access: AccessType) -> bool {
+        let privilege = self.effective_privilege(access);
+        self.csr.pmp.check(addr, size, access, privilege)
+    }
+
+    /// Loads size bytes from physical memory
+    pub(crate) fn physical_load(
+        &mut self,
+        addr: u32,
+        size: u32,
+        access: AccessType,
+    ) -> Result<u32, Exception> {
+        if !self.pmp_allows(addr, size, access) {
+            return Err(access.access_fault(addr));
+        }
+
+        if let Some((device, offset)) = self.device_at(addr) {
+            return Ok(device.read(offset, size));
+        }
+
+        let data = self.mem32(addr);
+        Ok(u32_le(&data[..size as usize]))
+    }
+
+    /// Stores the lower size bytes of value to physical memory
+    pub(crate) fn physical_store(
+        &mut self,
+        addr: u32,
+        size: u32,
+        value: u32,
+        access: AccessType,
+    ) -> Result<(), Exception> {
+        if !self.pmp_allows(addr, size, access) {
+            return Err(access.access_fault(addr));
+        }
+
+        if let Some((device, offset)) = self.device_at(addr) {
+            device.write(offset, size, value);
+            return Ok(());
+        }
+
+        if self.tohost == Some(addr) && size == 4 && value & 1 == 1 {
+            self.halt(HaltReason::Exit, value >> 1);
+        }
+
+        for (i, byte) in value.to_le_bytes()[..size as usize].iter().enumerate() {
+            *self.mem_mut(addr.wrapping_add(i as u32)) = *byte;
+        }
+        Ok(())
+    }
+
+    /// The device mapped at the physical address addr and the offset of addr into it
+    fn device_at(&mut self, addr: u32) -> Option<(&mut dyn Device, u32)> {
+        if let Some(offset) = device_offset(addr, CLINT_BASE, CLINT_SIZE) {
+            return Some((&mut self.clint, offset));
+        }
+        if let Some(offset) = device_offset(addr, PLIC_BASE, PLIC_SIZE) {
+            return Some((&mut self.plic, offset));
+        }
+        self.bus.device_at(addr)
+    }
+
+    /// Maps a device at [base, base + size) of the physical address space,
+    /// its interrupt line is connected to the given plic source
+    pub fn add_device(
+        &mut self,
+        base: u32,
+        size: u32,
+        interrupt: Option<u32>,
+        device: Box<dyn Device>,
+    ) -> Result<(), BusError> {
+        if size == 0 || base.checked_add(size - 1).is_none() {
+            return Err(BusError::InvalidRange);
+        }
+        if let Some(source) = interrupt {
+            if source == 0 || source >= PLIC_SOURCES {
+                return Err(BusError::InvalidInterrupt(source));
+            }
+        }
+        if ranges_overlap(base, size, CLINT_BASE, CLINT_SIZE)
+            || ranges_overlap(base, size, PLIC_BASE, PLIC_SIZE)
+            || self.bus.overlaps(base, size)
+        {
+            return Err(BusError::Overlap);
+        }
+
+        self.bus.add(base, size, interrupt, device);
+        Ok(())
+    }
+
+    /// Maps a 16550 uart at base, wired to plic source `UART_INTERRUPT`
+    pub fn add_uart(&mut self, base: u32, uart: Uart16550) -> Result<(), BusError> {
+        self.add_device(base, UART_SIZE, Some(UART_INTERRUPT), Box::new(uart))?;
+        self.uart = Some(base);
+        Ok(())
+    }
+
+    /// Maps a virtio block device at base, wired to the given plic source
+    pub fn add_virtio_block(
+        &mut self,
+        base: u32,
+        interrupt: u32,
+        block: VirtioBlock,
+    ) -> Result<(), BusError> {
+        self.add_device(base, VIRTIO_SIZE, Some(interrupt), Box::new(block))?;
+        self.virtio.push((base, interrupt));
+        Ok(())
+    }
+
+    /// Selects what drives the clint's mtime, defaults to `TimerSource::Instructions`
+    pub fn set_timer_source(&mut self, source: TimerSource) {
+        self.clint.set_source(source);
+    }
+
+    /// Raises or lowers an external interrupt line of the plic
+    pub fn set_interrupt_level(&mut self, source: u32, high: bool) {
+        self.plic.set_level(source, high);
+    }
+
+    /// Selects how ecalls and exceptions are handled, defaults to `Environment::Emulated`
+    /// switching to `Environment::Sbi` sets the hart up like a firmware handing over to a kernel:
+    /// supervisor mode, all of memory accessible and traps and interrupts delegated
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = environment;
+        if environment == Environment::Sbi {
+            self.privilege = Privilege::Supervisor;
+            // napot entry covering the whole address space
+            self.csr.pmp.write(PMPADDR0, u32::MAX);
+            self.csr.pmp.write(PMPCFG0, 0x1f);
+            // supervisor ecalls never trap, the firmware handles them
+            self.csr.medeleg = DELEGABLE_EXCEPTIONS & !(1 << 9);
+            self.csr.mideleg = DELEGABLE_INTERRUPTS;
+            self.csr.mcounteren = 0b111;
+        }
+    }
+
+    /// Limits the amount of guest memory that can be allocated, None removes the limit
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.memory_limit = bytes;
+    }
+
+    /// Replaces the handler ecalls are passed to in `Environment::Emulated`,
+    /// `LinuxSyscalls` by default
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler>) {
+        self.syscall_handler = handler;
+    }
+
+    /// Sets where the file syscalls resolve guest paths, an empty in memory tree by default
+    pub fn set_filesystem(&mut self, filesystem: Filesystem) {
+        self.process.filesystem = filesystem;
+    }
+
+    /// Sets what the guest reads from stdin (file descriptor 0), the host's stdin by default
+    pub fn set_stdin(&mut self, input: Box<dyn Read>) {
+        self.process.stdin = input;
+    }
+
+    /// Feeds the bytes to the guest's stdin, reads return end of file once they are consumed
+    pub fn set_stdin_bytes(&mut self, input: &[u8]) {
+        self.set_stdin(Box::new(Cursor::new(input.to_vec())));
+    }
+
+    /// Sets where the guest's writes to stdout (file descriptor 1) go, the host's stdout by
+    /// default, the exact bytes are written so output can be captured with a `CaptureBuffer`
+    pub fn set_stdout(&mut self, output: Box<dyn Write>) {
+        self.process.stdout = output;
+    }
+
+    /// Sets where the guest's writes to stderr (file descriptor 2) go, the host's stderr by default
+    pub fn set_stderr(&mut self, output: Box<dyn Write>) {
+        self.process.stderr = output;
+    }
+
+    /// Prints every executed instruction to stderr when enabled
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Number of instructions executed so far
+    pub fn instructions_retired(&self) -> u64 {
+        self.instret
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
     }
 
-    fn run(&mut self) {
+    /// Returns why the vm halted, None if it is still running
+    pub fn halt_reason(&self) -> Option<&HaltReason> {
+        self.halt_reason.as_ref()
+    }
+
+    pub fn exit_code(&self) -> u32 {
+        self.exit_code
+    }
+
+    pub(crate) fn halt(&mut self, reason: HaltReason, exit_code: u32) {
+        self.halted = true;
+        self.halt_reason = Some(reason);
+        self.exit_code = exit_code;
+    }
+
+    /// Fetches the instruction at pc, compressed instructions only occupy the lower 16 bits
+    /// the two halves are fetched separately as a 32 bit instruction can cross a page
+    fn fetch_instruction(&mut self, pc: u32) -> Result<u32, Exception> {
+        let low = self.load_with(pc, 2, AccessType::Fetch)?;
+        if is_compressed(low) {
+            return Ok(low);
+        }
+        let high = self.load_with(pc.wrapping_add(2), 2, AccessType::Fetch)?;
+        Ok(low | (high << 16))
+    }
+
+    /// Fetches, decodes and executes a single instruction
+    pub fn step(&mut self) {
+        if self.halted {
+            return;
+        }
+
+        // interrupts are taken between instructions
+        self.update_interrupts();
+        if let Some(interrupt) = self.pending_interrupt() {
+            self.interrupt(interrupt);
+            return;
+        }
+
+        // fetch instruction
+        let instruction = match self.fetch_instruction(self.pc) {
+            Ok(instruction) => instruction,
+            Err(exception) => {
+                self.tick();
+                self.handle_exception(exception);
+                return;
+            }
+        };
+
+        // decode instruction
+        let decoded_instruction = if is_compressed(instruction) {
+            decode_compressed_instruction(instruction as u16)
+        } else {
+            decode_instruction(instruction)
+        };
+        let result = decoded_instruction
+            .map_err(|_| Exception::IllegalInstruction(instruction))
+            .and_then(|decoded_instruction| {
+                if self.trace {
+                    eprintln!(
+                        "{:08x}: {:08x} {:?}",
+                        self.pc, instruction, decoded_instruction.opcode
+                    );
+                }
+
+                // execute instruction
+                execute_instruction(self, decoded_instruction)
+            });
+
+        self.tick();
+        match result {
+            Ok(()) => self.csr.minstret = self.csr.minstret.wrapping_add(1),
+            // illegal instructions report the faulting instruction bits
+            Err(Exception::IllegalInstruction(_)) => {
+                self.handle_exception(Exception::IllegalInstruction(instruction))
+            }
+            Err(exception) => self.handle_exception(exception),
+        }
+
+        if let Some(limit) = self.memory_limit {
+            if self.memory.allocated_bytes() > limit {
+                self.halt(HaltReason::OutOfMemory, 1);
+            }
+        }
+    }
+
+    /// Advances the counters and timers after an instruction
+    fn tick(&mut self) {
+        self.instret += 1;
+        self.csr.mcycle = self.csr.mcycle.wrapping_add(1);
+        self.clint.tick();
+        self.bus.tick(self.memory.as_mut(), &mut self.plic);
+    }
+
+    /// Reflects the interrupt sources in mip
+    fn update_interrupts(&mut self) {
+        self.csr.time = self.clint.mtime();
+
+        let mut mip = 0;
+        if self.clint.software_interrupt() {
+            mip |= MSI;
+        }
+        // the sbi firmware forwards the timer to supervisor mode
+        if self.clint.timer_interrupt() {
+            mip |= match self.environment {
+                Environment::Sbi => STI,
+                _ => MTI,
+            };
+        }
+        if self.plic.interrupt(0) {
+            mip |= MEI;
+        }
+        if self.plic.interrupt(1) {
+            mip |= SEI;
+        }
+        self.csr.mip_external = mip;
+    }
+
+    /// Traps to the guest handler, or halts if the environment has nowhere to deliver it
+    fn handle_exception(&mut self, exception: Exception) {
+        let vector = match self.trap_target(exception) {
+            Privilege::Supervisor => self.csr.stvec,
+            _ => self.csr.mtvec,
+        };
+        if self.environment != Environment::BareMetal && vector & !0b11 == 0 {
+            if self.trace {
+                eprintln!("{:08x}: unhandled exception {:?}", self.pc, exception);
+            }
+            self.halt(HaltReason::Exception(exception), 1);
+            return;
+        }
+        self.trap(exception);
+    }
+
+    /// Executes instructions until the vm halts
+    pub fn run(&mut self) {
         while !self.halted {
-            // fetch instruction
-            let instruction = self.load_instruction(self.pc);
-
-            // decode instruction
-            let decoded_instruction = decode_instruction(u32_le(&instruction));
-
-            if decoded_instruction.is_err() {
-                eprintln!("pc: {:x}", self.pc);
-                eprintln!(
-                    "halting due to unsupported instruction: {:b}",
-                    u32_le(&instruction)
-                );
-                self.halted = true;
-                self.exit_code = 1;
+            self.step();
+        }
+    }
+
+    /// Executes at most max_instructions instructions,
+    /// halts with `HaltReason::InstructionLimit` if the guest is still running after that
+    pub fn run_with_limit(&mut self, max_instructions: u64) {
+        let limit = self.instret.saturating_add(max_instructions);
+        while !self.halted {
+            if self.instret >= limit {
+                self.halt(HaltReason::InstructionLimit, 1);
                 break;
             }
-
-            // execute instruction
-            execute_instruction(self, decoded_instruction.unwrap());
-            // println!("{:?}", self.registers);
+            self.step();
         }
     }
 }
 
+/// Returns true if the access touches more than one page
+fn crosses_page(addr: u32, size: u32) -> bool {
+    (addr as usize % PAGE_SIZE) + size as usize > PAGE_SIZE
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use crate::bus::{BusError, Device};
+    use crate::capture::CaptureBuffer;
+    use crate::clint::CLINT_BASE;
+    use crate::csr::{Privilege, MEI, MSTATUS_MIE};
     use crate::decode_instruction::{
         decode_instruction, DecodedInstruction, InstructionType, Opcode, Register,
     };
-    use crate::elf::u32_le;
     use crate::execute_instruction::execute_instruction;
-    use crate::vm::VM;
+    use crate::memory::PAGE_SIZE;
+    use crate::plic::PLIC_BASE;
+    use crate::trap::Exception;
+    use crate::uart::{Uart16550, UART_BASE};
+    use crate::vm::{Environment, HaltReason, VM};
     use std::fs;
 
+    /// Loads the instructions at address 0 and starts executing there
+    fn vm_with_program(words: &[u32]) -> VM {
+        let program: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        VM::init_from_image(0, &program, 0)
+    }
+
+    /// Runs an ecall at pc with a7 and the arguments from a0 onwards set, returns a0
+    pub(crate) fn ecall(vm: &mut VM, a7: u32, args: &[u32]) -> u32 {
+        vm.write_memory(vm.pc(), &0x00000073_u32.to_le_bytes());
+        vm.set_reg(Register::A7.into(), a7);
+        for (i, arg) in args.iter().enumerate() {
+            vm.set_reg(Register::A0 as u32 + i as u32, *arg);
+        }
+        vm.step();
+        vm.reg(Register::A0.into())
+    }
+
     #[test]
     fn test_rv32ui() {
         let _ = fs::read_dir("e2e-tests")
             .expect("Failed to read directory")
             .filter_map(|entry| entry.ok())
+            // skips the generator's source directory
+            .filter(|entry| entry.path().is_file())
             .map(|entry| run_test_elf(entry.path().to_str().unwrap().to_string()))
             .collect::<Vec<_>>();
     }
@@ -132,12 +682,481 @@ mod tests {
     fn run_test_elf(path: String) {
         println!("running test: {}", path);
 
-        let mut vm = VM::init_from_elf(path);
+        // emulated: the final ecall exits through the host
+        // bare metal: the ecall traps to the test's handler which reports through tohost
+        for environment in [Environment::Emulated, Environment::BareMetal] {
+            let mut vm = VM::init_from_elf(path.clone()).unwrap();
+            vm.set_environment(environment);
+            vm.run_with_limit(1_000_000);
+
+            println!("exit-code: {}", vm.exit_code);
+            assert!(vm.halted);
+            assert_eq!(vm.halt_reason(), Some(&HaltReason::Exit));
+            assert_eq!(vm.exit_code, 0);
+        }
+    }
+
+    #[test]
+    fn test_init_from_image() {
+        // addi a0 zero 7
+        // addi a7 zero 93
+        // ecall
+        let program: Vec<u8> = [0x00700513_u32, 0x05d00893, 0x00000073]
+            .into_iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+
+        let mut vm = VM::init_from_image(0x1000, &program, 0x1000);
+        assert_eq!(vm.read_memory(0x1000, program.len()), program);
+
+        vm.step();
+        assert_eq!(vm.pc(), 0x1004);
+        assert_eq!(vm.reg(Register::A0.into()), 7);
+
         vm.run();
+        assert!(vm.halted());
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::Exit));
+        assert_eq!(vm.exit_code(), 7);
+    }
 
-        println!("exit-code: {}", vm.exit_code);
-        assert!(vm.halted);
-        assert_eq!(vm.exit_code, 0);
+    #[test]
+    fn test_unsupported_instruction_halts() {
+        let mut vm = vm_with_program(&[0xffffffff]);
+        vm.set_reg(Register::Zero.into(), 5);
+        assert_eq!(vm.reg(Register::Zero.into()), 0);
+
+        vm.run();
+        assert_eq!(
+            vm.halt_reason(),
+            Some(&HaltReason::Exception(Exception::IllegalInstruction(
+                0xffffffff
+            )))
+        );
+        assert_eq!(vm.exit_code(), 1);
+    }
+
+    #[test]
+    fn test_run_with_limit() {
+        // jal zero 0 (infinite loop)
+        let mut vm = vm_with_program(&[0x0000006f]);
+        vm.run_with_limit(10);
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::InstructionLimit));
+        assert_eq!(vm.instructions_retired(), 10);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        // sw zero 0(sp)
+        // addi sp sp 2047
+        // jal zero -8
+        let mut vm = vm_with_program(&[0x00012023, 0x7ff10113, 0xff9ff06f]);
+        vm.set_reg(Register::SP.into(), 0x1000);
+        vm.set_memory_limit(Some(4 * PAGE_SIZE));
+        vm.run_with_limit(10_000);
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::OutOfMemory));
+        assert_eq!(vm.memory.allocated_bytes(), 5 * PAGE_SIZE);
+    }
+
+    /// Executes an instruction of the form `op a2, a0, a1` and returns a2
+    fn execute_r_type(instruction: u32, a0: u32, a1: u32) -> u32 {
+        let mut vm = VM::init();
+        vm.set_reg(Register::A0.into(), a0);
+        vm.set_reg(Register::A1.into(), a1);
+        execute_instruction(&mut vm, decode_instruction(instruction).unwrap()).unwrap();
+        vm.reg(Register::A2.into())
+    }
+
+    #[test]
+    fn test_multiply_extension() {
+        const MUL: u32 = 0x02b50633;
+        const MULH: u32 = 0x02b51633;
+        const MULHSU: u32 = 0x02b52633;
+        const MULHU: u32 = 0x02b53633;
+        const DIV: u32 = 0x02b54633;
+        const DIVU: u32 = 0x02b55633;
+        const REM: u32 = 0x02b56633;
+        const REMU: u32 = 0x02b57633;
+
+        let neg = |v: i32| v as u32;
+        let cases = [
+            (MUL, 7, 3, 21),
+            (MUL, neg(-7), 3, neg(-21)),
+            (MUL, 0x80000000, neg(-1), 0x80000000),
+            (MULH, neg(-1), neg(-1), 0),
+            (MULH, 0x80000000, 0x80000000, 0x40000000),
+            (MULH, neg(-2), 3, neg(-1)),
+            (MULHSU, neg(-1), neg(-1), neg(-1)),
+            (MULHSU, 0x80000000, 0xffffffff, 0x80000000),
+            (MULHU, neg(-1), neg(-1), 0xfffffffe),
+            (MULHU, 0x80000000, 2, 1),
+            (DIV, 20, 6, 3),
+            (DIV, neg(-20), 6, neg(-3)),
+            (DIV, 20, 0, neg(-1)),
+            (DIV, 0x80000000, neg(-1), 0x80000000),
+            (DIVU, neg(-20), 6, 715827879),
+            (DIVU, 20, 0, u32::MAX),
+            (REM, neg(-20), 6, neg(-2)),
+            (REM, 20, neg(-6), 2),
+            (REM, 20, 0, 20),
+            (REM, 0x80000000, neg(-1), 0),
+            (REMU, neg(-20), 6, 2),
+            (REMU, 20, 0, 20),
+        ];
+
+        for (instruction, a0, a1, expected) in cases {
+            assert_eq!(
+                execute_r_type(instruction, a0, a1),
+                expected,
+                "instruction: {:08x} a0: {:08x} a1: {:08x}",
+                instruction,
+                a0,
+                a1
+            );
+        }
+    }
+
+    #[test]
+    fn test_atomic_extension() {
+        const LR_W: u32 = 0x1005262f;
+        const SC_W: u32 = 0x18b5262f;
+        const AMOADD_W: u32 = 0x00b5262f;
+        const AMOMIN_W: u32 = 0x80b5262f;
+        const AMOMAXU_W: u32 = 0xe0b5262f;
+        // amoadd.w a1, a1, (a0)
+        const AMOADD_W_SAME_REG: u32 = 0x00b525af;
+
+        let mut vm = VM::init();
+        let execute = |vm: &mut VM, instruction: u32| {
+            execute_instruction(vm, decode_instruction(instruction).unwrap()).unwrap()
+        };
+        let word = |vm: &VM| u32::from_le_bytes(vm.mem32(0x100));
+
+        vm.set_reg(Register::A0.into(), 0x100);
+        vm.write_memory(0x100, &5_u32.to_le_bytes());
+
+        // sc without a reservation fails
+        vm.set_reg(Register::A1.into(), 9);
+        execute(&mut vm, SC_W);
+        assert_eq!(vm.reg(Register::A2.into()), 1);
+        assert_eq!(word(&vm), 5);
+
+        // lr then sc succeeds, a second sc fails
+        execute(&mut vm, LR_W);
+        assert_eq!(vm.reg(Register::A2.into()), 5);
+        execute(&mut vm, SC_W);
+        assert_eq!(vm.reg(Register::A2.into()), 0);
+        assert_eq!(word(&vm), 9);
+        execute(&mut vm, SC_W);
+        assert_eq!(vm.reg(Register::A2.into()), 1);
+
+        // amo returns the old value and stores the result
+        vm.set_reg(Register::A1.into(), 3);
+        execute(&mut vm, AMOADD_W);
+        assert_eq!(vm.reg(Register::A2.into()), 9);
+        assert_eq!(word(&vm), 12);
+
+        vm.set_reg(Register::A1.into(), -4_i32 as u32);
+        execute(&mut vm, AMOMIN_W);
+        assert_eq!(word(&vm), -4_i32 as u32);
+        vm.set_reg(Register::A1.into(), 7);
+        execute(&mut vm, AMOMAXU_W);
+        assert_eq!(word(&vm), -4_i32 as u32);
+
+        // rd == rs2 uses the original register value as the operand
+        vm.write_memory(0x100, &10_u32.to_le_bytes());
+        vm.set_reg(Register::A1.into(), 1);
+        execute(&mut vm, AMOADD_W_SAME_REG);
+        assert_eq!(vm.reg(Register::A1.into()), 10);
+        assert_eq!(word(&vm), 11);
+    }
+
+    #[test]
+    fn test_compressed_instructions() {
+        // 0x00: c.li a0, 5
+        // 0x02: addi a1, zero, 3
+        // 0x06: c.jal 8 (-> 0x0e)
+        // 0x08: c.add a0, a1
+        // 0x0a: c.j 10 (-> 0x14)
+        // 0x0c: c.nop
+        // 0x0e: c.mv a2, ra
+        // 0x10: c.jr ra
+        // 0x12: c.nop
+        // 0x14: c.li a7, 29
+        // 0x16: c.addi a7, 64
+        // 0x1a: ecall
+        let program: Vec<u8> = vec![
+            0x15, 0x45, 0x93, 0x05, 0x30, 0x00, 0x21, 0x20, 0x2e, 0x95, 0x29, 0xa0, 0x01, 0x00,
+            0x06, 0x86, 0x82, 0x80, 0x01, 0x00, 0xf5, 0x48, 0x93, 0x88, 0x08, 0x04, 0x73, 0x00,
+            0x00, 0x00,
+        ];
+        let mut vm = VM::init_from_image(0, &program, 0);
+        vm.run_with_limit(100);
+
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::Exit));
+        assert_eq!(vm.exit_code(), 8);
+        // c.jal links to the instruction after it, which is only 2 bytes further
+        assert_eq!(vm.reg(Register::RA.into()), 0x08);
+        assert_eq!(vm.reg(Register::A2.into()), 0x08);
+        assert_eq!(vm.pc(), 0x1e);
+    }
+
+    #[test]
+    fn test_csr_instructions() {
+        let mut vm = VM::init();
+        let execute = |vm: &mut VM, instruction: u32| {
+            execute_instruction(vm, decode_instruction(instruction).unwrap()).unwrap()
+        };
+
+        // csrrw a0, mscratch, a1
+        vm.set_reg(Register::A1.into(), 0b1010);
+        execute(&mut vm, 0x34059573);
+        assert_eq!(vm.reg(Register::A0.into()), 0);
+        assert_eq!(vm.read_csr(0x340), Some(0b1010));
+
+        // csrrsi a0, mscratch, 5
+        execute(&mut vm, 0x3402e573);
+        assert_eq!(vm.reg(Register::A0.into()), 0b1010);
+        assert_eq!(vm.read_csr(0x340), Some(0b1111));
+
+        // csrrc a0, mscratch, a1
+        execute(&mut vm, 0x3405b573);
+        assert_eq!(vm.reg(Register::A0.into()), 0b1111);
+        assert_eq!(vm.read_csr(0x340), Some(0b0101));
+
+        // csrr a0, mhartid (csrrs with rs1 = x0 does not write the read only csr)
+        execute(&mut vm, 0xf1402573);
+        assert_eq!(vm.reg(Register::A0.into()), 0);
+        assert_eq!(vm.pc(), 16);
+    }
+
+    #[test]
+    fn test_illegal_csr_access_traps() {
+        // 0x00: csrw mhartid, a1 (read only)
+        // 0x04: csrr a0, 0x744 (not implemented)
+        let mut vm = vm_with_program(&[0xf1459073, 0x74402573]);
+        vm.csr.mtvec = 0x8000_0000;
+
+        vm.step();
+        assert_eq!(vm.pc(), 0x8000_0000);
+        assert_eq!(vm.read_csr(0x341), Some(0));
+        assert_eq!(vm.read_csr(0x342), Some(2));
+        assert_eq!(vm.read_csr(0x343), Some(0xf1459073));
+
+        vm.pc = 0x04;
+        vm.set_reg(Register::A0.into(), 7);
+        vm.step();
+        assert_eq!(vm.pc(), 0x8000_0000);
+        assert_eq!(vm.read_csr(0x341), Some(0x04));
+        assert_eq!(vm.reg(Register::A0.into()), 7);
+    }
+
+    #[test]
+    fn test_exceptions_trap_to_mtvec() {
+        // 0x00: lr.w a2, (a0) (misaligned)
+        // 0x04: ebreak
+        // 0x08: ecall
+        // 0x0c: mret
+        // 0x10: uret (not a valid system instruction)
+        let mut vm = vm_with_program(&[0x1005262f, 0x00100073, 0x00000073, 0x30200073, 0x00200073]);
+        vm.set_environment(Environment::BareMetal);
+        vm.csr.mtvec = 0x0c;
+        vm.set_reg(Register::A0.into(), 0x102);
+
+        let trap = |vm: &mut VM, pc: u32| {
+            vm.pc = pc;
+            vm.step();
+            assert_eq!(vm.pc(), 0x0c);
+            assert_eq!(vm.read_csr(0x341), Some(pc));
+            (vm.read_csr(0x342).unwrap(), vm.read_csr(0x343).unwrap())
+        };
+        assert_eq!(trap(&mut vm, 0x00), (4, 0x102));
+        assert_eq!(trap(&mut vm, 0x04), (3, 0x04));
+        assert_eq!(trap(&mut vm, 0x10), (2, 0x00200073));
+        assert_eq!(trap(&mut vm, 0x08), (11, 0));
+
+        // mret resumes at mepc
+        vm.step();
+        assert_eq!(vm.pc(), 0x08);
+        assert!(!vm.halted());
+    }
+
+    #[test]
+    fn test_user_mode() {
+        // 0x00: li t0, 0x40
+        // 0x04: csrw mtvec, t0
+        // 0x08: li t0, 0x1800
+        // 0x10: csrc mstatus, t0 (mpp = user)
+        // 0x14: li t0, 0x24
+        // 0x18: csrw mepc, t0
+        // 0x1c: mret
+        // 0x20: nop
+        // 0x24: csrr a0, mstatus
+        // 0x28: ecall
+        let mut vm = vm_with_program(&[
+            0x04000293, 0x30529073, 0x000022b7, 0x80028293, 0x3002b073, 0x02400293, 0x34129073,
+            0x30200073, 0x00000013, 0x30002573, 0x00000073,
+        ]);
+        vm.set_environment(Environment::BareMetal);
+        // pmpaddr0 = napot over the whole address space, pmpcfg0 = napot rwx
+        vm.csr.write(0x3b0, u32::MAX).unwrap();
+        vm.csr.write(0x3a0, 0x1f).unwrap();
+
+        for _ in 0..8 {
+            vm.step();
+        }
+        assert_eq!(vm.pc(), 0x24);
+        assert_eq!(vm.privilege(), Privilege::User);
+
+        // machine csrs are not accessible from user mode
+        vm.step();
+        assert_eq!(vm.pc(), 0x40);
+        assert_eq!(vm.privilege(), Privilege::Machine);
+        assert_eq!(vm.read_csr(0x342), Some(2));
+        assert_eq!(vm.read_csr(0x341), Some(0x24));
+
+        vm.privilege = Privilege::User;
+        vm.pc = 0x28;
+        vm.step();
+        assert_eq!(vm.read_csr(0x342), Some(8));
+        assert_eq!(vm.read_csr(0x300).unwrap() & 0x1800, 0);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        // 0x00: la t0, handler
+        // 0x08: csrw mtvec, t0
+        // 0x0c: lui t0, 0x2004 (mtimecmp)
+        // 0x10: li t1, 20
+        // 0x14: sw t1, 0(t0)
+        // 0x18: sw zero, 4(t0)
+        // 0x1c: li t0, 0x80
+        // 0x20: csrs mie, t0 (mtie)
+        // 0x24: csrsi mstatus, 8 (mie)
+        // 0x28: loop: addi a1, a1, 1
+        // 0x2c: j loop
+        // 0x30: handler: csrr a0, mcause
+        // 0x34: li a7, 93
+        // 0x38: ecall
+        let mut vm = vm_with_program(&[
+            0x00000297, 0x03028293, 0x30529073, 0x020042b7, 0x01400313, 0x0062a023, 0x0002a223,
+            0x08000293, 0x3042a073, 0x30046073, 0x00158593, 0xffdff06f, 0x34202573, 0x05d00893,
+            0x00000073,
+        ]);
+        vm.run_with_limit(1000);
+
+        assert_eq!(vm.halt_reason(), Some(&HaltReason::Exit));
+        assert_eq!(vm.exit_code(), 0x8000_0007);
+        // mtime reached mtimecmp after 10 instructions of setup and 10 of the loop
+        assert_eq!(vm.reg(Register::A1.into()), 5);
+        assert_eq!(vm.read_csr(0x341), Some(0x28));
+        assert_eq!(vm.read_csr(0xc01), Some(22));
+    }
+
+    #[test]
+    fn test_external_interrupt() {
+        // 0x00: lw a0, 4(t0) (claim)
+        // 0x04: sw a0, 4(t0) (complete)
+        let mut vm = vm_with_program(&[0x0042a503, 0x00a2a223]);
+        vm.pc = 0x100;
+        vm.csr.mtvec = 0;
+        vm.csr.mie = MEI;
+        vm.csr.mstatus |= MSTATUS_MIE;
+        vm.set_reg(Register::T0.into(), PLIC_BASE + 0x20_0000);
+        vm.plic.write(4 * 5, 4, 1);
+        vm.plic.write(0x2000, 4, 1 << 5);
+
+        vm.set_interrupt_level(5, true);
+        vm.step();
+        assert_eq!(vm.pc(), 0);
+        assert_eq!(vm.read_csr(0x342), Some(0x8000_000b));
+        assert_eq!(vm.read_csr(0x344), Some(MEI));
+
+        vm.step();
+        assert_eq!(vm.reg(Register::A0.into()), 5);
+        vm.set_interrupt_level(5, false);
+        vm.step();
+        vm.update_interrupts();
+        assert_eq!(vm.read_csr(0x344), Some(0));
+    }
+
+    /// Remembers the last write, reads return it
+    #[derive(Default)]
+    struct Latch {
+        value: u32,
+    }
+
+    impl Device for Latch {
+        fn read(&mut self, offset: u32, _size: u32) -> u32 {
+            self.value + offset
+        }
+
+        fn write(&mut self, _offset: u32, _size: u32, value: u32) {
+            self.value = value;
+        }
+    }
+
+    #[test]
+    fn test_add_device() {
+        // 0x00: sw a1, 0(a0)
+        // 0x04: lw a2, 8(a0)
+        let mut vm = vm_with_program(&[0x00b52023, 0x00852603]);
+        vm.add_device(0x1000_0000, 0x100, Some(1), Box::new(Latch::default()))
+            .unwrap();
+        vm.set_reg(Register::A0.into(), 0x1000_0000);
+        vm.set_reg(Register::A1.into(), 40);
+
+        vm.step();
+        vm.step();
+        assert_eq!(vm.reg(Register::A2.into()), 48);
+        // the device claimed the range, nothing reached ram
+        assert_eq!(vm.memory.allocated_bytes(), PAGE_SIZE);
+
+        let latch = || Box::new(Latch::default());
+        assert_eq!(
+            vm.add_device(0x1000_00f0, 0x100, None, latch()),
+            Err(BusError::Overlap)
+        );
+        assert_eq!(
+            vm.add_device(CLINT_BASE, 4, None, latch()),
+            Err(BusError::Overlap)
+        );
+        assert_eq!(
+            vm.add_device(0xffff_ff00, 0x200, None, latch()),
+            Err(BusError::InvalidRange)
+        );
+        assert_eq!(
+            vm.add_device(0x2000_0000, 0x100, Some(0), latch()),
+            Err(BusError::InvalidInterrupt(0))
+        );
+    }
+
+    #[test]
+    fn test_uart_console() {
+        // 0x00: sb a1, 0(a0)
+        // 0x04: lbu a2, 5(a0)
+        let mut vm = vm_with_program(&[0x00b50023, 0x00554603]);
+        let output = CaptureBuffer::new();
+        vm.add_uart(UART_BASE, Uart16550::new(Box::new(output.clone())))
+            .unwrap();
+        vm.set_reg(Register::A0.into(), UART_BASE);
+        vm.set_reg(Register::A1.into(), b'A' as u32);
+
+        vm.step();
+        vm.step();
+        assert_eq!(output.contents(), b"A");
+        // transmitter empty, no data ready
+        assert_eq!(vm.reg(Register::A2.into()), 0x60);
+    }
+
+    #[test]
+    fn test_unhandled_exception_halts() {
+        // ebreak with no trap handler installed
+        let mut vm = vm_with_program(&[0x00100073]);
+        vm.run();
+        assert_eq!(
+            vm.halt_reason(),
+            Some(&HaltReason::Exception(Exception::Breakpoint(0)))
+        );
     }
 
     #[test]
@@ -155,8 +1174,9 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: 93,
+            compressed: false,
         };
-        execute_instruction(&mut vm, set_a7_insn);
+        execute_instruction(&mut vm, set_a7_insn).unwrap();
         assert_eq!(vm.reg(Register::A7.into()), 93);
 
         // set the a0 to exit code
@@ -169,12 +1189,13 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: 4,
+            compressed: false,
         };
-        execute_instruction(&mut vm, set_a0_insn);
+        execute_instruction(&mut vm, set_a0_insn).unwrap();
         assert_eq!(vm.reg(Register::A0.into()), 4);
 
         // trigger ecall
-        assert_eq!(vm.halted, false);
+        assert!(!vm.halted);
         let ecall_insn = DecodedInstruction {
             inst_type: InstructionType::I,
             opcode: Opcode::Ecall,
@@ -184,22 +1205,24 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: 0,
+            compressed: false,
         };
-        execute_instruction(&mut vm, ecall_insn);
+        execute_instruction(&mut vm, ecall_insn).unwrap();
 
         // assert state
-        assert_eq!(vm.halted, true);
+        assert!(vm.halted);
         assert_eq!(vm.exit_code, 4);
     }
 
     #[test]
     fn vm_print_ecall() {
-        // TODO: capture and assert against stdout
         let hello_world: Vec<u8> = vec![
             0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x77, 0x6f, 0x72, 0x6c, 0x64, 0x21,
         ];
         let mut vm = VM::init();
-        vm.memory[0..hello_world.len()].copy_from_slice(&hello_world);
+        let output = CaptureBuffer::new();
+        vm.set_stdout(Box::new(output.clone()));
+        vm.memory.write_bytes(0, &hello_world);
 
         // set file descriptor
         // set a0 register to 1
@@ -212,8 +1235,9 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: 1,
+            compressed: false,
         };
-        execute_instruction(&mut vm, insn);
+        execute_instruction(&mut vm, insn).unwrap();
 
         // set starting point
         // set a1 register to 0
@@ -226,8 +1250,9 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: 0,
+            compressed: false,
         };
-        execute_instruction(&mut vm, insn);
+        execute_instruction(&mut vm, insn).unwrap();
 
         // set len
         // set a2 register to 11
@@ -240,8 +1265,9 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: hello_world.len() as u32,
+            compressed: false,
         };
-        execute_instruction(&mut vm, insn);
+        execute_instruction(&mut vm, insn).unwrap();
 
         // set the function
         // set a7 register to 64
@@ -254,8 +1280,9 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: 64,
+            compressed: false,
         };
-        execute_instruction(&mut vm, insn);
+        execute_instruction(&mut vm, insn).unwrap();
 
         // ecall
         let ecall_insn = DecodedInstruction {
@@ -267,14 +1294,19 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: 0,
+            compressed: false,
         };
-        execute_instruction(&mut vm, ecall_insn);
+        execute_instruction(&mut vm, ecall_insn).unwrap();
+        // the exact bytes, without a trailing newline
+        assert_eq!(output.contents(), hello_world);
+        assert_eq!(vm.reg(Register::A0 as u32), hello_world.len() as u32);
     }
 
     #[test]
     fn test_register_content_print() {
-        // TODO: capture and assert against stdout
         let mut vm = VM::init();
+        let output = CaptureBuffer::new();
+        vm.set_stdout(Box::new(output.clone()));
 
         vm.registers[Register::A7 as usize] = 1;
         vm.registers[Register::A0 as usize] = 1;
@@ -290,9 +1322,26 @@ mod tests {
             funct3: 0,
             funct7: 0,
             imm: 0,
+            compressed: false,
         };
 
-        execute_instruction(&mut vm, ecall_insn);
+        execute_instruction(&mut vm, ecall_insn).unwrap();
+        assert_eq!(output.contents(), b"5");
+        assert_eq!(vm.reg(Register::A0 as u32), 1);
+    }
+
+    #[test]
+    fn test_non_utf8_output() {
+        let mut vm = vm_with_program(&[0x00000073]);
+        let output = CaptureBuffer::new();
+        vm.set_stdout(Box::new(output.clone()));
+        vm.memory.write_bytes(0x100, &[0xff, 0xfe, 0x00]);
+        vm.registers[Register::A7 as usize] = 64;
+        vm.registers[Register::A0 as usize] = 1;
+        vm.registers[Register::A1 as usize] = 0x100;
+        vm.registers[Register::A2 as usize] = 3;
+        vm.step();
+        assert_eq!(output.contents(), [0xff, 0xfe, 0x00]);
     }
 
     #[test]
@@ -334,7 +1383,7 @@ mod tests {
            ecall
         */
 
-        let program: Vec<u32> = vec![
+        let mut vm = vm_with_program(&[
             // init
             0x00000493, 0x00100913, 0x01400993, // print content of a1
             0x00100893, 0x00100513, 0x00048593, 0x00000073, // store temp
@@ -344,12 +1393,12 @@ mod tests {
             0xfff98993, // loop if a3 is not equal to 0
             0xfe0990e3, // exit
             0x00000513, 0x05d00893, 0x00000073,
-        ];
-
-        let program: Vec<u8> = program.into_iter().flat_map(|v| v.to_le_bytes()).collect();
-
-        let mut vm = VM::init();
-        vm.memory[0..program.len()].copy_from_slice(&program);
+        ]);
+        let output = CaptureBuffer::new();
+        vm.set_stdout(Box::new(output.clone()));
         vm.run();
+
+        let output = String::from_utf8(output.contents()).unwrap();
+        assert_eq!(output, "01123581321345589144233377610987159725844181");
     }
 }